@@ -0,0 +1,144 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::dotconfig::{DotConfig, Value};
+use crate::kconfig::KconfigDb;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum DiffFormat {
+    /// Added, removed and changed symbols grouped by Kconfig menu
+    Text,
+    Json,
+    /// `scripts/config` commands that turn the first config into the second
+    Script,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    pub symbol: String,
+    /// Kconfig menu path, empty when no Kconfig tree is available or the
+    /// symbol is not defined by it.
+    pub menu: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip)]
+    target: Option<Value>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ConfigDiff {
+    pub added: Vec<Change>,
+    pub removed: Vec<Change>,
+    pub changed: Vec<Change>,
+}
+
+impl ConfigDiff {
+    /// Compares two `.config` files symbol by symbol. `# CONFIG_FOO is not
+    /// set` counts as a value, so only symbols missing from a file are
+    /// reported as added or removed.
+    pub fn new(a: &DotConfig, b: &DotConfig, db: Option<&KconfigDb>) -> Self {
+        let menu = |symbol: &str| {
+            db.and_then(|db| db.get(symbol))
+                .map(|symbol| symbol.menu.join(" > "))
+                .unwrap_or_default()
+        };
+        let change = |symbol: &str, from: Option<&Value>, to: Option<&Value>| Change {
+            symbol: symbol.to_string(),
+            menu: menu(symbol),
+            from: from.map(Value::to_string),
+            to: to.map(Value::to_string),
+            target: to.cloned(),
+        };
+
+        let mut diff = ConfigDiff::default();
+        for (symbol, old) in a.symbols() {
+            match b.get(symbol) {
+                None => diff.removed.push(change(symbol, Some(old), None)),
+                Some(new) if new != old => diff.changed.push(change(symbol, Some(old), Some(new))),
+                Some(_) => {}
+            }
+        }
+        for (symbol, new) in b.symbols() {
+            if a.get(symbol).is_none() {
+                diff.added.push(change(symbol, None, Some(new)));
+            }
+        }
+        for changes in [&mut diff.added, &mut diff.removed, &mut diff.changed] {
+            changes.sort_by(|x, y| (&x.menu, &x.symbol).cmp(&(&y.menu, &y.symbol)));
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn print(&self, format: DiffFormat) -> Result<()> {
+        match format {
+            DiffFormat::Text => self.print_text(),
+            DiffFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            DiffFormat::Script => self.print_script(),
+        }
+        Ok(())
+    }
+
+    fn print_text(&self) {
+        let mut menus: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for change in &self.added {
+            menus.entry(&change.menu).or_default().push(format!(
+                "+ CONFIG_{}={}",
+                change.symbol,
+                change.to.as_deref().unwrap_or_default()
+            ));
+        }
+        for change in &self.removed {
+            menus.entry(&change.menu).or_default().push(format!(
+                "- CONFIG_{}={}",
+                change.symbol,
+                change.from.as_deref().unwrap_or_default()
+            ));
+        }
+        for change in &self.changed {
+            menus.entry(&change.menu).or_default().push(format!(
+                "~ CONFIG_{}: {} -> {}",
+                change.symbol,
+                change.from.as_deref().unwrap_or_default(),
+                change.to.as_deref().unwrap_or_default()
+            ));
+        }
+
+        for (menu, lines) in menus {
+            println!("{}", if menu.is_empty() { "(no menu)" } else { menu });
+            for line in lines {
+                println!("  {}", line);
+            }
+        }
+        println!(
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        );
+    }
+
+    fn print_script(&self) {
+        for change in self.added.iter().chain(&self.changed) {
+            let args = match &change.target {
+                Some(Value::Yes) => format!("--enable {}", change.symbol),
+                Some(Value::Module) => format!("--module {}", change.symbol),
+                Some(Value::No) | None => format!("--disable {}", change.symbol),
+                Some(Value::Str(s)) => {
+                    format!("--set-str {} {}", change.symbol, shell_words::quote(s))
+                }
+                Some(Value::Number(n)) => format!("--set-val {} {}", change.symbol, n),
+            };
+            println!("scripts/config {}", args);
+        }
+        for change in &self.removed {
+            println!("scripts/config --undefine {}", change.symbol);
+        }
+    }
+}
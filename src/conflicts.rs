@@ -0,0 +1,483 @@
+use anyhow::{Context, Result};
+use dialoguer::{theme::ColorfulTheme, Select};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::editor;
+
+const MARKER_LEN: usize = 7;
+
+/// Directories that never hold conflicts worth resolving.
+const SKIPPED_DIRS: [&str; 2] = [".git", ".kcli-snapshots"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    /// `<<<<<<<`, followed by the current side.
+    Start,
+    /// `|||||||`, followed by the common ancestor in diff3 style.
+    Base,
+    /// `=======`, followed by the incoming side.
+    Separator,
+    /// `>>>>>>>`, ending the conflict.
+    End,
+}
+
+/// The text of a line without its `\n` or `\r\n`.
+fn content(line: &str) -> &str {
+    line.trim_end_matches('\n').trim_end_matches('\r')
+}
+
+fn marker(line: &str) -> Option<Marker> {
+    let line = content(line);
+    let labelled = |c: char| {
+        line.len() >= MARKER_LEN
+            && line[..MARKER_LEN].chars().all(|ch| ch == c)
+            && (line.len() == MARKER_LEN || line[MARKER_LEN..].starts_with(' '))
+    };
+    if line == "=======" {
+        Some(Marker::Separator)
+    } else if labelled('<') {
+        Some(Marker::Start)
+    } else if labelled('|') {
+        Some(Marker::Base)
+    } else if labelled('>') {
+        Some(Marker::End)
+    } else {
+        None
+    }
+}
+
+fn label(line: &str) -> Option<String> {
+    let label = content(line)[MARKER_LEN..].trim();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// One `<<<<<<<` ... `>>>>>>>` block. Every line keeps its own line ending.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    /// The block as it is in the file, markers included.
+    raw: String,
+    pub current: Vec<String>,
+    /// The common ancestor, for diff3-style conflicts.
+    pub base: Option<Vec<String>>,
+    pub incoming: Vec<String>,
+    pub current_label: Option<String>,
+    pub incoming_label: Option<String>,
+}
+
+/// How to replace a conflict block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Current,
+    Incoming,
+    /// The current side followed by the incoming one.
+    Both,
+    Base,
+}
+
+impl Conflict {
+    /// Parses the block starting at `lines[0]`, or returns `None` when the
+    /// markers are not a complete, well-formed conflict.
+    fn parse(lines: &[&str]) -> Option<(Conflict, usize)> {
+        let mut current = Vec::new();
+        let mut base = None;
+        let mut incoming = Vec::new();
+        let mut section = Marker::Start;
+        for (index, line) in lines.iter().enumerate().skip(1) {
+            let found = marker(line);
+            match (section, found) {
+                (_, None) => match section {
+                    Marker::Start => current.push(line.to_string()),
+                    Marker::Base => base.get_or_insert_with(Vec::new).push(line.to_string()),
+                    _ => incoming.push(line.to_string()),
+                },
+                (Marker::Start, Some(Marker::Base)) => {
+                    base = Some(Vec::new());
+                    section = Marker::Base;
+                }
+                (Marker::Start | Marker::Base, Some(Marker::Separator)) => {
+                    section = Marker::Separator;
+                }
+                (Marker::Separator, Some(Marker::End)) => {
+                    let conflict = Conflict {
+                        raw: lines[..=index].concat(),
+                        current,
+                        base,
+                        incoming,
+                        current_label: label(lines[0]),
+                        incoming_label: label(line),
+                    };
+                    return Some((conflict, index + 1));
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn resolved(&self, resolution: Resolution) -> String {
+        let lines: Vec<&String> = match resolution {
+            Resolution::Current => self.current.iter().collect(),
+            Resolution::Incoming => self.incoming.iter().collect(),
+            Resolution::Both => self.current.iter().chain(&self.incoming).collect(),
+            Resolution::Base => self.base.iter().flatten().collect(),
+        };
+        let mut text: String = lines.into_iter().map(String::as_str).collect();
+        // A conflict at the end of a file without a final newline
+        if !self.raw.ends_with('\n') {
+            let trimmed = text.trim_end_matches('\n').trim_end_matches('\r').len();
+            text.truncate(trimmed);
+        }
+        text
+    }
+
+    fn line_count(&self) -> usize {
+        self.raw.split_inclusive('\n').count()
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Text(String),
+    Conflict(Conflict),
+}
+
+/// A file with conflict markers, split into plain text and conflicts.
+#[derive(Debug)]
+pub struct ConflictFile {
+    pub path: PathBuf,
+    segments: Vec<Segment>,
+}
+
+impl ConflictFile {
+    fn parse(path: PathBuf, contents: &str) -> Self {
+        let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut index = 0;
+        while index < lines.len() {
+            let parsed = match marker(lines[index]) {
+                Some(Marker::Start) => Conflict::parse(&lines[index..]),
+                _ => None,
+            };
+            match parsed {
+                Some((conflict, consumed)) => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Conflict(conflict));
+                    index += consumed;
+                }
+                None => {
+                    text.push_str(lines[index]);
+                    index += 1;
+                }
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        ConflictFile { path, segments }
+    }
+
+    /// Reads a file, returning `None` unless it has conflicts. Binary and
+    /// non-UTF-8 files are skipped.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let Ok(contents) = String::from_utf8(bytes) else {
+            return Ok(None);
+        };
+        if !contents.contains("<<<<<<<") || contents.contains('\0') {
+            return Ok(None);
+        }
+        let file = Self::parse(path.to_path_buf(), &contents);
+        Ok((!file.is_resolved()).then_some(file))
+    }
+
+    /// The unresolved conflicts with the lines they span, 1-based.
+    pub fn conflicts(&self) -> Vec<(usize, usize, &Conflict)> {
+        let mut line = 1;
+        let mut conflicts = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => line += text.split_inclusive('\n').count(),
+                Segment::Conflict(conflict) => {
+                    let end = line + conflict.line_count() - 1;
+                    conflicts.push((line, end, conflict));
+                    line = end + 1;
+                }
+            }
+        }
+        conflicts
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.conflicts().is_empty()
+    }
+
+    /// Replaces the `index`th remaining conflict.
+    pub fn resolve(&mut self, index: usize, resolution: Resolution) {
+        let Some(segment) = self
+            .segments
+            .iter_mut()
+            .filter(|segment| matches!(segment, Segment::Conflict(_)))
+            .nth(index)
+        else {
+            return;
+        };
+        if let Segment::Conflict(conflict) = segment {
+            *segment = Segment::Text(conflict.resolved(resolution));
+        }
+    }
+
+    /// The file's text with resolved conflicts replaced, as `save` writes it.
+    fn contents(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Conflict(conflict) => conflict.raw.as_str(),
+            })
+            .collect()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::write(&self.path, self.contents())
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Conflicts in the given files of the tree, e.g. the ones a patch touched.
+pub fn find(
+    kernel_dir: &Path,
+    files: impl IntoIterator<Item = PathBuf>,
+) -> Result<Vec<ConflictFile>> {
+    let mut found = Vec::new();
+    for file in files {
+        let path = kernel_dir.join(file);
+        if path.is_file() {
+            found.extend(ConflictFile::load(&path)?);
+        }
+    }
+    Ok(found)
+}
+
+/// Conflicts anywhere in the tree.
+pub fn find_all(kernel_dir: &Path) -> Result<Vec<ConflictFile>> {
+    let mut found = Vec::new();
+    let entries = WalkDir::new(kernel_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !SKIPPED_DIRS.iter().any(|dir| entry.file_name() == *dir));
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if entry.file_type().is_file() {
+            found.extend(ConflictFile::load(entry.path())?);
+        }
+    }
+    Ok(found)
+}
+
+fn relative<'a>(kernel_dir: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(kernel_dir).unwrap_or(path)
+}
+
+/// Lists each file with the lines of its remaining conflicts.
+pub fn print_report(kernel_dir: &Path, files: &[ConflictFile]) {
+    if files.is_empty() {
+        println!("No conflicts left in {}.", kernel_dir.display());
+        return;
+    }
+    let total: usize = files.iter().map(|file| file.conflicts().len()).sum();
+    println!("{} unresolved conflicts in {} files:", total, files.len());
+    for file in files {
+        let ranges: Vec<String> = file
+            .conflicts()
+            .iter()
+            .map(|(start, end, _)| format!("{}-{}", start, end))
+            .collect();
+        println!(
+            "  {}: lines {}",
+            relative(kernel_dir, &file.path).display(),
+            ranges.join(", ")
+        );
+    }
+}
+
+fn print_side(title: &str, label: Option<&String>, lines: &[String]) {
+    match label {
+        Some(label) => println!("{} ({}):", title, label),
+        None => println!("{}:", title),
+    }
+    if lines.is_empty() {
+        println!("  (nothing)");
+    }
+    for line in lines {
+        println!("  | {}", content(line));
+    }
+}
+
+fn print_conflict(conflict: &Conflict) {
+    print_side(
+        "Current",
+        conflict.current_label.as_ref(),
+        &conflict.current,
+    );
+    if let Some(base) = &conflict.base {
+        print_side("Base", None, base);
+    }
+    print_side(
+        "Incoming",
+        conflict.incoming_label.as_ref(),
+        &conflict.incoming,
+    );
+}
+
+/// Walks through the conflicts one by one, asking how to resolve each, and
+/// writes every file back once its conflicts were handled. Returns the
+/// number of conflicts left.
+pub async fn resolve_interactive(
+    theme: &ColorfulTheme,
+    kernel_dir: &Path,
+    files: Vec<ConflictFile>,
+) -> Result<usize> {
+    let mut left = 0;
+    for mut file in files {
+        let name = relative(kernel_dir, &file.path).display().to_string();
+        let mut index = 0;
+        loop {
+            let conflicts = file.conflicts();
+            let total = conflicts.len();
+            let Some(&(start, end, conflict)) = conflicts.get(index) else {
+                break;
+            };
+            let conflict = conflict.clone();
+            println!();
+            println!(
+                "{}:{}-{}, conflict {} of {}",
+                name,
+                start,
+                end,
+                index + 1,
+                total
+            );
+            print_conflict(&conflict);
+
+            let mut choices = vec!["Keep Current", "Accept Incoming", "Accept Both"];
+            if conflict.base.is_some() {
+                choices.push("Keep Base");
+            }
+            choices.extend(["Skip", "Open in Editor"]);
+            let choice = Select::with_theme(theme)
+                .with_prompt("Resolve the conflict")
+                .items(&choices)
+                .default(0)
+                .interact()?;
+            let resolution = match choices[choice] {
+                "Keep Current" => Resolution::Current,
+                "Accept Incoming" => Resolution::Incoming,
+                "Accept Both" => Resolution::Both,
+                "Keep Base" => Resolution::Base,
+                "Skip" => {
+                    index += 1;
+                    continue;
+                }
+                _ => {
+                    file.save()?;
+                    if let Err(err) = editor::open(&file.path, start).await {
+                        eprintln!("Error: {:#}", err);
+                    }
+                    // Ask again about whatever the edit left unresolved
+                    match ConflictFile::load(&file.path)? {
+                        Some(edited) => {
+                            file = edited;
+                            index = 0;
+                            continue;
+                        }
+                        None => break,
+                    }
+                }
+            };
+            file.resolve(index, resolution);
+            file.save()?;
+        }
+        left += ConflictFile::load(&file.path)?.map_or(0, |file| file.conflicts().len());
+    }
+    Ok(left)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> ConflictFile {
+        ConflictFile::parse(PathBuf::from("file.c"), contents)
+    }
+
+    fn resolved(contents: &str, resolution: Resolution) -> String {
+        let mut file = parse(contents);
+        assert_eq!(file.conflicts().len(), 1);
+        file.resolve(0, resolution);
+        assert!(file.is_resolved());
+        file.contents()
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let contents = "a\r\n<<<<<<< ours\r\nx\r\n=======\r\ny\r\n>>>>>>> theirs\r\nb\r\n";
+        assert_eq!(resolved(contents, Resolution::Incoming), "a\r\ny\r\nb\r\n");
+        assert_eq!(resolved(contents, Resolution::Both), "a\r\nx\r\ny\r\nb\r\n");
+        let file = parse(contents);
+        let (_, _, conflict) = file.conflicts()[0];
+        assert_eq!(conflict.current_label.as_deref(), Some("ours"));
+        assert_eq!(conflict.incoming_label.as_deref(), Some("theirs"));
+    }
+
+    #[test]
+    fn conflict_at_end_without_final_newline() {
+        let contents = "a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs";
+        assert_eq!(resolved(contents, Resolution::Current), "a\nx");
+        assert_eq!(resolved(contents, Resolution::Both), "a\nx\ny");
+        assert_eq!(parse(contents).contents(), contents);
+    }
+
+    #[test]
+    fn resolves_diff3_block_to_base() {
+        let contents = "<<<<<<< ours\nx\n||||||| base\nold\n=======\ny\n>>>>>>> theirs\nb\n";
+        let file = parse(contents);
+        let (start, end, conflict) = file.conflicts()[0];
+        assert_eq!((start, end), (1, 7));
+        assert_eq!(conflict.base, Some(vec!["old\n".to_string()]));
+        assert_eq!(resolved(contents, Resolution::Base), "old\nb\n");
+        assert_eq!(resolved(contents, Resolution::Incoming), "y\nb\n");
+    }
+
+    #[test]
+    fn separator_outside_a_block_is_text() {
+        let contents = "a\n=======\nb\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n";
+        let file = parse(contents);
+        let (start, end, _) = file.conflicts()[0];
+        assert_eq!((start, end), (4, 8));
+        assert_eq!(
+            resolved(contents, Resolution::Current),
+            "a\n=======\nb\nx\n"
+        );
+    }
+
+    #[test]
+    fn unterminated_start_marker_is_text() {
+        let contents = "a\n<<<<<<< ours\nx\n=======\ny\n";
+        let file = parse(contents);
+        assert!(file.is_resolved());
+        assert_eq!(file.contents(), contents);
+
+        let contents = "<<<<<<< stray\na\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n";
+        let file = parse(contents);
+        let (start, end, _) = file.conflicts()[0];
+        assert_eq!((start, end), (3, 7));
+        assert_eq!(
+            resolved(contents, Resolution::Incoming),
+            "<<<<<<< stray\na\ny\n"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::kernel_config::Toggle;
+
+/// The value of a symbol in a `.config` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Yes,
+    Module,
+    /// `# CONFIG_FOO is not set`
+    No,
+    Str(String),
+    /// Integer or hexadecimal value, kept as written.
+    Number(String),
+}
+
+impl Value {
+    fn parse(raw: &str) -> Value {
+        match raw {
+            "y" => Value::Yes,
+            "m" => Value::Module,
+            "n" => Value::No,
+            _ if raw.starts_with('"') => Value::Str(unescape(raw)),
+            _ => Value::Number(raw.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Yes => f.write_str("y"),
+            Value::Module => f.write_str("m"),
+            Value::No => f.write_str("n"),
+            Value::Str(s) => write!(f, "\"{}\"", escape(s)),
+            Value::Number(n) => f.write_str(n),
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn unescape(raw: &str) -> String {
+    let inner = raw
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(raw);
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("CONFIG_{symbol}: invalid symbol name")]
+    BadSymbolName { symbol: String },
+    #[error("CONFIG_{symbol}: '{value}' is not an integer")]
+    NotAnInteger { symbol: String, value: String },
+    #[error("CONFIG_{symbol}: '{value}' is not a hexadecimal value")]
+    NotHexadecimal { symbol: String, value: String },
+}
+
+enum Line {
+    Symbol { name: String, value: Value },
+    Other(String),
+}
+
+/// A kernel `.config`, edited in place while keeping its layout and comments.
+pub struct DotConfig {
+    path: PathBuf,
+    lines: Vec<Line>,
+    index: HashMap<String, usize>,
+}
+
+impl DotConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(path, &contents))
+    }
+
+    /// Parses `.config` contents that `save` writes back to `path`.
+    pub fn parse(path: &Path, contents: &str) -> Self {
+        let mut lines = Vec::new();
+        let mut index = HashMap::new();
+        for raw in contents.lines() {
+            let symbol = if let Some(rest) = raw.strip_prefix("CONFIG_") {
+                rest.split_once('=')
+                    .map(|(name, value)| (name.to_string(), Value::parse(value)))
+            } else {
+                raw.strip_prefix("# CONFIG_")
+                    .and_then(|rest| rest.strip_suffix(" is not set"))
+                    .map(|name| (name.to_string(), Value::No))
+            };
+            match symbol {
+                Some((name, value)) => {
+                    index.insert(name.clone(), lines.len());
+                    lines.push(Line::Symbol { name, value });
+                }
+                None => lines.push(Line::Other(raw.to_string())),
+            }
+        }
+        DotConfig {
+            path: path.to_path_buf(),
+            lines,
+            index,
+        }
+    }
+
+    /// The value of a symbol, given without the `CONFIG_` prefix. Symbols
+    /// missing from the file are reported as `None`.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self.lines.get(*self.index.get(name)?) {
+            Some(Line::Symbol { value, .. }) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// All symbols in file order, without the `CONFIG_` prefix.
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Symbol { name, value } => Some((name.as_str(), value)),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// Sets a symbol, given with or without the `CONFIG_` prefix. Names may
+    /// use the characters Kconfig accepts, `[A-Za-z0-9_]`, as in
+    /// `SND_SOC_AMD_ACP6x`.
+    pub fn set(&mut self, name: &str, value: Value) -> Result<(), ConfigError> {
+        let name = name.strip_prefix("CONFIG_").unwrap_or(name);
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(ConfigError::BadSymbolName {
+                symbol: name.to_string(),
+            });
+        }
+        match self.index.get(name) {
+            Some(&i) => {
+                self.lines[i] = Line::Symbol {
+                    name: name.to_string(),
+                    value,
+                }
+            }
+            None => {
+                self.index.insert(name.to_string(), self.lines.len());
+                self.lines.push(Line::Symbol {
+                    name: name.to_string(),
+                    value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        if value.parse::<i64>().is_err() {
+            return Err(ConfigError::NotAnInteger {
+                symbol: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+        self.set(name, Value::Number(value.to_string()))
+    }
+
+    pub fn set_hex(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let digits = value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+            .unwrap_or(value);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ConfigError::NotHexadecimal {
+                symbol: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+        self.set(name, Value::Number(format!("0x{}", digits)))
+    }
+
+    /// Applies one kcli toggle. Numeric values starting with `0x` or `0X` are
+    /// treated as hexadecimal, anything else must be an integer.
+    pub fn apply(&mut self, toggle: &Toggle) -> Result<(), ConfigError> {
+        match toggle {
+            Toggle::Enable(name) => self.set(name, Value::Yes),
+            Toggle::Disable(name) => self.set(name, Value::No),
+            Toggle::Module(name) => self.set(name, Value::Module),
+            Toggle::Str(name, value) => self.set(name, Value::Str(value.clone())),
+            Toggle::Value(name, value)
+                if value.get(..2).is_some_and(|prefix| prefix.eq_ignore_ascii_case("0x")) =>
+            {
+                self.set_hex(name, value)
+            }
+            Toggle::Value(name, value) => self.set_int(name, value),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                Line::Symbol { name, value: Value::No } => {
+                    contents.push_str(&format!("# CONFIG_{} is not set\n", name))
+                }
+                Line::Symbol { name, value } => {
+                    contents.push_str(&format!("CONFIG_{}={}\n", name, value))
+                }
+                Line::Other(raw) => {
+                    contents.push_str(raw);
+                    contents.push('\n');
+                }
+            }
+        }
+        fs::write(&self.path, contents)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// A requested value that did not survive `make olddefconfig`.
+pub struct Rejected {
+    pub symbol: String,
+    pub requested: Value,
+    /// `None` when Kconfig dropped the symbol from the file entirely.
+    pub actual: Option<Value>,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(
+                f,
+                "CONFIG_{}: requested {}, Kconfig set {}",
+                self.symbol, self.requested, actual
+            ),
+            None => write!(
+                f,
+                "CONFIG_{}: requested {}, dropped by Kconfig",
+                self.symbol, self.requested
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ApplyReport {
+    pub errors: Vec<ConfigError>,
+    pub rejected: Vec<Rejected>,
+}
+
+/// Writes `toggles` into the tree's `.config`, runs `make olddefconfig` and
+/// reports every symbol that Kconfig dropped or overrode. When any toggle is
+/// invalid the file is left untouched and only the errors are reported.
+pub async fn apply_toggles(kernel_dir: &Path, toggles: &[Toggle]) -> Result<ApplyReport> {
+    let config_path = kernel_dir.join(".config");
+    let mut config = DotConfig::load(&config_path)?;
+    let mut report = ApplyReport::default();
+
+    let mut requested: Vec<(String, Value)> = Vec::new();
+    for toggle in toggles {
+        match config.apply(toggle) {
+            Ok(()) => {
+                let name = toggle.symbol().trim_start_matches("CONFIG_").to_string();
+                let value = config.get(&name).cloned().unwrap_or(Value::No);
+                requested.retain(|(symbol, _)| *symbol != name);
+                requested.push((name, value));
+            }
+            Err(err) => report.errors.push(err),
+        }
+    }
+    if !report.errors.is_empty() {
+        return Ok(report);
+    }
+    config.save()?;
+
+    olddefconfig(kernel_dir).await?;
+
+    let resolved = DotConfig::load(&config_path)?;
+    for (symbol, value) in requested {
+        let actual = resolved.get(&symbol).cloned();
+        let kept = match (&value, &actual) {
+            // A disabled symbol may legitimately vanish once its menu is off.
+            (Value::No, None) => true,
+            (_, Some(actual)) => *actual == value,
+            (_, None) => false,
+        };
+        if !kept {
+            report.rejected.push(Rejected {
+                symbol,
+                requested: value,
+                actual,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+pub async fn olddefconfig(kernel_dir: &Path) -> Result<()> {
+    let output = Command::new("make")
+        .arg("olddefconfig")
+        .current_dir(kernel_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to execute make olddefconfig")?;
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(crate::Error::CommandFailed("make olddefconfig".to_string()).into());
+    }
+    Ok(())
+}
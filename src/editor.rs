@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use dirs_next::config_dir;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::Error;
+
+/// Used when neither the environment nor the settings name an editor.
+const FALLBACK_EDITOR: &str = "nano";
+
+/// kcli's own settings in `~/.config/kcli/settings.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Settings {
+    /// Editor command, e.g. `vim` or `code --wait`.
+    editor: Option<String>,
+}
+
+fn settings_path() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli")
+        .join("settings.toml"))
+}
+
+fn load_settings() -> Result<Settings> {
+    let path = settings_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => {
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// The editor command from `$VISUAL`, `$EDITOR` or the `editor` setting,
+/// split into program and arguments.
+fn command() -> Result<Vec<String>> {
+    let from_env = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.trim().is_empty());
+    let editor = match from_env {
+        Some(editor) => editor,
+        None => load_settings()?
+            .editor
+            .unwrap_or_else(|| FALLBACK_EDITOR.to_string()),
+    };
+    shell_words::split(&editor).with_context(|| format!("Invalid editor command '{}'", editor))
+}
+
+/// Arguments that open `path` at `line` for the editors kcli knows; others
+/// just get the path.
+fn file_arguments(program: &str, path: &Path, line: usize) -> Vec<String> {
+    let name = Path::new(program)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let path = path.display().to_string();
+    match name.as_str() {
+        "vi" | "vim" | "nvim" | "gvim" | "view" | "nano" | "pico" | "emacs" | "emacsclient"
+        | "kak" | "micro" | "joe" | "jed" | "mg" | "ne" => vec![format!("+{}", line), path],
+        "code" | "code-insiders" | "codium" => {
+            vec!["--goto".to_string(), format!("{}:{}", path, line)]
+        }
+        "hx" | "helix" | "subl" | "zed" => vec![format!("{}:{}", path, line)],
+        _ => vec![path],
+    }
+}
+
+/// Opens `path` in the user's editor at `line` and waits for it to exit.
+pub async fn open(path: &Path, line: usize) -> Result<()> {
+    let command = command()?;
+    let (program, args) = command
+        .split_first()
+        .context("The editor command is empty")?;
+    let status = Command::new(program)
+        .args(args)
+        .args(file_arguments(program, path, line))
+        .status()
+        .await
+        .with_context(|| format!("Failed to start {}", program))?;
+    if !status.success() {
+        return Err(Error::CommandFailed(program.to_string()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,359 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::kconfig::{CompareOp, Evaluator, Expr, Operand, Symbol, SymbolType, Tristate};
+use crate::kernel_config::Toggle;
+
+/// One `depends on` expression of a symbol and what it evaluates to.
+pub struct Dependency {
+    pub expr: String,
+    pub value: Tristate,
+    /// Current values of the symbols the expression refers to.
+    pub symbols: Vec<(String, String)>,
+}
+
+/// A `select` or `imply` targeting the explained symbol.
+pub struct ReverseDependency {
+    pub from: String,
+    pub condition: Option<String>,
+    /// The value the reverse dependency currently imposes.
+    pub value: Tristate,
+}
+
+/// Why a symbol has its current value in a `.config`.
+pub struct Explanation {
+    pub name: String,
+    pub kind: SymbolType,
+    pub prompt: Option<String>,
+    pub value: String,
+    pub depends_on: Vec<Dependency>,
+    pub selected_by: Vec<ReverseDependency>,
+    pub implied_by: Vec<ReverseDependency>,
+}
+
+impl Explanation {
+    pub fn new(eval: &Evaluator, symbol: &Symbol) -> Self {
+        let depends_on = symbol
+            .depends_on
+            .iter()
+            .map(|expr| Dependency {
+                expr: expr.clone(),
+                value: eval.eval_str(expr),
+                symbols: Expr::parse(expr)
+                    .map(|parsed| {
+                        parsed
+                            .symbols()
+                            .into_iter()
+                            .map(|name| (name.to_string(), eval.value(name)))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        let mut selected_by = Vec::new();
+        let mut implied_by = Vec::new();
+        for other in eval.db().symbols() {
+            let reverse = |conditional: &crate::kconfig::Conditional| ReverseDependency {
+                from: other.name.clone(),
+                condition: conditional.condition.clone(),
+                value: eval.tristate(&other.name).min(
+                    conditional
+                        .condition
+                        .as_deref()
+                        .map_or(Tristate::Yes, |condition| eval.eval_str(condition)),
+                ),
+            };
+            selected_by.extend(
+                other
+                    .selects
+                    .iter()
+                    .filter(|select| select.value == symbol.name)
+                    .map(reverse),
+            );
+            implied_by.extend(
+                other
+                    .implies
+                    .iter()
+                    .filter(|imply| imply.value == symbol.name)
+                    .map(reverse),
+            );
+        }
+
+        Explanation {
+            name: symbol.name.clone(),
+            kind: symbol.kind,
+            prompt: symbol.prompt.clone(),
+            value: eval.value(&symbol.name),
+            depends_on,
+            selected_by,
+            implied_by,
+        }
+    }
+
+    /// The highest value the dependencies allow.
+    pub fn upper_bound(&self) -> Tristate {
+        self.depends_on
+            .iter()
+            .map(|dependency| dependency.value)
+            .min()
+            .unwrap_or(Tristate::Yes)
+    }
+
+    /// The lowest value active `select`s force.
+    pub fn lower_bound(&self) -> Tristate {
+        self.selected_by
+            .iter()
+            .map(|select| select.value)
+            .max()
+            .unwrap_or(Tristate::No)
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CONFIG_{}={} [{}]", self.name, self.value, self.kind)?;
+        match &self.prompt {
+            Some(prompt) => writeln!(f, " \"{}\"", prompt)?,
+            None => writeln!(f, " (no prompt: only set by select or default)")?,
+        }
+
+        if !self.depends_on.is_empty() {
+            writeln!(f, "depends on:")?;
+        }
+        for dependency in &self.depends_on {
+            write!(f, "  [{}] {}", dependency.value, dependency.expr)?;
+            if dependency.value == Tristate::No {
+                let values: Vec<String> = dependency
+                    .symbols
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                write!(f, "  <- unmet ({})", values.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+
+        for (title, reverse) in [
+            ("selected by", &self.selected_by),
+            ("implied by", &self.implied_by),
+        ] {
+            if reverse.is_empty() {
+                continue;
+            }
+            writeln!(f, "{}:", title)?;
+            for dependency in reverse {
+                write!(f, "  [{}] {}", dependency.value, dependency.from)?;
+                if let Some(condition) = &dependency.condition {
+                    write!(f, " if {}", condition)?;
+                }
+                if dependency.value != Tristate::No && title == "selected by" {
+                    write!(f, "  <- active")?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        let (upper, lower) = (self.upper_bound(), self.lower_bound());
+        if upper < Tristate::Yes {
+            writeln!(f, "Dependencies limit CONFIG_{} to {}.", self.name, upper)?;
+        }
+        if lower > Tristate::No {
+            writeln!(
+                f,
+                "Reverse selects force CONFIG_{} to at least {}.",
+                self.name, lower
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The toggles needed to bring a symbol to a value, in the order they must
+/// be applied.
+pub struct Chain {
+    pub toggles: Vec<Toggle>,
+    /// Reasons the chain could not be completed; empty when it is complete.
+    pub blockers: Vec<String>,
+}
+
+/// Works out which symbols have to change, through their dependencies or
+/// the symbols selecting them, for `name` to reach `target`.
+pub fn plan(eval: Evaluator, name: &str, target: Tristate) -> Chain {
+    let mut planner = Planner {
+        eval,
+        toggles: Vec::new(),
+        blockers: Vec::new(),
+        visiting: HashSet::new(),
+    };
+    if !planner.require(name, target) && planner.blockers.is_empty() {
+        planner
+            .blockers
+            .push(format!("CONFIG_{} depends on itself", name));
+    }
+    Chain {
+        toggles: planner.toggles,
+        blockers: planner.blockers,
+    }
+}
+
+struct Planner<'a> {
+    eval: Evaluator<'a>,
+    toggles: Vec<Toggle>,
+    blockers: Vec<String>,
+    visiting: HashSet<String>,
+}
+
+impl Planner<'_> {
+    fn require(&mut self, name: &str, target: Tristate) -> bool {
+        let current = self.eval.tristate(name);
+        let satisfied = match target {
+            Tristate::No => current == Tristate::No,
+            _ => current >= target,
+        };
+        if satisfied {
+            return true;
+        }
+        if !self.visiting.insert(name.to_string()) {
+            return false;
+        }
+        let done = self.require_unvisited(name, target);
+        self.visiting.remove(name);
+        done
+    }
+
+    fn require_unvisited(&mut self, name: &str, target: Tristate) -> bool {
+        let db = self.eval.db();
+        let Some(symbol) = db.get(name) else {
+            self.blockers.push(format!(
+                "CONFIG_{} is not defined by this tree's Kconfig",
+                name
+            ));
+            return false;
+        };
+        let target = match (symbol.kind, target) {
+            (SymbolType::Bool, Tristate::Module) => Tristate::Yes,
+            (SymbolType::Bool | SymbolType::Tristate, target) => target,
+            _ => {
+                self.blockers.push(format!(
+                    "CONFIG_{} is a {} symbol and must be set by hand",
+                    name, symbol.kind
+                ));
+                return false;
+            }
+        };
+
+        if target == Tristate::No {
+            let selectors: Vec<&str> = db
+                .symbols()
+                .filter(|other| {
+                    other.selects.iter().any(|select| {
+                        select.value == name
+                            && select.condition.as_deref().is_none_or(|condition| {
+                                self.eval.eval_str(condition) != Tristate::No
+                            })
+                    })
+                })
+                .map(|other| other.name.as_str())
+                .collect();
+            for selector in selectors {
+                if !self.require(selector, Tristate::No) {
+                    return false;
+                }
+            }
+        } else {
+            if symbol.prompt.is_none() {
+                self.blockers.push(format!(
+                    "CONFIG_{} has no prompt and can only be selected by another option",
+                    name
+                ));
+                return false;
+            }
+            for dependency in &symbol.depends_on {
+                let Ok(expr) = Expr::parse(dependency) else {
+                    self.blockers.push(format!(
+                        "cannot evaluate '{}' for CONFIG_{}",
+                        dependency, name
+                    ));
+                    return false;
+                };
+                if !self.satisfy(&expr, true) {
+                    return false;
+                }
+            }
+        }
+
+        self.eval.set_override(name, target);
+        self.toggles.push(match target {
+            Tristate::Yes => Toggle::Enable(name.to_string()),
+            Tristate::Module => Toggle::Module(name.to_string()),
+            Tristate::No => Toggle::Disable(name.to_string()),
+        });
+        true
+    }
+
+    /// Makes `expr` true (`want`) or false (`!want`).
+    fn satisfy(&mut self, expr: &Expr, want: bool) -> bool {
+        if (self.eval.eval(expr) != Tristate::No) == want {
+            return true;
+        }
+        match expr {
+            Expr::Atom(Operand::Symbol(name)) => {
+                self.require(name, if want { Tristate::Yes } else { Tristate::No })
+            }
+            Expr::Not(inner) => self.satisfy(inner, !want),
+            Expr::And(left, right) if want => self.satisfy(left, true) && self.satisfy(right, true),
+            Expr::Or(left, right) if !want => {
+                self.satisfy(left, false) && self.satisfy(right, false)
+            }
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                let blockers = self.blockers.len();
+                if self.attempt(left, want) || self.attempt(right, want) {
+                    self.blockers.truncate(blockers);
+                    true
+                } else {
+                    false
+                }
+            }
+            Expr::Compare(op, Operand::Symbol(name), Operand::Const(value))
+            | Expr::Compare(op, Operand::Const(value), Operand::Symbol(name))
+                if matches!(value.as_str(), "y" | "m" | "n") =>
+            {
+                let value = match value.as_str() {
+                    "y" => Tristate::Yes,
+                    "m" => Tristate::Module,
+                    _ => Tristate::No,
+                };
+                match (op, want) {
+                    (CompareOp::Eq, true) | (CompareOp::Ne, false) => self.require(name, value),
+                    (CompareOp::Ne, true) | (CompareOp::Eq, false) if value == Tristate::No => {
+                        self.require(name, Tristate::Yes)
+                    }
+                    (CompareOp::Ne, true) | (CompareOp::Eq, false) => {
+                        self.require(name, Tristate::No)
+                    }
+                    _ => self.unsupported(expr),
+                }
+            }
+            _ => self.unsupported(expr),
+        }
+    }
+
+    /// Tries to satisfy `expr`, undoing any partial changes on failure.
+    fn attempt(&mut self, expr: &Expr, want: bool) -> bool {
+        let toggles = self.toggles.len();
+        let overrides = self.eval.overrides().clone();
+        if self.satisfy(expr, want) {
+            return true;
+        }
+        self.toggles.truncate(toggles);
+        self.eval.restore_overrides(overrides);
+        false
+    }
+
+    fn unsupported(&mut self, expr: &Expr) -> bool {
+        self.blockers
+            .push(format!("cannot satisfy '{}' automatically", expr));
+        false
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use dirs_next::data_dir;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::{Error, KernelChannel};
+
+/// Runs git and returns its trimmed standard output.
+pub async fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to execute git")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            eprintln!("{}", stderr.trim_end());
+        }
+        return Err(Error::CommandFailed(format!("git {}", args.join(" "))).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Runs git with its progress output shown to the user.
+async fn git_interactive(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .status()
+        .await
+        .context("Failed to execute git")?;
+    if !status.success() {
+        return Err(Error::CommandFailed(format!("git {}", args.join(" "))).into());
+    }
+    Ok(())
+}
+
+/// The single bare repository all git-based source trees are checked out
+/// from as worktrees, with one remote per kernel channel.
+pub struct Mirror {
+    path: PathBuf,
+}
+
+impl Mirror {
+    /// Opens the mirror under `~/.local/share/kcli`, creating it on first use.
+    pub async fn open() -> Result<Self> {
+        let path = data_dir()
+            .context("Failed to locate the data directory")?
+            .join("kcli")
+            .join("linux.git");
+        if !path.join("HEAD").is_file() {
+            std::fs::create_dir_all(&path)?;
+            git(&path, &["init", "--bare", "--quiet"]).await?;
+        }
+        Ok(Mirror { path })
+    }
+
+    /// Fetches the channel's branches and tags. Only objects the mirror does
+    /// not have yet are downloaded.
+    pub async fn fetch(&self, channel: KernelChannel) -> Result<()> {
+        let (url, _) = channel.source();
+        let remote = channel.remote();
+        let key = format!("remote.{}.url", remote);
+        if git(&self.path, &["config", "--get", &key]).await.is_err() {
+            git(&self.path, &["remote", "add", remote, url]).await?;
+        }
+        println!("Fetching {} into {}", url, self.path.display());
+        git_interactive(&self.path, &["fetch", "--tags", remote]).await
+    }
+
+    /// Resolves a tag, a branch of the channel's remote or a commit to a
+    /// commit id. Without a reference the remote's `master` is used.
+    pub async fn resolve(&self, channel: KernelChannel, reference: Option<&str>) -> Result<String> {
+        let reference = reference.unwrap_or("master");
+        let candidates = [
+            format!("refs/tags/{}", reference),
+            format!("refs/remotes/{}/{}", channel.remote(), reference),
+            reference.to_string(),
+        ];
+        for candidate in &candidates {
+            let spec = format!("{}^{{commit}}", candidate);
+            if let Ok(commit) = git(&self.path, &["rev-parse", "--verify", "--quiet", &spec]).await
+            {
+                return Ok(commit);
+            }
+        }
+        anyhow::bail!(
+            "'{}' is neither a tag nor a branch of {}",
+            reference,
+            channel.source().0
+        )
+    }
+
+    /// Checks `commit` out in `target_dir`, adding a worktree the first time
+    /// and moving the existing one afterwards.
+    pub async fn checkout(&self, commit: &str, target_dir: &Path) -> Result<()> {
+        if target_dir.exists() {
+            if !target_dir.join(".git").is_file() {
+                anyhow::bail!(
+                    "{} exists and is not a kcli git worktree",
+                    target_dir.display()
+                );
+            }
+            return git_interactive(target_dir, &["checkout", "--detach", commit]).await;
+        }
+        // Forget worktrees whose directories were deleted by hand.
+        git(&self.path, &["worktree", "prune"]).await?;
+        let target = target_dir.to_string_lossy();
+        git_interactive(
+            &self.path,
+            &["worktree", "add", "--detach", &target, commit],
+        )
+        .await
+    }
+}
+
+/// `git describe` of a source tree, with `-dirty` appended when it has
+/// uncommitted changes. `None` for trees that are not git checkouts.
+pub async fn describe(tree: &Path) -> Option<String> {
+    if !tree.join(".git").exists() {
+        return None;
+    }
+    git(tree, &["describe", "--tags", "--always", "--dirty"])
+        .await
+        .ok()
+}
@@ -0,0 +1,937 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dotconfig::{DotConfig, Value};
+use crate::kernel_config::Toggle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolType {
+    Bool,
+    Tristate,
+    String,
+    Int,
+    Hex,
+    Unknown,
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SymbolType::Bool => "bool",
+            SymbolType::Tristate => "tristate",
+            SymbolType::String => "string",
+            SymbolType::Int => "int",
+            SymbolType::Hex => "hex",
+            SymbolType::Unknown => "unknown",
+        })
+    }
+}
+
+/// An expression with an optional `if` condition, as used by `default`,
+/// `select`, `imply` and `range`.
+#[derive(Debug, Clone)]
+pub struct Conditional {
+    pub value: String,
+    pub condition: Option<String>,
+}
+
+impl fmt::Display for Conditional {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.condition {
+            Some(condition) => write!(f, "{} if {}", self.value, condition),
+            None => f.write_str(&self.value),
+        }
+    }
+}
+
+/// A `config` or `menuconfig` entry, merged across every place the symbol
+/// is defined.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolType,
+    pub prompt: Option<String>,
+    pub help: String,
+    /// `depends on` expressions, including those inherited from enclosing
+    /// `if` blocks, menus and choices.
+    pub depends_on: Vec<String>,
+    pub selects: Vec<Conditional>,
+    pub implies: Vec<Conditional>,
+    pub defaults: Vec<Conditional>,
+    pub ranges: Vec<Conditional>,
+    /// Titles of the enclosing menus, outermost first.
+    pub menu: Vec<String>,
+    pub files: Vec<PathBuf>,
+}
+
+impl Symbol {
+    fn new(name: &str) -> Self {
+        Symbol {
+            name: name.to_string(),
+            kind: SymbolType::Unknown,
+            prompt: None,
+            help: String::new(),
+            depends_on: Vec::new(),
+            selects: Vec::new(),
+            implies: Vec::new(),
+            defaults: Vec::new(),
+            ranges: Vec::new(),
+            menu: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Checks `input` against the symbol's type and turns it into a toggle.
+    pub fn parse_value(&self, input: &str) -> Result<Toggle, KconfigError> {
+        let input = input.trim();
+        let invalid = |expected: &str| KconfigError::InvalidValue {
+            symbol: self.name.clone(),
+            value: input.to_string(),
+            expected: expected.to_string(),
+        };
+        let name = self.name.clone();
+        match self.kind {
+            SymbolType::Bool => match input {
+                "y" => Ok(Toggle::Enable(name)),
+                "n" => Ok(Toggle::Disable(name)),
+                _ => Err(invalid("y or n")),
+            },
+            SymbolType::Tristate => match input {
+                "y" => Ok(Toggle::Enable(name)),
+                "m" => Ok(Toggle::Module(name)),
+                "n" => Ok(Toggle::Disable(name)),
+                _ => Err(invalid("y, m or n")),
+            },
+            SymbolType::String => Ok(Toggle::Str(name, input.to_string())),
+            SymbolType::Int => {
+                let number: i64 = input.parse().map_err(|_| invalid("an integer"))?;
+                if let Some((low, high)) = self.literal_range() {
+                    if number < low || number > high {
+                        return Err(invalid(&format!("an integer between {} and {}", low, high)));
+                    }
+                }
+                Ok(Toggle::Value(name, number.to_string()))
+            }
+            SymbolType::Hex => {
+                let digits = input
+                    .strip_prefix("0x")
+                    .or_else(|| input.strip_prefix("0X"))
+                    .unwrap_or(input);
+                if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid("a hexadecimal value"));
+                }
+                Ok(Toggle::Value(name, format!("0x{}", digits)))
+            }
+            SymbolType::Unknown => Err(KconfigError::UntypedSymbol(name)),
+        }
+    }
+
+    /// The first unconditional `range` made of two integer literals.
+    fn literal_range(&self) -> Option<(i64, i64)> {
+        self.ranges
+            .iter()
+            .filter(|range| range.condition.is_none())
+            .find_map(|range| {
+                let (low, high) = range.value.split_once(char::is_whitespace)?;
+                Some((low.trim().parse().ok()?, high.trim().parse().ok()?))
+            })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KconfigError {
+    #[error("CONFIG_{symbol}: '{value}' is not valid, expected {expected}")]
+    InvalidValue {
+        symbol: String,
+        value: String,
+        expected: String,
+    },
+    #[error("CONFIG_{0} has no type and cannot be set")]
+    UntypedSymbol(String),
+    #[error("cannot parse Kconfig expression '{0}'")]
+    BadExpression(String),
+}
+
+/// Every symbol declared by a source tree's Kconfig files.
+pub struct KconfigDb {
+    symbols: BTreeMap<String, Symbol>,
+}
+
+/// Block that `depends on` and `prompt` lines currently apply to.
+enum Entry {
+    None,
+    Symbol(String),
+    /// A menu, choice or `if` block at this depth of the dependency stack.
+    Block,
+}
+
+struct Parser<'a> {
+    srctree: &'a Path,
+    arch: &'a str,
+    symbols: BTreeMap<String, Symbol>,
+    /// Dependencies of the enclosing blocks, one entry per block.
+    deps: Vec<Vec<String>>,
+    /// Titles of the enclosing menus and choices; `None` for `if` blocks.
+    blocks: Vec<Option<String>>,
+    entry: Entry,
+}
+
+impl KconfigDb {
+    /// Parses the Kconfig tree rooted at `srctree/Kconfig`, following
+    /// `source` statements for the host architecture.
+    pub fn load(srctree: &Path) -> Result<Self> {
+        let mut parser = Parser {
+            srctree,
+            arch: host_srcarch(),
+            symbols: BTreeMap::new(),
+            deps: Vec::new(),
+            blocks: Vec::new(),
+            entry: Entry::None,
+        };
+        parser.parse_file(&srctree.join("Kconfig"), true)?;
+        Ok(KconfigDb {
+            symbols: parser.symbols,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols
+            .get(name.strip_prefix("CONFIG_").unwrap_or(name))
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Symbols whose name, prompt or help text contains `query`, ignoring case.
+    pub fn search(&self, query: &str) -> Vec<&Symbol> {
+        let query = query.to_lowercase();
+        let query = query.strip_prefix("config_").unwrap_or(&query);
+        self.symbols
+            .values()
+            .filter(|symbol| {
+                symbol.name.to_lowercase().contains(query)
+                    || symbol
+                        .prompt
+                        .as_deref()
+                        .is_some_and(|prompt| prompt.to_lowercase().contains(query))
+                    || symbol.help.to_lowercase().contains(query)
+            })
+            .collect()
+    }
+}
+
+/// Kconfig's `SRCARCH` for the machine kcli runs on.
+fn host_srcarch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86" | "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "riscv64" => "riscv",
+        "powerpc64" => "powerpc",
+        "loongarch64" => "loongarch",
+        other => other,
+    }
+}
+
+impl Parser<'_> {
+    fn parse_file(&mut self, path: &Path, required: bool) -> Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) if !required => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        let relative = path
+            .strip_prefix(self.srctree)
+            .unwrap_or(path)
+            .to_path_buf();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        let mut i = 0;
+        while i < lines.len() {
+            // Join continuation lines.
+            let mut line = lines[i].to_string();
+            while line.ends_with('\\') && i + 1 < lines.len() {
+                line.pop();
+                line.truncate(line.trim_end().len());
+                i += 1;
+                line.push(' ');
+                line.push_str(lines[i].trim_start());
+            }
+            i += 1;
+
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = match trimmed.split_once(char::is_whitespace) {
+                Some((keyword, rest)) => (keyword, rest.trim()),
+                None => (trimmed, ""),
+            };
+
+            match keyword {
+                "config" | "menuconfig" => {
+                    let symbol = self
+                        .symbols
+                        .entry(rest.to_string())
+                        .or_insert_with(|| Symbol::new(rest));
+                    symbol
+                        .depends_on
+                        .extend(self.deps.iter().flatten().cloned());
+                    if symbol.menu.is_empty() {
+                        symbol.menu = self.blocks.iter().flatten().cloned().collect();
+                    }
+                    if !symbol.files.contains(&relative) {
+                        symbol.files.push(relative.clone());
+                    }
+                    self.entry = Entry::Symbol(rest.to_string());
+                }
+                "menu" => {
+                    self.deps.push(Vec::new());
+                    self.blocks.push(Some(unquote(rest)));
+                    self.entry = Entry::Block;
+                }
+                "choice" => {
+                    self.deps.push(Vec::new());
+                    // Titled by the choice's prompt once it is read.
+                    self.blocks.push(None);
+                    self.entry = Entry::Block;
+                }
+                "if" => {
+                    self.deps.push(vec![rest.to_string()]);
+                    self.blocks.push(None);
+                    self.entry = Entry::None;
+                }
+                "endmenu" | "endchoice" | "endif" => {
+                    self.deps.pop();
+                    self.blocks.pop();
+                    self.entry = Entry::None;
+                }
+                "source" | "rsource" | "osource" | "orsource" => {
+                    let target = unquote(rest)
+                        .replace("$(SRCARCH)", self.arch)
+                        .replace("$SRCARCH", self.arch);
+                    let target = if keyword.starts_with('r') || keyword.starts_with("or") {
+                        path.parent().unwrap_or(self.srctree).join(target)
+                    } else {
+                        self.srctree.join(target)
+                    };
+                    let optional =
+                        keyword.starts_with('o') || target.to_string_lossy().contains('*');
+                    let entry = std::mem::replace(&mut self.entry, Entry::None);
+                    self.parse_file(&target, !optional)?;
+                    self.entry = entry;
+                }
+                "comment" | "mainmenu" => self.entry = Entry::None,
+                "help" | "---help---" => {
+                    let (help, next) = read_help(&lines, i, indent(&line));
+                    if let Some(symbol) = self.current_symbol() {
+                        symbol.help = help;
+                    }
+                    i = next;
+                }
+                _ => self.parse_attribute(keyword, rest),
+            }
+        }
+        Ok(())
+    }
+
+    fn current_symbol(&mut self) -> Option<&mut Symbol> {
+        match &self.entry {
+            Entry::Symbol(name) => self.symbols.get_mut(name),
+            _ => None,
+        }
+    }
+
+    fn parse_attribute(&mut self, keyword: &str, rest: &str) {
+        if keyword == "depends" {
+            let expr = rest.strip_prefix("on").unwrap_or(rest).trim().to_string();
+            match self.entry {
+                Entry::Block => {
+                    if let Some(deps) = self.deps.last_mut() {
+                        deps.push(expr);
+                    }
+                }
+                Entry::Symbol(_) => {
+                    if let Some(symbol) = self.current_symbol() {
+                        symbol.depends_on.push(expr);
+                    }
+                }
+                Entry::None => {}
+            }
+            return;
+        }
+        if let (Entry::Block, "prompt") = (&self.entry, keyword) {
+            if let Some(title @ None) = self.blocks.last_mut() {
+                *title = Some(unquote(&conditional(rest).value));
+            }
+            return;
+        }
+
+        let Some(symbol) = self.current_symbol() else {
+            return;
+        };
+        let kind = match keyword {
+            "bool" | "boolean" | "def_bool" => Some(SymbolType::Bool),
+            "tristate" | "def_tristate" => Some(SymbolType::Tristate),
+            "string" => Some(SymbolType::String),
+            "int" => Some(SymbolType::Int),
+            "hex" => Some(SymbolType::Hex),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            if symbol.kind == SymbolType::Unknown {
+                symbol.kind = kind;
+            }
+            if keyword.starts_with("def_") {
+                symbol.defaults.push(conditional(rest));
+            } else if !rest.is_empty() && symbol.prompt.is_none() {
+                symbol.prompt = Some(unquote(&conditional(rest).value));
+            }
+            return;
+        }
+        match keyword {
+            "prompt" if symbol.prompt.is_none() => {
+                symbol.prompt = Some(unquote(&conditional(rest).value));
+            }
+            "default" => symbol.defaults.push(conditional(rest)),
+            "select" => symbol.selects.push(conditional(rest)),
+            "imply" => symbol.implies.push(conditional(rest)),
+            "range" => symbol.ranges.push(conditional(rest)),
+            _ => {}
+        }
+    }
+}
+
+/// Width of a line's leading whitespace, with tabs counting as 8.
+fn indent(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 8 } else { 1 })
+        .sum()
+}
+
+/// Reads a help block starting at `start`, returning the text and the index
+/// of the first line after it. The block is made of the lines indented
+/// deeper than the `help` keyword, which is indented `help_indent` deep.
+fn read_help(lines: &[&str], start: usize, help_indent: usize) -> (String, usize) {
+    let Some(first) = lines[start..].iter().find(|line| !line.trim().is_empty()) else {
+        return (String::new(), lines.len());
+    };
+    let block_indent = indent(first);
+    if block_indent <= help_indent {
+        // An empty help text; the line belongs to the next entry
+        return (String::new(), start);
+    }
+
+    let mut text = Vec::new();
+    let mut i = start;
+    while i < lines.len() {
+        let line = lines[i];
+        if !line.trim().is_empty() && indent(line) < block_indent {
+            break;
+        }
+        text.push(line.trim());
+        i += 1;
+    }
+    while text.last().is_some_and(|line| line.is_empty()) {
+        text.pop();
+    }
+    (text.join("\n"), i)
+}
+
+/// Splits `value if condition` at the first `if` outside quotes.
+fn conditional(rest: &str) -> Conditional {
+    let mut in_quotes = false;
+    let bytes = rest.as_bytes();
+    for i in 0..bytes.len() {
+        match bytes[i] {
+            b'"' if i == 0 || bytes[i - 1] != b'\\' => in_quotes = !in_quotes,
+            b'i' if !in_quotes
+                && rest[i..].starts_with("if")
+                && (i == 0 || bytes[i - 1].is_ascii_whitespace())
+                && bytes.get(i + 2).is_some_and(|b| b.is_ascii_whitespace()) =>
+            {
+                return Conditional {
+                    value: rest[..i].trim().to_string(),
+                    condition: Some(rest[i + 2..].trim().to_string()),
+                };
+            }
+            _ => {}
+        }
+    }
+    Conditional {
+        value: rest.trim().to_string(),
+        condition: None,
+    }
+}
+
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')))
+        .unwrap_or(s)
+        .replace("\\\"", "\"")
+}
+
+/// The current value of a symbol in the tree's `.config`, for display.
+pub fn current_value(config: Option<&DotConfig>, name: &str) -> String {
+    match config.and_then(|config| config.get(name)) {
+        Some(Value::No) | None => "n".to_string(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// A Kconfig tristate value, ordered `n < m < y`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tristate {
+    No,
+    Module,
+    Yes,
+}
+
+impl Tristate {
+    fn from_value(value: &str) -> Tristate {
+        match value {
+            "y" => Tristate::Yes,
+            "m" => Tristate::Module,
+            _ => Tristate::No,
+        }
+    }
+}
+
+impl fmt::Display for Tristate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Tristate::Yes => "y",
+            Tristate::Module => "m",
+            Tristate::No => "n",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Symbol(String),
+    /// A quoted string, a number, `y`/`m`/`n` or an unexpanded `$(...)` macro.
+    Const(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A parsed `depends on`, `if` or `select ... if` expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Atom(Operand),
+    Compare(CompareOp, Operand, Operand),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, KconfigError> {
+        let bad = || KconfigError::BadExpression(source.to_string());
+        let tokens = tokenize(source).ok_or_else(bad)?;
+        let mut parser = ExprParser { tokens, pos: 0 };
+        let expr = parser.or().ok_or_else(bad)?;
+        if parser.pos != parser.tokens.len() {
+            return Err(bad());
+        }
+        Ok(expr)
+    }
+
+    /// Names of the symbols the expression refers to.
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_symbols(&mut names);
+        names
+    }
+
+    fn collect_symbols<'a>(&'a self, names: &mut Vec<&'a str>) {
+        let mut push = |operand: &'a Operand| {
+            if let Operand::Symbol(name) = operand {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        };
+        match self {
+            Expr::Atom(operand) => push(operand),
+            Expr::Compare(_, left, right) => {
+                push(left);
+                push(right);
+            }
+            Expr::Not(inner) => inner.collect_symbols(names),
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.collect_symbols(names);
+                right.collect_symbols(names);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Symbol(name) => f.write_str(name),
+            Operand::Const(value)
+                if value.starts_with("$(")
+                    || (!value.is_empty()
+                        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) =>
+            {
+                f.write_str(value)
+            }
+            Operand::Const(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Atom(operand) => write!(f, "{}", operand),
+            Expr::Compare(op, left, right) => write!(f, "{}{}{}", left, op, right),
+            Expr::Not(inner) => match **inner {
+                Expr::Atom(_) | Expr::Not(_) => write!(f, "!{}", inner),
+                _ => write!(f, "!({})", inner),
+            },
+            Expr::And(left, right) => {
+                for (i, side) in [left, right].into_iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" && ")?;
+                    }
+                    match **side {
+                        Expr::Or(..) => write!(f, "({})", side)?,
+                        _ => write!(f, "{}", side)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(left, right) => write!(f, "{} || {}", left, right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Not,
+    And,
+    Or,
+    Compare(CompareOp),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::And);
+                i += 2;
+            }
+            '|' if next == Some('|') => {
+                tokens.push(Token::Or);
+                i += 2;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Compare(CompareOp::Ne));
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(Token::Compare(CompareOp::Eq));
+                i += 1;
+            }
+            '<' | '>' => {
+                let op = match (c, next == Some('=')) {
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    _ => CompareOp::Ge,
+                };
+                tokens.push(Token::Compare(op));
+                i += if next == Some('=') { 2 } else { 1 };
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        i += 1;
+                    }
+                    value.push(chars[i]);
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return None;
+                }
+                tokens.push(Token::Quoted(value));
+                i += 1;
+            }
+            '$' if next == Some('(') => {
+                // Macros are kept verbatim; kcli cannot expand them.
+                let start = i;
+                let mut depth = 0;
+                while i < chars.len() {
+                    match chars[i] {
+                        '(' => depth += 1,
+                        ')' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return None;
+                }
+                i += 1;
+                tokens.push(Token::Quoted(chars[start..i].iter().collect()));
+            }
+            _ if c.is_ascii_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Word(chars[start..i].iter().collect()));
+            }
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Option<Expr> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Some(expr)
+    }
+
+    fn and(&mut self) -> Option<Expr> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Some(expr)
+    }
+
+    fn not(&mut self) -> Option<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            return Some(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let expr = self.or()?;
+            if self.peek() != Some(&Token::Close) {
+                return None;
+            }
+            self.pos += 1;
+            return Some(expr);
+        }
+        let left = self.operand()?;
+        if let Some(Token::Compare(op)) = self.peek().cloned() {
+            self.pos += 1;
+            let right = self.operand()?;
+            return Some(Expr::Compare(op, left, right));
+        }
+        Some(Expr::Atom(left))
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        let operand = match self.peek()? {
+            Token::Quoted(value) => Operand::Const(value.clone()),
+            Token::Word(word)
+                if matches!(word.as_str(), "y" | "m" | "n")
+                    || word.starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                Operand::Const(word.clone())
+            }
+            Token::Word(word) => Operand::Symbol(word.clone()),
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(operand)
+    }
+}
+
+/// Evaluates expressions against a `.config`, with optional overrides for
+/// values that are about to be set.
+pub struct Evaluator<'a> {
+    db: &'a KconfigDb,
+    config: Option<&'a DotConfig>,
+    overrides: BTreeMap<String, Tristate>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(db: &'a KconfigDb, config: Option<&'a DotConfig>) -> Self {
+        Evaluator {
+            db,
+            config,
+            overrides: BTreeMap::new(),
+        }
+    }
+
+    pub fn db(&self) -> &'a KconfigDb {
+        self.db
+    }
+
+    pub fn set_override(&mut self, name: &str, value: Tristate) {
+        self.overrides.insert(name.to_string(), value);
+    }
+
+    pub fn overrides(&self) -> &BTreeMap<String, Tristate> {
+        &self.overrides
+    }
+
+    pub fn restore_overrides(&mut self, overrides: BTreeMap<String, Tristate>) {
+        self.overrides = overrides;
+    }
+
+    /// The value of a symbol as Kconfig would compare it: `y`/`m`/`n` for
+    /// bool and tristate symbols, the raw value otherwise.
+    pub fn value(&self, name: &str) -> String {
+        if let Some(value) = self.overrides.get(name) {
+            return value.to_string();
+        }
+        match self.config.and_then(|config| config.get(name)) {
+            Some(Value::Str(s)) => s.clone(),
+            Some(Value::Number(n)) => n.clone(),
+            Some(value) => value.to_string(),
+            None => match self.db.get(name).map(|symbol| symbol.kind) {
+                Some(SymbolType::String | SymbolType::Int | SymbolType::Hex) => String::new(),
+                _ => "n".to_string(),
+            },
+        }
+    }
+
+    pub fn tristate(&self, name: &str) -> Tristate {
+        Tristate::from_value(&self.value(name))
+    }
+
+    fn operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Symbol(name) => self.value(name),
+            Operand::Const(value) => value.clone(),
+        }
+    }
+
+    pub fn eval(&self, expr: &Expr) -> Tristate {
+        match expr {
+            Expr::Atom(operand) => Tristate::from_value(&self.operand(operand)),
+            Expr::Compare(op, left, right) => {
+                let (left, right) = (self.operand(left), self.operand(right));
+                let ordering = match (parse_number(&left), parse_number(&right)) {
+                    (Some(l), Some(r)) => l.cmp(&r),
+                    _ => left.cmp(&right),
+                };
+                let result = match op {
+                    CompareOp::Eq => ordering.is_eq(),
+                    CompareOp::Ne => ordering.is_ne(),
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Le => ordering.is_le(),
+                    CompareOp::Gt => ordering.is_gt(),
+                    CompareOp::Ge => ordering.is_ge(),
+                };
+                if result {
+                    Tristate::Yes
+                } else {
+                    Tristate::No
+                }
+            }
+            Expr::Not(inner) => match self.eval(inner) {
+                Tristate::Yes => Tristate::No,
+                Tristate::Module => Tristate::Module,
+                Tristate::No => Tristate::Yes,
+            },
+            Expr::And(left, right) => self.eval(left).min(self.eval(right)),
+            Expr::Or(left, right) => self.eval(left).max(self.eval(right)),
+        }
+    }
+
+    /// Evaluates the expression source, treating expressions kcli cannot
+    /// parse as unmet.
+    pub fn eval_str(&self, source: &str) -> Tristate {
+        Expr::parse(source)
+            .map(|expr| self.eval(&expr))
+            .unwrap_or(Tristate::No)
+    }
+}
+
+fn parse_number(value: &str) -> Option<i64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,554 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A single Kconfig change requested by one of the kernel options. Symbols
+/// are given without the `CONFIG_` prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Toggle {
+    Enable(String),
+    Disable(String),
+    Module(String),
+    Value(String, String),
+    Str(String, String),
+}
+
+impl Toggle {
+    pub fn symbol(&self) -> &str {
+        match self {
+            Toggle::Enable(sym)
+            | Toggle::Disable(sym)
+            | Toggle::Module(sym)
+            | Toggle::Value(sym, _)
+            | Toggle::Str(sym, _) => sym,
+        }
+    }
+}
+
+impl fmt::Display for Toggle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Toggle::Enable(sym) => write!(f, "CONFIG_{}=y", sym),
+            Toggle::Disable(sym) => write!(f, "CONFIG_{}=n", sym),
+            Toggle::Module(sym) => write!(f, "CONFIG_{}=m", sym),
+            Toggle::Value(sym, value) => write!(f, "CONFIG_{}={}", sym, value),
+            Toggle::Str(sym, value) => write!(f, "CONFIG_{}=\"{}\"", sym, value),
+        }
+    }
+}
+
+fn enable(symbol: &str) -> Toggle {
+    Toggle::Enable(symbol.to_string())
+}
+
+fn disable(symbol: &str) -> Toggle {
+    Toggle::Disable(symbol.to_string())
+}
+
+fn set_val(symbol: &str, value: impl ToString) -> Toggle {
+    Toggle::Value(symbol.to_string(), value.to_string())
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid {option} value '{value}', expected one of: {expected}")]
+pub struct InvalidValue {
+    option: &'static str,
+    value: String,
+    expected: String,
+}
+
+/// A kernel option with a fixed set of choices. Each choice has the label
+/// shown in the menu, the value stored in JSON and the Kconfig toggles it
+/// maps to.
+pub trait ConfigChoice: Copy + PartialEq + 'static {
+    const ALL: &'static [Self];
+    const PROMPT: &'static str;
+
+    fn label(self) -> &'static str;
+    fn value(self) -> &'static str;
+    fn toggles(self) -> Vec<Toggle>;
+}
+
+/// Declares a `ConfigChoice` enum from a table of
+/// `Variant => "json value", "Menu label" | "alias"...;` rows. The enum
+/// provides the Kconfig side through an inherent `kconfig_toggles`. Labels and
+/// aliases are accepted when loading so that configs written by earlier
+/// versions, which stored the menu label, still load.
+macro_rules! config_choice {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident ($option:literal, $prompt:literal) {
+            $( $variant:ident => $value:literal, $label:literal $(| $alias:literal)*; )+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(try_from = "String", into = "String")]
+        pub enum $name {
+            $( $variant, )+
+        }
+
+        impl ConfigChoice for $name {
+            const ALL: &'static [Self] = &[$( $name::$variant, )+];
+            const PROMPT: &'static str = $prompt;
+
+            fn label(self) -> &'static str {
+                match self {
+                    $( $name::$variant => $label, )+
+                }
+            }
+
+            fn value(self) -> &'static str {
+                match self {
+                    $( $name::$variant => $value, )+
+                }
+            }
+
+            fn toggles(self) -> Vec<Toggle> {
+                self.kconfig_toggles()
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = InvalidValue;
+
+            fn try_from(value: String) -> Result<Self, InvalidValue> {
+                match value.as_str() {
+                    $( $value | $label $(| $alias)* => Ok($name::$variant), )+
+                    _ => Err(InvalidValue {
+                        option: $option,
+                        value,
+                        expected: [$( $value, )+].join(", "),
+                    }),
+                }
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(choice: $name) -> String {
+                choice.value().to_string()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.label())
+            }
+        }
+    };
+}
+
+config_choice! {
+    pub enum Architecture ("Architecture", "Architecture Configuration") {
+        Native => "native", "Native (-march=native)";
+        Generic => "generic", "Generic x86-64";
+        GenericV2 => "generic-v2", "x86-64-v2";
+        GenericV3 => "generic-v3", "x86-64-v3";
+        GenericV4 => "generic-v4", "x86-64-v4";
+    }
+}
+
+impl Architecture {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        let level = match self {
+            Architecture::Native => {
+                return vec![disable("GENERIC_CPU"), enable("X86_NATIVE_CPU")]
+            }
+            Architecture::Generic => "1",
+            Architecture::GenericV2 => "2",
+            Architecture::GenericV3 => "3",
+            Architecture::GenericV4 => "4",
+        };
+        vec![
+            disable("X86_NATIVE_CPU"),
+            enable("GENERIC_CPU"),
+            set_val("X86_64_VERSION", level),
+        ]
+    }
+}
+
+config_choice! {
+    pub enum CpuScheduler ("CPU scheduler", "CPU Scheduler Configuration") {
+        CachyOs => "cachyos", "CachyOS";
+        Bore => "bore", "BORE";
+        Pds => "pds", "PDS";
+        None => "none", "None";
+    }
+}
+
+impl CpuScheduler {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            CpuScheduler::CachyOs => vec![
+                enable("SCHED_CLASS_EXT"),
+                enable("SCHED_BORE"),
+                disable("SCHED_ALT"),
+            ],
+            CpuScheduler::Bore => vec![
+                disable("SCHED_CLASS_EXT"),
+                enable("SCHED_BORE"),
+                disable("SCHED_ALT"),
+            ],
+            CpuScheduler::Pds => vec![
+                disable("SCHED_BORE"),
+                enable("SCHED_ALT"),
+                enable("SCHED_PDS"),
+                disable("SCHED_BMQ"),
+            ],
+            CpuScheduler::None => vec![
+                disable("SCHED_BORE"),
+                disable("SCHED_CLASS_EXT"),
+                disable("SCHED_PDS"),
+            ],
+        }
+    }
+}
+
+config_choice! {
+    pub enum LlvmLto ("LLVM LTO", "LLVM LTO Configuration") {
+        Thin => "thin", "Thin";
+        Full => "full", "Full";
+        None => "none", "None";
+    }
+}
+
+impl LlvmLto {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            LlvmLto::Thin => vec![
+                disable("LTO_NONE"),
+                enable("LTO_CLANG"),
+                disable("LTO_CLANG_FULL"),
+                enable("LTO_CLANG_THIN"),
+            ],
+            LlvmLto::Full => vec![
+                disable("LTO_NONE"),
+                enable("LTO_CLANG"),
+                enable("LTO_CLANG_FULL"),
+                disable("LTO_CLANG_THIN"),
+            ],
+            LlvmLto::None => vec![
+                enable("LTO_NONE"),
+                disable("LTO_CLANG_FULL"),
+                disable("LTO_CLANG_THIN"),
+            ],
+        }
+    }
+}
+
+config_choice! {
+    pub enum TickRate ("tick rate", "Tick Rate Configuration") {
+        Hz100 => "100", "100 Hz";
+        Hz250 => "250", "250 Hz";
+        Hz300 => "300", "300 Hz";
+        Hz500 => "500", "500 Hz";
+        Hz600 => "600", "600 Hz";
+        Hz750 => "750", "750 Hz";
+        Hz1000 => "1000", "1000 Hz";
+    }
+}
+
+impl TickRate {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        let symbol = |rate: TickRate| match rate {
+            TickRate::Hz100 => "HZ_100",
+            TickRate::Hz250 => "HZ_250",
+            TickRate::Hz300 => "HZ_300",
+            TickRate::Hz500 => "HZ_500",
+            TickRate::Hz600 => "HZ_600",
+            TickRate::Hz750 => "HZ_750",
+            TickRate::Hz1000 => "HZ_1000",
+        };
+        let mut toggles: Vec<Toggle> = Self::ALL
+            .iter()
+            .filter(|rate| **rate != self)
+            .map(|rate| disable(symbol(*rate)))
+            .collect();
+        toggles.push(enable(symbol(self)));
+        toggles.push(set_val("HZ", self.value()));
+        toggles
+    }
+}
+
+config_choice! {
+    pub enum Hugepages ("hugepages", "Hugepages Configuration") {
+        Always => "always", "Always";
+        Madvise => "madvise", "Madvise";
+        No => "no", "No";
+    }
+}
+
+impl Hugepages {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            Hugepages::Always => vec![
+                enable("TRANSPARENT_HUGEPAGE"),
+                enable("TRANSPARENT_HUGEPAGE_ALWAYS"),
+                disable("TRANSPARENT_HUGEPAGE_MADVISE"),
+            ],
+            Hugepages::Madvise => vec![
+                enable("TRANSPARENT_HUGEPAGE"),
+                disable("TRANSPARENT_HUGEPAGE_ALWAYS"),
+                enable("TRANSPARENT_HUGEPAGE_MADVISE"),
+            ],
+            Hugepages::No => vec![disable("TRANSPARENT_HUGEPAGE")],
+        }
+    }
+}
+
+config_choice! {
+    pub enum Lru ("LRU", "LRU Configuration") {
+        Standard => "standard", "Standard";
+        Stats => "stats", "Stats";
+        None => "none", "None";
+    }
+}
+
+impl Lru {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            Lru::Standard => vec![
+                enable("LRU_GEN"),
+                enable("LRU_GEN_ENABLED"),
+                disable("LRU_GEN_STATS"),
+            ],
+            Lru::Stats => vec![
+                enable("LRU_GEN"),
+                enable("LRU_GEN_ENABLED"),
+                enable("LRU_GEN_STATS"),
+            ],
+            Lru::None => vec![disable("LRU_GEN")],
+        }
+    }
+}
+
+config_choice! {
+    pub enum TickType ("tick type", "Tick Type Configuration") {
+        Periodic => "periodic", "Periodic";
+        NoHzFull => "nohz_full", "NoHz_Full";
+        NoHzIdle => "nohz_idle", "NoHz_Idle";
+    }
+}
+
+impl TickType {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            TickType::Periodic => vec![
+                enable("HZ_PERIODIC"),
+                disable("NO_HZ_IDLE"),
+                disable("NO_HZ_FULL"),
+                disable("NO_HZ"),
+                disable("NO_HZ_COMMON"),
+            ],
+            TickType::NoHzFull => vec![
+                disable("HZ_PERIODIC"),
+                disable("NO_HZ_IDLE"),
+                disable("CONTEXT_TRACKING_FORCE"),
+                enable("NO_HZ_FULL_NODEF"),
+                enable("NO_HZ_FULL"),
+                enable("NO_HZ"),
+                enable("NO_HZ_COMMON"),
+                enable("CONTEXT_TRACKING"),
+            ],
+            TickType::NoHzIdle => vec![
+                disable("HZ_PERIODIC"),
+                disable("NO_HZ_FULL"),
+                enable("NO_HZ_IDLE"),
+                enable("NO_HZ"),
+                enable("NO_HZ_COMMON"),
+            ],
+        }
+    }
+}
+
+config_choice! {
+    pub enum PreemptType ("preempt type", "Preempt Type Configuration") {
+        Full => "full", "Full (Preempt)" | "Preempt";
+        Voluntary => "voluntary", "Voluntary";
+        None => "none", "None";
+    }
+}
+
+impl PreemptType {
+    fn kconfig_toggles(self) -> Vec<Toggle> {
+        match self {
+            PreemptType::Full => vec![
+                enable("PREEMPT_BUILD"),
+                disable("PREEMPT_NONE"),
+                disable("PREEMPT_VOLUNTARY"),
+                enable("PREEMPT"),
+                enable("PREEMPT_COUNT"),
+                enable("PREEMPTION"),
+                enable("PREEMPT_DYNAMIC"),
+            ],
+            PreemptType::Voluntary => vec![
+                enable("PREEMPT_BUILD"),
+                disable("PREEMPT_NONE"),
+                enable("PREEMPT_VOLUNTARY"),
+                disable("PREEMPT"),
+                enable("PREEMPT_COUNT"),
+                enable("PREEMPTION"),
+                disable("PREEMPT_DYNAMIC"),
+            ],
+            PreemptType::None => vec![
+                enable("PREEMPT_NONE_BUILD"),
+                enable("PREEMPT_NONE"),
+                disable("PREEMPT_VOLUNTARY"),
+                disable("PREEMPT"),
+                disable("PREEMPTION"),
+                disable("PREEMPT_DYNAMIC"),
+            ],
+        }
+    }
+}
+
+/// Maximum number of CPUs (`CONFIG_NR_CPUS`), within the range x86_64 accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NrCpus(u32);
+
+impl NrCpus {
+    pub const MAX: u32 = 8192;
+    pub const PRESETS: &'static [u32] = &[2, 4, 8, 16, 32, 64, 128, 256, 320, 512];
+
+    pub fn new(count: u32) -> Result<Self, InvalidValue> {
+        if (2..=Self::MAX).contains(&count) {
+            Ok(NrCpus(count))
+        } else {
+            Err(InvalidValue {
+                option: "NR_CPUS",
+                value: count.to_string(),
+                expected: format!("a number between 2 and {}", Self::MAX),
+            })
+        }
+    }
+
+    pub fn toggles(self) -> Vec<Toggle> {
+        vec![set_val("NR_CPUS", self.0)]
+    }
+}
+
+impl TryFrom<String> for NrCpus {
+    type Error = InvalidValue;
+
+    fn try_from(value: String) -> Result<Self, InvalidValue> {
+        match value.trim().parse::<u32>() {
+            // Earlier versions offered 1, which x86_64 does not accept
+            Ok(1) => {
+                eprintln!("warning: NR_CPUS 1 is below the minimum of 2; using 2");
+                Ok(NrCpus(2))
+            }
+            Ok(count) => NrCpus::new(count),
+            Err(_) => Err(InvalidValue {
+                option: "NR_CPUS",
+                value,
+                expected: format!("a number between 2 and {}", Self::MAX),
+            }),
+        }
+    }
+}
+
+impl From<NrCpus> for String {
+    fn from(nr_cpus: NrCpus) -> String {
+        nr_cpus.0.to_string()
+    }
+}
+
+impl fmt::Display for NrCpus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KernelConfig {
+    pub architecture: Architecture,
+    pub cpusched_selection: CpuScheduler,
+    pub llvm_lto_selection: LlvmLto,
+    pub tick_rate: TickRate,
+    pub nr_cpus: NrCpus,
+    pub hugepages: Hugepages,
+    pub lru: Lru,
+    pub tick_type: TickType,
+    pub preempt_type: PreemptType,
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        Self {
+            architecture: Architecture::Native,
+            cpusched_selection: CpuScheduler::None,
+            llvm_lto_selection: LlvmLto::None,
+            tick_rate: TickRate::Hz600,
+            nr_cpus: NrCpus(400),
+            hugepages: Hugepages::Always,
+            lru: Lru::Standard,
+            tick_type: TickType::Periodic,
+            preempt_type: PreemptType::Voluntary,
+        }
+    }
+}
+
+impl KernelConfig {
+    pub fn save_to_file(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?; // Ensure the directory exists
+        }
+        let serialized = serde_json::to_string_pretty(self)?;
+        fs::write(path, serialized)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Loads the configuration saved at `path`, falling back to the defaults
+    /// only when nothing has been saved yet. A file with unknown values is an
+    /// error.
+    pub fn load_or_default(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid kernel configuration in {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        }
+    }
+
+    /// Options whose stored values differ, as `(option, self, other)`.
+    pub fn diff(&self, other: &KernelConfig) -> Vec<(String, String, String)> {
+        let ours = serde_json::to_value(self).unwrap_or_default();
+        let theirs = serde_json::to_value(other).unwrap_or_default();
+        let (Some(ours), Some(theirs)) = (ours.as_object(), theirs.as_object()) else {
+            return Vec::new();
+        };
+        ours.iter()
+            .filter_map(|(option, value)| {
+                let other_value = theirs.get(option)?;
+                (value != other_value).then(|| {
+                    (
+                        option.clone(),
+                        value.as_str().unwrap_or_default().to_string(),
+                        other_value.as_str().unwrap_or_default().to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Every Kconfig change implied by the selected options, in apply order.
+    pub fn toggles(&self) -> Vec<Toggle> {
+        let mut toggles = self.architecture.toggles();
+        toggles.extend(self.cpusched_selection.toggles());
+        toggles.extend(self.llvm_lto_selection.toggles());
+        toggles.extend(self.hugepages.toggles());
+        toggles.extend(self.lru.toggles());
+        toggles.extend(self.preempt_type.toggles());
+        toggles.extend(self.tick_rate.toggles());
+        toggles.extend(self.nr_cpus.toggles());
+        toggles.extend(self.tick_type.toggles());
+        toggles
+    }
+}
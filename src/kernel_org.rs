@@ -0,0 +1,385 @@
+use anyhow::{Context, Result};
+use dirs_next::{cache_dir, config_dir};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::metadata::{Origin, TreeMetadata};
+use crate::Error;
+
+const DEFAULT_MIRROR: &str = "https://www.kernel.org";
+const DEFAULT_CDN: &str = "https://cdn.kernel.org";
+
+/// Environment variable pointing kcli at a kernel.org mirror, or at a local
+/// stand-in serving `releases.json` and `/pub/linux/kernel`.
+pub const MIRROR_ENV: &str = "KCLI_KERNEL_ORG_MIRROR";
+
+/// How long the cached release list is used before asking kernel.org again.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+const LINUX_NEXT_SNAPSHOT_URL: &str =
+    "https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git/snapshot";
+
+#[derive(Deserialize)]
+struct ReleaseFeed {
+    releases: Vec<Release>,
+}
+
+/// One entry of kernel.org's `releases.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Release {
+    /// `mainline`, `stable`, `longterm` or `linux-next`.
+    pub moniker: String,
+    pub version: String,
+    pub iseol: bool,
+    pub released: Released,
+    /// Tarball URL; kernel.org publishes none for linux-next.
+    pub source: Option<String>,
+    /// Detached signature of the uncompressed tarball.
+    pub pgp: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Released {
+    pub timestamp: i64,
+    pub isodate: String,
+}
+
+impl Release {
+    fn tarball_url(&self) -> String {
+        match &self.source {
+            Some(source) => mirrored(source),
+            None => format!(
+                "{}/linux-next-{}.tar.gz",
+                LINUX_NEXT_SNAPSHOT_URL, self.version
+            ),
+        }
+    }
+
+    /// Directory name the release is unpacked to under the source directory.
+    pub fn tree_name(&self) -> String {
+        format!("linux-{}", self.version)
+    }
+}
+
+impl fmt::Display for Release {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<10} {:<14} {}",
+            self.moniker, self.version, self.released.isodate
+        )?;
+        if self.iseol {
+            f.write_str(" (EOL)")?;
+        }
+        Ok(())
+    }
+}
+
+/// kernel.org's list of current releases, cached on disk so menus keep
+/// working offline.
+pub struct ReleaseIndex {
+    releases: Vec<Release>,
+    /// When the list was fetched, if it came from a cache kcli could not
+    /// refresh.
+    stale_since: Option<SystemTime>,
+}
+
+impl ReleaseIndex {
+    /// Uses the cached list while it is younger than an hour, otherwise
+    /// fetches `releases.json` from the mirror. When the mirror cannot be
+    /// reached an older cache is used instead of failing.
+    pub async fn load() -> Result<Self> {
+        let mirror = std::env::var(MIRROR_ENV).unwrap_or_else(|_| DEFAULT_MIRROR.to_string());
+        Self::load_from(&mirror, &cache_path()?).await
+    }
+
+    async fn load_from(mirror: &str, cache: &Path) -> Result<Self> {
+        let cached_at = fs::metadata(cache).and_then(|m| m.modified()).ok();
+        let fresh = cached_at
+            .and_then(|time| time.elapsed().ok())
+            .is_some_and(|age| age < CACHE_TTL);
+        if fresh {
+            if let Ok(releases) = Self::read_cache(cache) {
+                return Ok(ReleaseIndex {
+                    releases,
+                    stale_since: None,
+                });
+            }
+        }
+
+        match Self::fetch(mirror).await {
+            Ok(body) => {
+                let feed: ReleaseFeed = serde_json::from_str(&body).map_err(Error::Parse)?;
+                if let Some(parent) = cache.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(cache, &body)
+                    .with_context(|| format!("Failed to write {}", cache.display()))?;
+                Ok(ReleaseIndex {
+                    releases: feed.releases,
+                    stale_since: None,
+                })
+            }
+            Err(err) => match (cached_at, Self::read_cache(cache)) {
+                (Some(time), Ok(releases)) => Ok(ReleaseIndex {
+                    releases,
+                    stale_since: Some(time),
+                }),
+                _ => Err(err),
+            },
+        }
+    }
+
+    async fn fetch(mirror: &str) -> Result<String> {
+        let url = format!("{}/releases.json", mirror.trim_end_matches('/'));
+        let body = reqwest::get(&url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::from)
+            .with_context(|| format!("Failed to download {}", url))?
+            .text()
+            .await
+            .map_err(Error::from)?;
+        Ok(body)
+    }
+
+    fn read_cache(cache: &Path) -> Result<Vec<Release>> {
+        let body = fs::read_to_string(cache)?;
+        let feed: ReleaseFeed = serde_json::from_str(&body).map_err(Error::Parse)?;
+        Ok(feed.releases)
+    }
+
+    pub fn releases(&self) -> &[Release] {
+        &self.releases
+    }
+
+    /// A release by version, or the newest one of a moniker such as
+    /// `stable` or `longterm`.
+    pub fn find(&self, release: &str) -> Option<&Release> {
+        self.releases
+            .iter()
+            .find(|r| r.version == release)
+            .or_else(|| self.releases.iter().find(|r| r.moniker == release))
+    }
+
+    /// Prints a warning when the list is an outdated offline copy.
+    pub fn warn_if_stale(&self) {
+        if let Some(time) = self.stale_since {
+            let time: chrono::DateTime<chrono::Local> = time.into();
+            println!(
+                "warning: kernel.org is unreachable, using the release list from {}",
+                time.format("%Y-%m-%d %H:%M")
+            );
+        }
+    }
+}
+
+/// Keyring holding the kernel.org release signing keys, e.g. created with
+/// `gpg --no-default-keyring --keyring <path> --locate-keys torvalds@kernel.org gregkh@kernel.org`.
+pub fn keyring_path() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli")
+        .join("kernel.org.gpg"))
+}
+
+fn kcli_cache_dir() -> Result<PathBuf> {
+    Ok(cache_dir()
+        .context("Failed to locate the cache directory")?
+        .join("kcli"))
+}
+
+fn cache_path() -> Result<PathBuf> {
+    Ok(kcli_cache_dir()?.join("releases.json"))
+}
+
+fn downloads_dir() -> Result<PathBuf> {
+    let dir = kcli_cache_dir()?.join("downloads");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Downloads `url` to `target`, replacing it only once the download completed.
+pub async fn download(url: &str, target: &Path) -> Result<()> {
+    println!("Downloading {}", url);
+    let mut response = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(Error::from)
+        .with_context(|| format!("Failed to download {}", url))?;
+
+    // Write to a partial file so an interrupted download is never reused.
+    let partial = target.with_extension("part");
+    let mut file = tokio::fs::File::create(&partial)
+        .await
+        .with_context(|| format!("Failed to create {}", partial.display()))?;
+    while let Some(chunk) = response.chunk().await.map_err(Error::from)? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    tokio::fs::rename(&partial, target).await?;
+    Ok(())
+}
+
+/// Downloads `url` into the download cache unless it is already there.
+async fn download_cached(url: &str) -> Result<PathBuf> {
+    let file_name = url.rsplit('/').next().unwrap_or("download");
+    let path = downloads_dir()?.join(file_name);
+    if !path.is_file() {
+        download(url, &path).await?;
+    }
+    Ok(path)
+}
+
+/// Downloads a compressed file and its detached signature and checks the
+/// signature, returning the path of the verified file.
+pub async fn download_verified(url: &str, signature_url: &str) -> Result<PathBuf> {
+    let path = download_cached(url).await?;
+    let signature = path.with_extension("sign");
+    download(signature_url, &signature).await?;
+    if let Err(err) = verify_signature(&path, &signature).await {
+        // Never keep a file that failed verification around.
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+/// URL of a file under kernel.org's `/pub/linux/kernel`, on the mirror when
+/// one is configured.
+pub fn pub_url(path: &str) -> String {
+    let base = std::env::var(MIRROR_ENV).unwrap_or_else(|_| DEFAULT_CDN.to_string());
+    format!("{}/pub/linux/kernel/{}", base.trim_end_matches('/'), path)
+}
+
+/// Moves a URL under kernel.org's `/pub/linux/kernel`, as `releases.json`
+/// lists them, to the mirror when one is configured.
+fn mirrored(url: &str) -> String {
+    let path = ["https://cdn.kernel.org", "https://www.kernel.org"]
+        .iter()
+        .find_map(|host| url.strip_prefix(host)?.strip_prefix("/pub/linux/kernel/"));
+    match path {
+        Some(path) => pub_url(path),
+        None => url.to_string(),
+    }
+}
+
+/// Checks a detached signature of the uncompressed file against the local
+/// kernel.org keyring.
+async fn verify_signature(file: &Path, signature: &Path) -> Result<()> {
+    let keyring = keyring_path()?;
+    if !keyring.is_file() {
+        anyhow::bail!(
+            "No kernel.org keyring at {}. Create it with:\n  gpg --no-default-keyring --keyring {} --locate-keys torvalds@kernel.org gregkh@kernel.org",
+            keyring.display(),
+            keyring.display()
+        );
+    }
+    let decompress = if file.extension().is_some_and(|ext| ext == "xz") {
+        "xz"
+    } else {
+        "gzip"
+    };
+    let mut decompressor = Command::new(decompress)
+        .arg("-dc")
+        .arg(file)
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute {}", decompress))?;
+    let uncompressed: Stdio = decompressor
+        .stdout
+        .take()
+        .context("Failed to read the decompressed file")?
+        .try_into()?;
+    let output = Command::new("gpg")
+        .args(["--batch", "--no-default-keyring", "--keyring"])
+        .arg(&keyring)
+        .arg("--verify")
+        .arg(signature)
+        .arg("-")
+        .stdin(uncompressed)
+        .output()
+        .await
+        .context("Failed to execute gpg")?;
+    let decompressed = decompressor.wait().await?.success();
+    if !output.status.success() || !decompressed {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(Error::CommandFailed("gpg --verify".to_string()).into());
+    }
+    println!("Good signature on {}.", file.display());
+    Ok(())
+}
+
+/// Downloads a release tarball, verifies its signature and unpacks it to
+/// `packages_dir/linux-<version>`. Releases without a published signature
+/// (release candidates and linux-next) are only unpacked when
+/// `allow_unsigned` is set.
+pub async fn install_release(
+    release: &Release,
+    packages_dir: &Path,
+    allow_unsigned: bool,
+) -> Result<PathBuf> {
+    let target_dir = packages_dir.join(release.tree_name());
+    if target_dir.exists() {
+        anyhow::bail!("{} already exists", target_dir.display());
+    }
+
+    let tarball = match &release.pgp {
+        Some(pgp) => download_verified(&release.tarball_url(), &mirrored(pgp)).await?,
+        None if allow_unsigned => {
+            println!(
+                "warning: kernel.org publishes no signature for {}",
+                release.version
+            );
+            download_cached(&release.tarball_url()).await?
+        }
+        None => anyhow::bail!(
+            "kernel.org publishes no signature for {}; allow unsigned downloads to use it",
+            release.version
+        ),
+    };
+
+    // Unpack next to the target and rename, since the top-level directory
+    // inside the tarball does not always match the version.
+    let unpack_dir = packages_dir.join(format!(".unpack-{}", release.version));
+    fs::create_dir_all(&unpack_dir)?;
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(&unpack_dir)
+        .status()
+        .await
+        .context("Failed to execute tar")?;
+    if !status.success() {
+        let _ = fs::remove_dir_all(&unpack_dir);
+        return Err(Error::CommandFailed("tar -xf".to_string()).into());
+    }
+    let top = fs::read_dir(&unpack_dir)?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().is_dir())
+        .context("The tarball contains no source directory")?;
+    fs::rename(top.path(), &target_dir)?;
+    fs::remove_dir_all(&unpack_dir)?;
+    TreeMetadata::record_origin(
+        &target_dir,
+        Some(release.version.clone()),
+        Origin {
+            url: release.tarball_url(),
+            commit: None,
+        },
+    )?;
+
+    println!(
+        "Kernel {} unpacked to {}",
+        release.version,
+        target_dir.display()
+    );
+    Ok(target_dir)
+}
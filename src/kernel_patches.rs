@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use dirs_next::config_dir;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::git_source::git;
+use crate::Error;
+
+const DEFAULT_REMOTE: &str = "https://github.com/CachyOS/kernel-patches";
+
+/// Git config key in the clone holding the pinned revision.
+const PIN_KEY: &str = "kcli.pin";
+
+/// The kernel-patches revision a tree's patches came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchesRevision {
+    pub commit: String,
+    pub remote: String,
+    /// Commit, branch or tag the clone was pinned to, if any.
+    pub pin: Option<String>,
+}
+
+/// What `update` should change besides fetching.
+#[derive(Debug, Default)]
+pub struct UpdateOptions {
+    /// Fetch from this URL or path from now on, e.g. a fork or a local mirror.
+    pub remote: Option<String>,
+    /// Pin the clone to a commit, branch or tag.
+    pub pin: Option<String>,
+    /// Follow the remote's default branch again.
+    pub unpin: bool,
+}
+
+/// Location of the clone, `~/.config/kcli/kernel-patches`.
+pub fn clone_path() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli")
+        .join("kernel-patches"))
+}
+
+async fn clone(remote: &str, path: &Path) -> Result<()> {
+    println!("Cloning {} into {}", remote, path.display());
+    let status = Command::new("git")
+        .arg("clone")
+        .arg(remote)
+        .arg(path)
+        .status()
+        .await
+        .context("Failed to execute git")?;
+    if !status.success() {
+        return Err(Error::CommandFailed("git clone".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Clones kernel-patches on first use and returns its location.
+pub async fn ensure_clone() -> Result<PathBuf> {
+    let path = clone_path()?;
+    if !path.join(".git").exists() {
+        clone(DEFAULT_REMOTE, &path).await?;
+    }
+    Ok(path)
+}
+
+async fn pin(path: &Path) -> Option<String> {
+    git(path, &["config", "--get", PIN_KEY]).await.ok()
+}
+
+/// The revision the clone is at, or `None` before it was cloned.
+pub async fn revision() -> Result<Option<PatchesRevision>> {
+    let path = clone_path()?;
+    if !path.join(".git").exists() {
+        return Ok(None);
+    }
+    Ok(Some(PatchesRevision {
+        commit: git(&path, &["rev-parse", "HEAD"]).await?,
+        remote: git(&path, &["config", "--get", "remote.origin.url"]).await?,
+        pin: pin(&path).await,
+    }))
+}
+
+/// Resolves a pin to a commit, preferring the remote's branches so a pinned
+/// branch follows new pushes.
+async fn resolve(path: &Path, reference: &str) -> Result<String> {
+    let candidates = [
+        format!("refs/remotes/origin/{}", reference),
+        format!("refs/tags/{}", reference),
+        reference.to_string(),
+    ];
+    for candidate in &candidates {
+        let spec = format!("{}^{{commit}}", candidate);
+        if let Ok(commit) = git(path, &["rev-parse", "--verify", "--quiet", &spec]).await {
+            return Ok(commit);
+        }
+    }
+    anyhow::bail!(
+        "'{}' is not a commit, branch or tag of kernel-patches",
+        reference
+    )
+}
+
+/// Fetches the kernel-patches clone and moves it to the pinned revision, or
+/// fast-forwards it along the remote's default branch, then prints what
+/// changed.
+pub async fn update(options: &UpdateOptions) -> Result<()> {
+    let path = clone_path()?;
+    if !path.join(".git").exists() {
+        let remote = options.remote.as_deref().unwrap_or(DEFAULT_REMOTE);
+        clone(remote, &path).await?;
+    } else if let Some(remote) = &options.remote {
+        git(&path, &["remote", "set-url", "origin", remote]).await?;
+    }
+
+    let old = git(&path, &["rev-parse", "HEAD"]).await?;
+    let remote = git(&path, &["config", "--get", "remote.origin.url"]).await?;
+    println!("Fetching {}", remote);
+    git(&path, &["fetch", "--tags", "--prune", "origin"]).await?;
+
+    if let Some(reference) = &options.pin {
+        // Only keep pins that exist
+        resolve(&path, reference).await?;
+        git(&path, &["config", PIN_KEY, reference]).await?;
+    } else if options.unpin {
+        // Fails when nothing was pinned, which is fine
+        let _ = git(&path, &["config", "--unset", PIN_KEY]).await;
+    }
+
+    match pin(&path).await {
+        Some(reference) => {
+            let commit = resolve(&path, &reference).await?;
+            git(&path, &["checkout", "--quiet", "--detach", &commit]).await?;
+            println!("kernel-patches is pinned to {}.", reference);
+        }
+        None => {
+            git(&path, &["remote", "set-head", "origin", "--auto"]).await?;
+            let upstream = git(
+                &path,
+                &["symbolic-ref", "--short", "refs/remotes/origin/HEAD"],
+            )
+            .await?;
+            let branch = upstream.trim_start_matches("origin/");
+            if git(&path, &["symbolic-ref", "--quiet", "HEAD"])
+                .await
+                .is_err()
+            {
+                // Leaving a pin: go back to the default branch
+                git(&path, &["checkout", "--quiet", branch]).await?;
+            }
+            git(&path, &["merge", "--ff-only", "--quiet", &upstream]).await?;
+        }
+    }
+
+    let new = git(&path, &["rev-parse", "HEAD"]).await?;
+    print_changes(&path, &old, &new).await
+}
+
+async fn print_changes(path: &Path, old: &str, new: &str) -> Result<()> {
+    let short = |commit: &str| commit.chars().take(12).collect::<String>();
+    if old == new {
+        println!("kernel-patches is up to date at {}.", short(new));
+        return Ok(());
+    }
+    println!(
+        "kernel-patches moved from {} to {}:",
+        short(old),
+        short(new)
+    );
+    let range = format!("{}..{}", old, new);
+    let log = git(
+        path,
+        &["log", "--oneline", "--no-decorate", "-n", "50", &range],
+    )
+    .await?;
+    if log.is_empty() {
+        println!("  (the new revision is not a descendant of the old one)");
+    }
+    for line in log.lines() {
+        println!("  {}", line);
+    }
+    let stat = git(path, &["diff", "--shortstat", old, new]).await?;
+    if !stat.is_empty() {
+        println!("{}", stat.trim());
+    }
+    Ok(())
+}
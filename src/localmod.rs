@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use dirs_next::config_dir;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::dotconfig::{DotConfig, Value};
+use crate::kernel_config::Toggle;
+
+const PROC_MODULES: &str = "/proc/modules";
+
+/// Which Kconfig symbols build which modules, read from the tree's Kbuild
+/// makefiles the same way `make localmodconfig` does.
+#[derive(Default)]
+pub struct ModuleMap {
+    /// Module name, with `-` normalised to `_`, to the symbols building it.
+    modules: BTreeMap<String, BTreeSet<String>>,
+    /// Size of the sources behind each symbol's modules, in bytes.
+    source_bytes: BTreeMap<String, u64>,
+}
+
+/// Module count and estimated build size of a `.config`.
+pub struct ModuleStats {
+    pub modules: usize,
+    pub source_bytes: u64,
+}
+
+impl ModuleMap {
+    pub fn scan(kernel_dir: &Path) -> Result<Self> {
+        let obj_re = Regex::new(r"^obj-\$\(CONFIG_([A-Za-z0-9_]+)\)\s*[+:]?=\s*(.*)$")?;
+        let composite_re = Regex::new(
+            r"^([A-Za-z0-9_-]+)-(?:objs|y|\$\(CONFIG_[A-Za-z0-9_]+\))\s*[+:]?=\s*(.*)$",
+        )?;
+
+        let mut map = ModuleMap::default();
+        let walker = WalkDir::new(kernel_dir).into_iter().filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            !(entry.depth() == 1 && matches!(name.as_ref(), ".git" | "tools" | "Documentation"))
+        });
+        for entry in walker.filter_map(Result::ok) {
+            let name = entry.file_name();
+            if name != "Makefile" && name != "Kbuild" {
+                continue;
+            }
+            let dir = entry.path().parent().unwrap_or(kernel_dir);
+            let Ok(contents) = fs::read_to_string(entry.path()) else {
+                continue;
+            };
+            let contents = contents.replace("\\\n", " ");
+
+            // Objects making up composite modules, e.g. `foo-y += a.o b.o`.
+            let mut composites: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+            for line in contents.lines() {
+                if let Some(caps) = composite_re.captures(line.trim()) {
+                    let objects = caps.get(2).map_or("", |m| m.as_str()).split_whitespace();
+                    composites
+                        .entry(caps.get(1).map_or("", |m| m.as_str()))
+                        .or_default()
+                        .extend(objects);
+                }
+            }
+
+            for line in contents.lines() {
+                let Some(caps) = obj_re.captures(line.trim()) else {
+                    continue;
+                };
+                let symbol = caps[1].to_string();
+                for object in caps[2].split_whitespace() {
+                    let Some(module) = object.strip_suffix(".o") else {
+                        continue;
+                    };
+                    let objects = composites
+                        .get(module)
+                        .cloned()
+                        .unwrap_or_else(|| vec![object]);
+                    let bytes: u64 = objects.iter().map(|object| source_size(dir, object)).sum();
+                    *map.source_bytes.entry(symbol.clone()).or_default() += bytes;
+                    map.modules
+                        .entry(module.replace('-', "_"))
+                        .or_default()
+                        .insert(symbol.clone());
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Toggles disabling every module symbol in `config` that builds none of
+    /// the `needed` modules. Symbols kcli cannot map to a module are kept.
+    pub fn unused(
+        &self,
+        config: &DotConfig,
+        needed: &BTreeSet<String>,
+        allowlist: &BTreeSet<String>,
+    ) -> Vec<Toggle> {
+        let mut keep: BTreeSet<&str> = BTreeSet::new();
+        for module in needed.iter().chain(allowlist) {
+            if let Some(symbols) = self.modules.get(&module.replace('-', "_")) {
+                keep.extend(symbols.iter().map(String::as_str));
+            }
+        }
+        keep.extend(
+            allowlist
+                .iter()
+                .filter_map(|entry| entry.strip_prefix("CONFIG_")),
+        );
+
+        let mapped: BTreeSet<&str> = self
+            .modules
+            .values()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        config
+            .symbols()
+            .filter(|(symbol, value)| {
+                **value == Value::Module && mapped.contains(symbol) && !keep.contains(symbol)
+            })
+            .map(|(symbol, _)| Toggle::Disable(symbol.to_string()))
+            .collect()
+    }
+
+    pub fn stats(&self, config: &DotConfig) -> ModuleStats {
+        let modules: Vec<&str> = config
+            .symbols()
+            .filter(|(_, value)| **value == Value::Module)
+            .map(|(symbol, _)| symbol)
+            .collect();
+        ModuleStats {
+            modules: modules.len(),
+            source_bytes: modules
+                .iter()
+                .filter_map(|symbol| self.source_bytes.get(*symbol))
+                .sum(),
+        }
+    }
+}
+
+/// Size of the C or assembly source behind an object file; subdirectory
+/// entries such as `foo/` are counted through their own makefiles.
+fn source_size(dir: &Path, object: &str) -> u64 {
+    let Some(stem) = object.strip_suffix(".o") else {
+        return 0;
+    };
+    ["c", "S"]
+        .iter()
+        .find_map(|ext| fs::metadata(dir.join(format!("{}.{}", stem, ext))).ok())
+        .map_or(0, |metadata| metadata.len())
+}
+
+/// Modules loaded on this machine, or listed in `lsmod` snapshots taken
+/// elsewhere. Several snapshots are merged.
+pub fn loaded_modules(snapshots: &[PathBuf]) -> Result<BTreeSet<String>> {
+    let sources = if snapshots.is_empty() {
+        vec![PathBuf::from(PROC_MODULES)]
+    } else {
+        snapshots.to_vec()
+    };
+    let mut modules = BTreeSet::new();
+    for path in sources {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read module list {}", path.display()))?;
+        // Both `/proc/modules` and `lsmod` start each line with the module
+        // name; `lsmod` adds a "Module Size Used by" header.
+        modules.extend(
+            contents
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .filter(|name| *name != "Module")
+                .map(|name| name.replace('-', "_")),
+        );
+    }
+    Ok(modules)
+}
+
+pub fn allowlist_path() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli")
+        .join("modules-allowlist"))
+}
+
+/// Module names or `CONFIG_` symbols that are never trimmed, one per line.
+pub fn allowlist() -> Result<BTreeSet<String>> {
+    let path = allowlist_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Disables the modules in the tree's `.config` that none of the listed
+/// machines load, keeping the allowlist, and prints the before and after
+/// module count and estimated build size.
+pub async fn trim_modules(kernel_dir: &Path, snapshots: &[PathBuf]) -> Result<()> {
+    crate::ensure_kernel_config(kernel_dir).await?;
+    let config_path = kernel_dir.join(".config");
+
+    let needed = loaded_modules(snapshots)?;
+    let allowlist = allowlist()?;
+    println!("Scanning Kbuild makefiles...");
+    let map = ModuleMap::scan(kernel_dir)?;
+
+    let config = DotConfig::load(&config_path)?;
+    let before = map.stats(&config);
+    let toggles = map.unused(&config, &needed, &allowlist);
+    println!(
+        "{} modules in use, {} allowlisted, {} module options to disable.",
+        needed.len(),
+        allowlist.len(),
+        toggles.len()
+    );
+    if toggles.is_empty() {
+        return Ok(());
+    }
+    crate::apply_kernel_toggles(kernel_dir, &toggles).await?;
+
+    let after = map.stats(&DotConfig::load(&config_path)?);
+    println!(
+        "Modules:              {} -> {}",
+        before.modules, after.modules
+    );
+    println!(
+        "Estimated build size: {} -> {} of module sources",
+        format_size(before.source_bytes),
+        format_size(after.source_bytes)
+    );
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -1,74 +1,212 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use dialoguer::{theme::ColorfulTheme, Input, Select};
-use directories::BaseDirs;
+use clap::{Parser, Subcommand, ValueEnum};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use dirs_next::config_dir;
-use fs_extra::dir::{copy, CopyOptions};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::fs::File;
-use std::io::{self, BufRead};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
 use tokio::process::Command;
-use tokio::process::Command as TokioCommand;
-use num_cpus;
 
+mod config_diff;
+mod conflicts;
+mod dotconfig;
+mod editor;
+mod explain;
+mod git_source;
+mod kconfig;
+mod kernel_config;
+mod kernel_org;
+mod kernel_patches;
+mod localmod;
+mod metadata;
+mod patch;
+mod patch_backend;
+mod patch_sources;
 mod pkg_manager;
+mod preflight;
+mod profiles;
+mod recipe;
+mod rejects;
+mod seed;
+mod series;
+mod snapshot;
+mod update;
+
+use config_diff::{ConfigDiff, DiffFormat};
+use dotconfig::DotConfig;
+use explain::Explanation;
+use git_source::Mirror;
+use kernel_org::ReleaseIndex;
+use kernel_patches::UpdateOptions;
+use metadata::{Origin, TreeMetadata};
+use patch_backend::PatchBackend;
+use patch_sources::Location;
+use kconfig::{Evaluator, KconfigDb, SymbolType, Tristate};
+use kernel_config::{ConfigChoice, KernelConfig, NrCpus, Toggle};
+use profiles::ConfigSource;
+use recipe::{BuildSettings, Recipe};
+use seed::SeedSource;
+use series::Series;
+
+/// Searches the Kconfig symbols of a source tree by name, prompt and help
+/// text, and sets the chosen symbol after checking the value against its type.
+async fn search_and_configure_option(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
+    let Some(kernel_dir) = select_kernel_tree(theme, packages_dir).await? else {
+        return Ok(());
+    };
+    let db = KconfigDb::load(&kernel_dir)?;
+    println!("Loaded {} Kconfig symbols.", db.len());
 
-async fn fetch_kernel_config_options() -> Result<Vec<String>> {
-    let file_path = "kernel_options.txt"; // Adjust the path to where your file is located
-    let file = File::open(file_path).context("Failed to open kernel options file")?;
-
-    let buf_reader = io::BufReader::new(file);
-
-    // Here, we use map and filter_map to handle potential errors in line reading
-    let options = buf_reader
-        .lines()
-        .filter_map(|line_result| line_result.ok()) // Ignore lines that fail to read
-        .collect::<Vec<String>>(); // Collect into Vec<String>
-
-    Ok(options)
-}
-async fn search_and_configure_option(theme: &ColorfulTheme) -> Result<()> {
-    // Fetch available options (simulated here)
-    let available_options = fetch_kernel_config_options().await;
+    loop {
+        let search_query: String = Input::with_theme(theme)
+            .with_prompt("Search Kconfig symbols (leave empty to go back)")
+            .allow_empty(true)
+            .interact_text()
+            .context("Failed to read input")?;
+        if search_query.trim().is_empty() {
+            return Ok(());
+        }
 
-    // Get user input for search
-    let search_query: String = Input::with_theme(theme)
-        .with_prompt("Search Kernel Options (leave empty to list all)")
-        .allow_empty(true)
-        .interact_text()
-        .context("Failed to read input")?;
+        let matches = db.search(search_query.trim());
+        if matches.is_empty() {
+            println!("No symbols match '{}'.", search_query.trim());
+            continue;
+        }
 
-    // Filter options based on search query
-    let filtered_options: Vec<String> = if search_query.is_empty() {
-        available_options?
-    } else {
-        available_options?
+        let dot_config = DotConfig::load(&kernel_dir.join(".config")).ok();
+        let mut items: Vec<String> = matches
             .iter()
-            .filter(|option| option.to_lowercase().contains(&search_query.to_lowercase()))
-            .map(|option| option.clone())
-            .collect()
-    };
+            .map(|symbol| {
+                format!(
+                    "CONFIG_{} [{}] = {}  {}",
+                    symbol.name,
+                    symbol.kind,
+                    kconfig::current_value(dot_config.as_ref(), &symbol.name),
+                    symbol.prompt.as_deref().unwrap_or("")
+                )
+            })
+            .collect();
+        items.push("<- Back".to_string());
 
-    // If only one option matches, or if listing all without a search query, proceed directly
-    if filtered_options.len() == 1 || search_query.is_empty() {
-        // Present the filtered options to the user
         let selection = Select::with_theme(theme)
-            .with_prompt("Select an option to configure")
-            .items(&filtered_options)
+            .with_prompt(format!("{} matching symbols", matches.len()))
+            .items(&items)
             .default(0)
             .interact()
             .context("Failed to select an option")?;
+        if selection == matches.len() {
+            continue;
+        }
+        let symbol = matches[selection];
+
+        println!("CONFIG_{} ({})", symbol.name, symbol.kind);
+        if let Some(prompt) = &symbol.prompt {
+            println!("  prompt:     {}", prompt);
+        }
+        if !symbol.menu.is_empty() {
+            println!("  menu:       {}", symbol.menu.join(" > "));
+        }
+        for dependency in &symbol.depends_on {
+            println!("  depends on: {}", dependency);
+        }
+        for default in &symbol.defaults {
+            println!("  default:    {}", default);
+        }
+        println!(
+            "  current:    {}",
+            kconfig::current_value(dot_config.as_ref(), &symbol.name)
+        );
+        if !symbol.help.is_empty() {
+            println!("\n{}\n", symbol.help);
+        }
+
+        let actions = vec!["Set Value", "Explain Value", "<- Back"];
+        let action = Select::with_theme(theme)
+            .with_prompt(format!("CONFIG_{}", symbol.name))
+            .items(&actions)
+            .default(0)
+            .interact()?;
 
-        // Placeholder for configuring the selected option
-        println!("Configuring: {}", filtered_options[selection]);
-        // Implement the configuration change here
+        match actions[action] {
+            "Set Value" => {
+                let value: String = Input::with_theme(theme)
+                    .with_prompt(format!("New value for CONFIG_{} (leave empty to keep)", symbol.name))
+                    .allow_empty(true)
+                    .interact_text()
+                    .context("Failed to read input")?;
+                if value.trim().is_empty() {
+                    continue;
+                }
+                match symbol.parse_value(&value) {
+                    Ok(toggle) => apply_kernel_toggles(&kernel_dir, &[toggle]).await?,
+                    Err(err) => println!("{}", err),
+                }
+            }
+            "Explain Value" => {
+                let explanation = explain_symbol(&db, &kernel_dir, &symbol.name)?;
+                let target = match (explanation.kind, explanation.value.as_str()) {
+                    (SymbolType::Bool | SymbolType::Tristate, "n") => Tristate::Yes,
+                    (SymbolType::Bool | SymbolType::Tristate, _)
+                        if explanation.lower_bound() > Tristate::No =>
+                    {
+                        Tristate::No
+                    }
+                    _ => continue,
+                };
+                let prompt = format!(
+                    "{} CONFIG_{} and the options it needs?",
+                    if target == Tristate::No { "Disable" } else { "Enable" },
+                    symbol.name
+                );
+                if Confirm::with_theme(theme).with_prompt(prompt).interact()? {
+                    set_symbol_chain(&db, &kernel_dir, &symbol.name, target).await?;
+                }
+            }
+            _ => {}
+        }
     }
+}
 
-    Ok(())
+/// Prints why a Kconfig symbol has its current value in a tree's `.config`.
+fn explain_symbol(db: &KconfigDb, kernel_dir: &Path, name: &str) -> Result<Explanation> {
+    let symbol = db
+        .get(name)
+        .with_context(|| format!("{} is not defined by the tree's Kconfig files", name))?;
+    let dot_config = DotConfig::load(&kernel_dir.join(".config"))?;
+    let explanation = Explanation::new(&Evaluator::new(db, Some(&dot_config)), symbol);
+    print!("{}", explanation);
+    Ok(explanation)
+}
+
+/// Sets a symbol to `target` together with every dependency or selecting
+/// option that has to change, refusing when part of the chain is blocked.
+async fn set_symbol_chain(
+    db: &KconfigDb,
+    kernel_dir: &Path,
+    name: &str,
+    target: Tristate,
+) -> Result<()> {
+    let name = name.trim_start_matches("CONFIG_");
+    let dot_config = DotConfig::load(&kernel_dir.join(".config"))?;
+    let chain = explain::plan(Evaluator::new(db, Some(&dot_config)), name, target);
+
+    if !chain.blockers.is_empty() {
+        for blocker in &chain.blockers {
+            eprintln!("blocked: {}", blocker);
+        }
+        anyhow::bail!("CONFIG_{} cannot be set to {}", name, target);
+    }
+    if chain.toggles.is_empty() {
+        println!("CONFIG_{} is already {}.", name, target);
+        return Ok(());
+    }
+    println!("Setting:");
+    for toggle in &chain.toggles {
+        println!("  {}", toggle);
+    }
+    apply_kernel_toggles(kernel_dir, &chain.toggles).await
 }
 
 //use reqwest;
@@ -79,7 +217,31 @@ enum Error {
     #[error("Request failed")]
     Request(#[from] reqwest::Error),
     #[error("Failed to parse version information")]
-    Parse,
+    Parse(#[source] serde_json::Error),
+    #[error("Kernel source tree '{0}' not found")]
+    TreeNotFound(String),
+    #[error("`{0}` failed")]
+    CommandFailed(String),
+}
+
+impl Error {
+    /// Process exit status reported for this error when running a subcommand.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Request(_) | Error::Parse(_) => 3,
+            Error::TreeNotFound(_) => 4,
+            Error::CommandFailed(_) => 5,
+        }
+    }
+}
+
+/// Maps an error to the exit status of the process: 1 for generic failures,
+/// 2 for usage errors (reported by clap), 3 for network or parse failures,
+/// 4 for an unknown source tree and 5 when an external command fails.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code)
 }
 
 #[derive(Parser, Debug)]
@@ -97,6 +259,331 @@ struct CliArgs {
     kernel_version: Option<String>, // Optional kernel version to uninstall
     #[clap(long)]
     list: bool, // This flag will be true if --list is used
+    /// Kernel configuration profile to use instead of the selected one
+    #[clap(long, global = true)]
+    profile: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Download a kernel source tree into the kcli source directory
+    Fetch {
+        /// Which kernel to clone
+        #[clap(long, value_enum, default_value = "stable")]
+        channel: KernelChannel,
+        /// Git tag or branch to check out instead of the channel head
+        #[clap(long)]
+        tag: Option<String>,
+        /// Name of the tree under the kcli source directory; defaults to the
+        /// channel's. An existing tree without applied patches is moved to
+        /// the new commit
+        #[clap(long, conflicts_with = "release")]
+        tree: Option<String>,
+        /// Download a kernel.org release tarball instead of cloning: a version
+        /// from releases.json, or mainline, stable, longterm or linux-next
+        /// for the newest of that kind
+        #[clap(long, conflicts_with_all = &["channel", "tag"])]
+        release: Option<String>,
+        /// Unpack releases kernel.org publishes no signature for
+        #[clap(long, requires = "release")]
+        allow_unsigned: bool,
+    },
+    /// Apply the saved kernel configuration to a source tree
+    Configure {
+        /// Source tree name under the kcli source directory, or a path
+        tree: String,
+    },
+    /// Apply patch files to a source tree, in the order given, or undo them
+    #[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Patch {
+        #[clap(subcommand)]
+        action: Option<PatchAction>,
+        /// Source tree name under the kcli source directory, or a path
+        #[clap(required = true)]
+        tree: Option<String>,
+        /// Patch files or directories, given as paths, inside the kernel-patches
+        /// clone or as `source:path`
+        #[clap(required = true)]
+        patches: Vec<PathBuf>,
+        /// Only print how each patch would apply: clean, offset, fuzz and
+        /// failing hunks
+        #[clap(long)]
+        dry_run: bool,
+        /// Apply with this tool from now on; the git backends need a git tree
+        #[clap(long, value_enum)]
+        backend: Option<PatchBackend>,
+    },
+    /// Manage the CachyOS kernel-patches clone
+    #[clap(subcommand)]
+    Patches(PatchesCommand),
+    /// Manage a tree's ordered patch series
+    #[clap(subcommand)]
+    Series(SeriesCommand),
+    /// Run a build step in a source tree, or a whole recipe with --recipe
+    Build {
+        /// Source tree name under the kcli source directory, or a path
+        #[clap(required_unless_present = "recipe", conflicts_with = "recipe")]
+        tree: Option<String>,
+        #[clap(long, value_enum, default_value = "compile")]
+        target: BuildTarget,
+        /// Fetch, patch, configure, compile and package as described by a kcli.toml
+        #[clap(long)]
+        recipe: Option<PathBuf>,
+    },
+    /// Package a built source tree
+    Package {
+        /// Source tree name under the kcli source directory, or a path
+        tree: String,
+    },
+    /// Manage named kernel configuration profiles
+    #[clap(subcommand)]
+    Profile(ProfileCommand),
+    /// Disable the modules in a tree's .config that this machine does not load,
+    /// like `make localmodconfig`
+    TrimModules {
+        /// Source tree name under the kcli source directory, or a path
+        tree: String,
+        /// `lsmod` output saved on other machines, used instead of
+        /// /proc/modules; may be given several times to merge them
+        #[clap(long = "lsmod")]
+        snapshots: Vec<PathBuf>,
+    },
+    /// Replace a tree's .config with the running kernel's, an installed one,
+    /// a local file or the CachyOS default, then run olddefconfig
+    Seed {
+        /// Source tree name under the kcli source directory, or a path
+        tree: String,
+        /// `running` (/proc/config.gz), `boot` (/boot/config-$(uname -r)),
+        /// `cachyos` or a path to a config file
+        #[clap(long, default_value = "running")]
+        from: String,
+    },
+    /// Apply kernel.org's incremental patches to bring a tree to the latest
+    /// point release of its series
+    Update {
+        /// Source tree name under the kcli source directory, or a path
+        tree: String,
+    },
+    /// List the source trees with their version, origin, patches, config
+    /// profile and last build
+    Trees,
+    /// Compare kernel .config files
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Show which dependencies or reverse selects decide a Kconfig symbol's value
+    Explain {
+        /// Symbol name, with or without the CONFIG_ prefix
+        symbol: String,
+        /// Source tree name under the kcli source directory, or a path
+        #[clap(default_value = "linux-stable")]
+        tree: String,
+        /// Enable the symbol and every dependency it needs
+        #[clap(long, conflicts_with = "disable")]
+        enable: bool,
+        /// Disable the symbol and every option selecting it
+        #[clap(long)]
+        disable: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Show the symbols added, removed and changed from A to B. Each side is
+    /// `cachyos` for the CachyOS baseline, a .config file or a source tree
+    Diff {
+        a: String,
+        b: String,
+        /// Source tree whose Kconfig menus group the output; defaults to
+        /// whichever side is a tree
+        #[clap(long)]
+        tree: Option<String>,
+        #[clap(long, value_enum, default_value = "text")]
+        format: DiffFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    /// List saved profiles
+    List,
+    /// Create a profile from the current configuration
+    Create { name: String },
+    /// Copy an existing profile under a new name
+    Clone { from: String, to: String },
+    /// Show the options that differ between two profiles
+    Diff { a: String, b: String },
+    /// Delete a profile
+    Delete { name: String },
+    /// Make a profile the one used by default ("default" for none)
+    Select { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum PatchAction {
+    /// Restore the tree to before a patch was applied; patches applied after
+    /// it are reverted as well
+    Revert {
+        tree: String,
+        /// The patch as applied, or the end of its path such as its file name
+        patch: PathBuf,
+    },
+    /// Restore the tree to its state before kcli applied any patch
+    Reset { tree: String },
+    /// Summarize the hunks patch rejected into .rej files, and its .orig backups
+    Rejects {
+        tree: String,
+        /// Remove every .rej and .orig file once the hunks are dealt with
+        #[clap(long)]
+        clean: bool,
+    },
+    /// Resolve the conflict markers a failed patch left, one at a time
+    Conflicts {
+        tree: String,
+        /// Only list the conflicts left anywhere in the tree
+        #[clap(long)]
+        list: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PatchesCommand {
+    /// Fetch the clone and fast-forward it, or move it to the pinned revision
+    Update {
+        /// Fetch from another remote from now on, such as a fork or a local mirror
+        #[clap(long)]
+        remote: Option<String>,
+        /// Pin the clone to a commit, branch or tag
+        #[clap(long, conflicts_with = "unpin")]
+        pin: Option<String>,
+        /// Follow the remote's default branch again
+        #[clap(long)]
+        unpin: bool,
+    },
+    /// List the patches of every source, with the source they come from
+    List {
+        /// Only list the directories matching this tree's kernel version
+        #[clap(long)]
+        tree: Option<String>,
+    },
+    /// Fast-forward the git sources and download the URL sources again
+    Sync,
+    /// Manage the patch sources used next to CachyOS's kernel-patches
+    #[clap(subcommand)]
+    Source(SourceCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum SourceCommand {
+    /// Show the configured patch sources
+    List,
+    /// Add a git repository, a local directory, or a URL to a .patch, .diff or mbox file
+    #[clap(group(clap::ArgGroup::new("location").required(true)))]
+    Add {
+        name: String,
+        #[clap(long, group = "location")]
+        git: Option<String>,
+        #[clap(long, group = "location")]
+        dir: Option<PathBuf>,
+        #[clap(long, group = "location")]
+        url: Option<String>,
+    },
+    /// Remove a patch source and its clone or downloads
+    Remove { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum SeriesCommand {
+    /// Show the patches in a tree's series
+    Show { tree: String },
+    /// Append patches or whole directories; directories are looked up in the
+    /// kernel-patches folder matching the tree's version first, so `sched`
+    /// means `6.10/sched` for a 6.10 tree
+    Add {
+        tree: String,
+        #[clap(required = true)]
+        patches: Vec<PathBuf>,
+    },
+    /// Remove patches, given as shown by `kcli series show`
+    Remove {
+        tree: String,
+        #[clap(required = true)]
+        patches: Vec<PathBuf>,
+    },
+    /// Remove every patch from the series
+    Clear { tree: String },
+    /// Apply the series in order. A dry run checks every patch first and
+    /// nothing is applied if a hunk fails, unless --force is given
+    Apply {
+        tree: String,
+        /// Only print how each patch would apply: clean, offset, fuzz and
+        /// failing hunks
+        #[clap(long)]
+        dry_run: bool,
+        /// Skip the dry run and stop at the first patch that fails
+        #[clap(long, conflicts_with = "dry-run")]
+        force: bool,
+        /// Apply with this tool from now on; the git backends need a git tree
+        #[clap(long, value_enum)]
+        backend: Option<PatchBackend>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum KernelChannel {
+    Stable,
+    Rc,
+}
+
+impl KernelChannel {
+    fn source(self) -> (&'static str, &'static str) {
+        match self {
+            KernelChannel::Stable => (
+                "https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git",
+                "linux-stable",
+            ),
+            KernelChannel::Rc => ("https://github.com/torvalds/linux.git", "linux-rc"),
+        }
+    }
+
+    /// Name of the channel's remote in the shared git mirror.
+    fn remote(self) -> &'static str {
+        match self {
+            KernelChannel::Stable => "stable",
+            KernelChannel::Rc => "torvalds",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum BuildTarget {
+    Compile,
+    Modules,
+    Headers,
+}
+
+impl BuildTarget {
+    fn make_args(self, settings: &BuildSettings) -> Vec<String> {
+        match self {
+            BuildTarget::Compile => vec![
+                format!("LOCALVERSION={}", settings.localversion),
+                format!("KCFLAGS={}", settings.kcflags),
+                format!("-j{}", num_cpus::get()),
+            ],
+            BuildTarget::Modules => vec!["modules_install".to_string()],
+            BuildTarget::Headers => vec!["headers_install".to_string()],
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BuildTarget::Compile => "compile",
+            BuildTarget::Modules => "modules",
+            BuildTarget::Headers => "headers",
+        }
+    }
 }
 
 use std::process;
@@ -155,7 +642,7 @@ async fn execute_custom_command(file_path: Option<String>) -> Result<()> {
         // create dir for kernel version extracted from file_path
         let kernel_version = file_path
             .split('/')
-            .last()
+            .next_back()
             .unwrap()
             .split(".tar.gz")
             .next()
@@ -192,24 +679,19 @@ async fn execute_custom_command(file_path: Option<String>) -> Result<()> {
 }
 
 async fn execute_list_command() -> Result<()> {
-    // list the installed kernels, by listing config_path
+    // list the installed kernels: --install keeps each one's file list in
+    // config_path/<kernel_version>/.srctree, next to kcli's own files
     let config_path: PathBuf = config_dir().unwrap().join("kcli");
     let kernel_versions = fs::read_dir(&config_path)
         .context("Failed to read kernel versions directory")?
         .filter_map(|entry| {
             entry
                 .ok()
+                .filter(|e| e.path().join(".srctree").is_file())
                 .and_then(|e| e.file_name().into_string().ok())
-                .filter(|name| name != "kernel_config.json")
         })
         .collect::<Vec<String>>();
 
-    // remove "options" from the list
-    let kernel_versions = kernel_versions
-        .iter()
-        .filter(|&x| x != "options")
-        .collect::<Vec<&String>>();
-
     if kernel_versions.is_empty() {
         println!("No installed kernels found.");
     } else {
@@ -260,67 +742,16 @@ async fn execute_uninstall_command(kernel_name: Option<String>) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct KernelConfig {
-    architecture: String,
-    cpusched_selection: String,
-    llvm_lto_selection: String,
-    tick_rate: String,
-    nr_cpus: String,
-    hugepages: String,
-    lru: String,
-    tick_type: String,
-    preempt_type: String,
-}
-
-impl KernelConfig {
-    fn save_to_file(&self) -> std::io::Result<()> {
-        if let Some(mut config_path) = config_dir() {
-            config_path.push("kcli");
-            fs::create_dir_all(&config_path)?; // Ensure the directory exists
-
-            config_path.push("kernel_config.json");
-            let serialized = serde_json::to_string_pretty(self)?;
-            fs::write(config_path, serialized)?;
-        }
-        Ok(())
-    }
-}
-
-impl Default for KernelConfig {
-    fn default() -> Self {
-        Self {
-            architecture: "native".to_string(), // Default to x86_64, adjust as necessary
-            cpusched_selection: "None".to_string(), // Assuming 'None' as a default value
-            llvm_lto_selection: "None".to_string(), // Default to no LTO
-            tick_rate: "600".to_string(),       // Common default for many Linux distros
-            nr_cpus: "400".to_string(),         // Default to 4 CPUs
-            hugepages: "Always".to_string(),    // Default to not using hugepages
-            lru: "Standard".to_string(),        // Default LRU configuration
-            tick_type: "Periodic".to_string(),  // Default tick type
-            preempt_type: "Voluntary".to_string(), // Default preempt type
-        }
-    }
-}
-
-impl KernelConfig {
-    fn load_or_default() -> Self {
-        if let Some(mut path) = config_dir() {
-            path.push("kcli/options/kernel_config.json");
-            if let Ok(contents) = fs::read_to_string(&path) {
-                if let Ok(config) = serde_json::from_str(&contents) {
-                    return config;
-                }
-            }
-        }
-        Self::default()
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run().await {
+        eprintln!("Error: {:#}", err);
+        process::exit(exit_code(&err));
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+async fn run() -> Result<()> {
     let args = CliArgs::parse();
-    let mut config = KernelConfig::load_or_default(); // Load the existing config or use default
 
     if args.list {
         execute_list_command().await?;
@@ -336,20 +767,33 @@ async fn main() -> Result<()> {
         execute_uninstall_command(args.kernel_version).await?;
         return Ok(());
     }
+
+    if let Some(command) = args.command {
+        return run_subcommand(command, args.profile.as_deref()).await;
+    }
+
+    let (mut config, mut source) = load_profile(args.profile.as_deref())?;
     let theme = ColorfulTheme::default();
 
     print_ascii_art().await;
     let cpu_architecture = autodetect_cpu_architecture().await?;
     println!("CPU Architecture: {}", cpu_architecture);
 
-    let kver = fetch_latest_kernel_link().await?;
-    println!("Latest Kernel Stable: {}", kver);
+    match ReleaseIndex::load().await {
+        Ok(index) => {
+            index.warn_if_stale();
+            if let Some(release) = index.find("stable") {
+                println!("Latest Kernel Stable: {}", release.version);
+            }
+        }
+        Err(_) => println!("Latest Kernel Stable: unknown (kernel.org is unreachable)"),
+    }
     println!();
 
     list_installed_kernels()?;
     println!();
     if !args.auto_accept_defaults {
-        main_menu(&mut config, &theme).await?;
+        main_menu(&mut config, &mut source, &theme).await?;
     }
 
     println!("Final Kernel Configuration: {:?}", config);
@@ -357,6 +801,479 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Loads the kernel options of `--profile`, or of the selected profile.
+/// Only the menu and the commands that use the options load them, so a
+/// broken profile does not get in the way of anything else.
+fn load_profile(profile: Option<&str>) -> Result<(KernelConfig, ConfigSource)> {
+    let source = match profile {
+        Some(name) => ConfigSource::parse(name)?,
+        None => profiles::selected()?,
+    };
+    let config = source.load()?; // Load the existing config or use default
+    Ok((config, source))
+}
+
+async fn run_subcommand(command: Commands, profile: Option<&str>) -> Result<()> {
+    let packages_dir = ksrc_dir()?;
+
+    match command {
+        Commands::Fetch {
+            release: Some(release),
+            allow_unsigned,
+            ..
+        } => {
+            let index = ReleaseIndex::load().await?;
+            index.warn_if_stale();
+            let release = index
+                .find(&release)
+                .with_context(|| format!("kernel.org does not list release '{}'", release))?;
+            kernel_org::install_release(release, &packages_dir, allow_unsigned).await?;
+            Ok(())
+        }
+        Commands::Fetch {
+            channel, tag, tree, ..
+        } => {
+            let tree = tree.unwrap_or_else(|| channel.source().1.to_string());
+            download_kernel_source(channel, tag.as_deref(), &tree).await
+        }
+        Commands::Configure { tree } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            let (config, source) = load_profile(profile)?;
+            apply_kernel_configuration(&config, &source, &kernel_dir).await
+        }
+        Commands::Patch {
+            action: Some(PatchAction::Revert { tree, patch }),
+            ..
+        } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            patch::revert(&kernel_dir, &patch).await
+        }
+        Commands::Patch {
+            action: Some(PatchAction::Reset { tree }),
+            ..
+        } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            patch::reset(&kernel_dir).await
+        }
+        Commands::Patch {
+            action: Some(PatchAction::Rejects { tree, clean }),
+            ..
+        } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            let leftovers = rejects::find(&kernel_dir)?;
+            if leftovers.is_empty() {
+                println!("No .rej or .orig files in {}.", kernel_dir.display());
+            } else if clean {
+                rejects::clean(&kernel_dir, &leftovers)?;
+            } else {
+                rejects::print_summary(&rejects::by_patch(&kernel_dir, leftovers)?);
+            }
+            Ok(())
+        }
+        Commands::Patch {
+            action: Some(PatchAction::Conflicts { tree, list }),
+            ..
+        } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            if list {
+                conflicts::print_report(&kernel_dir, &conflicts::find_all(&kernel_dir)?);
+                Ok(())
+            } else {
+                patch::resolve_conflicts(&ColorfulTheme::default(), &kernel_dir).await
+            }
+        }
+        Commands::Patch {
+            tree,
+            patches,
+            dry_run: true,
+            ..
+        } => {
+            let tree = tree.context("No source tree given")?;
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            let named = patch::resolve_all(&kernel_dir, &patches).await?;
+            let checks = patch::check(&kernel_dir, &named).await?;
+            preflight::print_table(&checks);
+            patch::ensure_applies(&checks)
+        }
+        Commands::Patch {
+            tree,
+            patches,
+            backend,
+            ..
+        } => {
+            let tree = tree.context("No source tree given")?;
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            if let Some(backend) = backend {
+                patch::set_backend(&kernel_dir, backend)?;
+            }
+            for (_, patch_file) in patch::resolve_all(&kernel_dir, &patches).await? {
+                patch::apply(&kernel_dir, &patch_file).await?;
+            }
+            Ok(())
+        }
+        Commands::Patches(PatchesCommand::Update { remote, pin, unpin }) => {
+            kernel_patches::update(&UpdateOptions { remote, pin, unpin }).await
+        }
+        Commands::Patches(PatchesCommand::List { tree }) => {
+            let kernel_dir = match tree {
+                Some(tree) => Some(resolve_kernel_tree(&packages_dir, &tree)?),
+                None => None,
+            };
+            let catalog = patch_sources::catalog(kernel_dir.as_deref()).await?;
+            let width = catalog
+                .iter()
+                .map(|entry| entry.source.len())
+                .max()
+                .unwrap_or(0);
+            for entry in catalog {
+                println!("{:<width$}  {}", entry.source, entry.name.display(), width = width);
+            }
+            Ok(())
+        }
+        Commands::Patches(PatchesCommand::Sync) => patch_sources::sync().await,
+        Commands::Patches(PatchesCommand::Source(command)) => match command {
+            SourceCommand::List => patch_sources::print_sources(),
+            SourceCommand::Add { name, git, dir, url } => {
+                let location = if let Some(git) = git {
+                    Location::Git(git)
+                } else if let Some(dir) = dir {
+                    Location::Dir(dir)
+                } else {
+                    Location::Url(url.context("No patch source location given")?)
+                };
+                patch_sources::add(&name, location).await
+            }
+            SourceCommand::Remove { name } => patch_sources::remove(&name),
+        },
+        Commands::Series(command) => run_series_command(command, &packages_dir).await,
+        Commands::Build {
+            recipe: Some(recipe),
+            ..
+        } => build_from_recipe(&Recipe::load(&recipe)?, &packages_dir).await,
+        Commands::Build { tree, target, .. } => {
+            let tree = tree.context("No source tree given")?;
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            build_tree(&kernel_dir, target, &BuildSettings::default()).await
+        }
+        Commands::Package { tree } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            package_kernel_tree(&kernel_dir, &packages_dir, &pkg_manager::PackageInfo::default())
+                .await
+        }
+        Commands::Profile(command) => run_profile_command(command, profile),
+        Commands::TrimModules { tree, snapshots } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            localmod::trim_modules(&kernel_dir, &snapshots).await
+        }
+        Commands::Seed { tree, from } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            seed::seed_config(&kernel_dir, &SeedSource::parse(&from)).await
+        }
+        Commands::Update { tree } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            update::update_tree(&kernel_dir).await
+        }
+        Commands::Trees => {
+            let mut trees = pkg_manager::list_kernel_packages(&packages_dir).await?;
+            trees.sort();
+            for tree in trees {
+                println!("{}", tree);
+                TreeMetadata::load(&packages_dir.join(&tree))?.print();
+            }
+            Ok(())
+        }
+        Commands::Config(ConfigCommand::Diff {
+            a,
+            b,
+            tree,
+            format,
+        }) => {
+            let (a, a_tree) = load_config_arg(&packages_dir, &a).await?;
+            let (b, b_tree) = load_config_arg(&packages_dir, &b).await?;
+            let kernel_dir = match tree {
+                Some(tree) => Some(resolve_kernel_tree(&packages_dir, &tree)?),
+                None => a_tree.or(b_tree),
+            };
+            let db = kernel_dir.as_deref().map(KconfigDb::load).transpose()?;
+            let diff = ConfigDiff::new(&a, &b, db.as_ref());
+            if diff.is_empty() && matches!(format, DiffFormat::Text) {
+                println!("The configs are identical.");
+                return Ok(());
+            }
+            diff.print(format)
+        }
+        Commands::Explain {
+            symbol,
+            tree,
+            enable,
+            disable,
+        } => {
+            let kernel_dir = resolve_kernel_tree(&packages_dir, &tree)?;
+            let db = KconfigDb::load(&kernel_dir)?;
+            explain_symbol(&db, &kernel_dir, &symbol)?;
+            if enable || disable {
+                let target = if enable { Tristate::Yes } else { Tristate::No };
+                set_symbol_chain(&db, &kernel_dir, &symbol, target).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn run_series_command(command: SeriesCommand, packages_dir: &Path) -> Result<()> {
+    match command {
+        SeriesCommand::Show { tree } => {
+            let kernel_dir = resolve_kernel_tree(packages_dir, &tree)?;
+            print_series(&Series::load(&kernel_dir)?);
+        }
+        SeriesCommand::Add { tree, patches } => {
+            let kernel_dir = resolve_kernel_tree(packages_dir, &tree)?;
+            let patches_root = kernel_patches::ensure_clone().await?;
+            let mut series = Series::load(&kernel_dir)?;
+            for entry in patches {
+                let path = patch::resolve(&kernel_dir, &entry).await?;
+                series.add(patch::expand(&path)?, &patches_root);
+            }
+            series.save(&kernel_dir)?;
+            print_series(&series);
+        }
+        SeriesCommand::Remove { tree, patches } => {
+            let kernel_dir = resolve_kernel_tree(packages_dir, &tree)?;
+            let mut series = Series::load(&kernel_dir)?;
+            for patch in patches {
+                let index = series
+                    .patches()
+                    .iter()
+                    .position(|entry| *entry == patch)
+                    .with_context(|| format!("{} is not in the series", patch.display()))?;
+                series.remove(index);
+            }
+            series.save(&kernel_dir)?;
+            print_series(&series);
+        }
+        SeriesCommand::Clear { tree } => {
+            let kernel_dir = resolve_kernel_tree(packages_dir, &tree)?;
+            let mut series = Series::load(&kernel_dir)?;
+            series.clear();
+            series.save(&kernel_dir)?;
+        }
+        SeriesCommand::Apply {
+            tree,
+            dry_run,
+            force,
+            backend,
+        } => {
+            let kernel_dir = resolve_kernel_tree(packages_dir, &tree)?;
+            if let Some(backend) = backend {
+                patch::set_backend(&kernel_dir, backend)?;
+            }
+            if dry_run {
+                let checks = series::check_series(&kernel_dir).await?;
+                preflight::print_table(&checks);
+                patch::ensure_applies(&checks)?;
+            } else {
+                series::apply_series(&kernel_dir, force).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_series(series: &Series) {
+    if series.is_empty() {
+        println!("The patch series is empty.");
+    }
+    for (index, patch) in series.patches().iter().enumerate() {
+        println!("{:>3}. {}", index + 1, patch_sources::label(patch));
+    }
+}
+
+fn run_profile_command(command: ProfileCommand, profile: Option<&str>) -> Result<()> {
+    match command {
+        ProfileCommand::List => {
+            let selected = profiles::selected()?;
+            println!("{} {}", marker(selected == ConfigSource::Default), profiles::DEFAULT_PROFILE);
+            for name in profiles::list()? {
+                let is_selected = selected == ConfigSource::Profile(name.clone());
+                println!("{} {}", marker(is_selected), name);
+            }
+        }
+        ProfileCommand::Create { name } => {
+            let (config, _) = load_profile(profile)?;
+            profiles::create(&ConfigSource::parse(&name)?, &config)?;
+            println!("Profile '{}' created.", name);
+        }
+        ProfileCommand::Clone { from, to } => {
+            profiles::clone(&ConfigSource::parse(&from)?, &ConfigSource::parse(&to)?)?;
+            println!("Profile '{}' cloned to '{}'.", from, to);
+        }
+        ProfileCommand::Diff { a, b } => {
+            profiles::print_diff(&ConfigSource::parse(&a)?, &ConfigSource::parse(&b)?)?
+        }
+        ProfileCommand::Delete { name } => {
+            profiles::delete(&ConfigSource::parse(&name)?)?;
+            println!("Profile '{}' deleted.", name);
+        }
+        ProfileCommand::Select { name } => {
+            profiles::select(&ConfigSource::parse(&name)?)?;
+            println!("Profile '{}' selected.", name);
+        }
+    }
+    Ok(())
+}
+
+fn marker(selected: bool) -> &'static str {
+    if selected {
+        "*"
+    } else {
+        " "
+    }
+}
+
+/// Runs the whole pipeline described by a recipe: fetch the tree if it is not
+/// there yet, apply the patch series, configure, compile and package.
+async fn build_from_recipe(recipe: &Recipe, packages_dir: &Path) -> Result<()> {
+    let tree = recipe.source.tree_name();
+    let kernel_dir = packages_dir.join(&tree);
+    if kernel_dir.is_dir() {
+        check_recipe_tree(recipe, &kernel_dir).await?;
+        println!("Using existing source tree '{}'.", kernel_dir.display());
+    } else {
+        download_kernel_source(recipe.source.channel, recipe.source.tag.as_deref(), &tree).await?;
+    }
+
+    let patches = patch::resolve_all(&kernel_dir, &recipe.patches).await?;
+    patch::apply_exact(&kernel_dir, &patches).await?;
+
+    let mut toggles = recipe.config.toggles();
+    toggles.extend(recipe.kconfig.toggles());
+    apply_kernel_toggles(&kernel_dir, &toggles).await?;
+
+    build_tree(&kernel_dir, BuildTarget::Compile, &recipe.build).await?;
+    package_kernel_tree(&kernel_dir, packages_dir, &recipe.package).await
+}
+
+/// Fails unless an existing tree was checked out at the recipe's tag, so a
+/// recipe never builds from a revision other than the one it pins.
+async fn check_recipe_tree(recipe: &Recipe, kernel_dir: &Path) -> Result<()> {
+    let Some(tag) = recipe.source.tag.as_deref() else {
+        return Ok(());
+    };
+    let channel = recipe.source.channel;
+    let mirror = Mirror::open().await?;
+    let expected = match mirror.resolve(channel, Some(tag)).await {
+        Ok(commit) => commit,
+        Err(_) => {
+            mirror.fetch(channel).await?;
+            mirror.resolve(channel, Some(tag)).await?
+        }
+    };
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    let recorded = metadata.origin.and_then(|origin| origin.commit);
+    if recorded.as_deref() != Some(expected.as_str()) {
+        anyhow::bail!(
+            "{} is at {}, but the recipe pins {} ({}); remove the tree or set another `tree` in the recipe",
+            kernel_dir.display(),
+            recorded.as_deref().unwrap_or("an unknown revision"),
+            tag,
+            expected
+        );
+    }
+    // `kcli update` moves a tree past its tag with kernel.org's patches
+    let pinned = update::KernelVersion::parse(tag.trim_start_matches('v'));
+    if let (Some(pinned), Some(version)) = (pinned, &metadata.upstream_version) {
+        if update::KernelVersion::parse(version) != Some(pinned) {
+            anyhow::bail!(
+                "{} was updated to {}, but the recipe pins {}; remove the tree or set another `tree` in the recipe",
+                kernel_dir.display(),
+                version,
+                tag
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn package_kernel_tree(
+    kernel_dir: &Path,
+    packages_dir: &Path,
+    package_info: &pkg_manager::PackageInfo,
+) -> Result<()> {
+    let kernel_name = kernel_dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::TreeNotFound(kernel_dir.display().to_string()))?;
+    let pkg_dir = packages_dir
+        .parent()
+        .context("Failed to locate the kcli config directory")?
+        .join("pkg");
+    pkg_manager::installing_kernel(kernel_dir, &pkg_dir, kernel_name, package_info).await
+}
+
+/// Directory holding the downloaded kernel source trees (`~/.config/kcli/ksrc`).
+fn ksrc_dir() -> Result<PathBuf> {
+    let mut config_path = config_dir().context("Failed to locate the config directory")?;
+    config_path.push("kcli");
+    config_path.push("ksrc");
+    fs::create_dir_all(&config_path)?;
+    Ok(config_path)
+}
+
+/// Resolves a tree given on the command line, either by name inside the
+/// source directory or as a path to an existing directory.
+fn resolve_kernel_tree(packages_dir: &Path, tree: &str) -> Result<PathBuf> {
+    let named = packages_dir.join(tree);
+    if named.is_dir() {
+        return Ok(named);
+    }
+    let path = PathBuf::from(tree);
+    if path.is_dir() {
+        return Ok(path);
+    }
+    Err(Error::TreeNotFound(tree.to_string()).into())
+}
+
+/// Prompts for one of the source trees under `packages_dir`, returning `None`
+/// when the user goes back to the main menu.
+async fn select_kernel_tree(theme: &ColorfulTheme, packages_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut packages = pkg_manager::list_kernel_packages(packages_dir)
+        .await
+        .context("Failed to list kernel packages")?;
+
+    // If no packages are found, return an error or a message
+    if packages.is_empty() {
+        return Err(anyhow::anyhow!("No kernel packages found."));
+    }
+
+    // Show each tree's version, patches, profile and last build
+    let mut items = Vec::with_capacity(packages.len() + 1);
+    for package in &packages {
+        items.push(metadata::tree_label(&packages_dir.join(package)).await);
+    }
+
+    // add <- Go Back to Main Menu option
+    packages.push("<- Back to Main Menu".to_string());
+    items.push("<- Back to Main Menu".to_string());
+
+    // Prompt the user to select a Linux version
+    let selected_package_index = Select::with_theme(theme)
+        .with_prompt("Select a Linux version to configure")
+        .items(&items)
+        .default(0)
+        .interact()?;
+
+    // if <- Back to Main Menu is selected, return to main menu
+    if selected_package_index == packages.len() - 1 {
+        return Ok(None);
+    }
+
+    // Get the selected package name
+    let selected_package = &packages[selected_package_index];
+    println!("Selected package for configuration: {}", selected_package);
+
+    Ok(Some(packages_dir.join(selected_package)))
+}
+
 fn list_installed_kernels() -> Result<()> {
     let paths = fs::read_dir("/usr/src").context("Failed to read /usr/src directory")?;
 
@@ -406,36 +1323,6 @@ async fn autodetect_cpu_architecture() -> Result<String> {
     }
 }
 
-async fn fetch_latest_kernel_link() -> Result<String> {
-    let command = r#"
-    curl -s https://www.kernel.org | 
-    grep -A 1 'id="latest_link"' | 
-    awk 'NR==2' | 
-    grep -oP 'href="\K[^"]+' | 
-    grep -oP 'linux-\K[^"]+' |
-    xargs basename -s .tar.xz
-"#;
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .output()
-        .await // Corrected to async context
-        .context("Failed to execute command")?;
-
-    if output.status.success() {
-        let link = str::from_utf8(&output.stdout)
-            .context("Failed to parse output")?
-            .trim()
-            .to_string();
-        Ok(link)
-    } else {
-        let error_message =
-            str::from_utf8(&output.stderr).context("Failed to read error message")?;
-        Err(anyhow::anyhow!("Command failed: {}", error_message))
-    }
-}
-
 async fn print_ascii_art() {
     println!(
         r#"
@@ -454,16 +1341,16 @@ async fn print_ascii_art() {
 }
 async fn configure_kernel_options(
     config: &mut KernelConfig,
+    source: &ConfigSource,
     theme: &ColorfulTheme,
-    packages_dir: &Path,
 ) -> Result<()> {
-    // First, list available Linux versions
-
     loop {
         let selections = vec![
+            "Architecture",
             "CPU Scheduler",
             "LLVM LTO",
             "Tick Rate",
+            "NR_CPUS",
             "Hugepages",
             "LRU",
             "Tick Type",
@@ -473,23 +1360,25 @@ async fn configure_kernel_options(
         ];
 
         let selection = Select::with_theme(theme)
-            .with_prompt("Configure Kernel Options")
+            .with_prompt(format!("Configure Kernel Options (profile: {})", source))
             .items(&selections)
             .default(0)
             .interact()?;
 
         match selections[selection] {
-            "CPU Scheduler" => configure_cpusched(config, theme)?,
-            "LLVM LTO" => configure_llvm_lto(config, theme)?,
-            "Tick Rate" => configure_tick_rate(config, theme)?,
-            "Hugepages" => configure_hugepages(config, theme)?,
-            "LRU" => configure_lru(config, theme)?,
-            "Tick Type" => configure_tick_type(config, theme)?,
-            "Preempt Type" => configure_preempt_type(config, theme)?,
-            "System Optimizations" => configure_system_optimizations(config, theme)?,
+            "Architecture" => configure_choice(&mut config.architecture, theme)?,
+            "CPU Scheduler" => configure_choice(&mut config.cpusched_selection, theme)?,
+            "LLVM LTO" => configure_choice(&mut config.llvm_lto_selection, theme)?,
+            "Tick Rate" => configure_choice(&mut config.tick_rate, theme)?,
+            "NR_CPUS" => configure_nr_cpus(config, theme)?,
+            "Hugepages" => configure_choice(&mut config.hugepages, theme)?,
+            "LRU" => configure_choice(&mut config.lru, theme)?,
+            "Tick Type" => configure_choice(&mut config.tick_type, theme)?,
+            "Preempt Type" => configure_choice(&mut config.preempt_type, theme)?,
+            "System Optimizations" => configure_system_optimizations()?,
             "<-" => {
                 println!("Saving and returning to main menu...");
-                config.save_to_file()?; // Saves the config
+                source.save(config)?; // Saves the config
                 break; // Exits the loop
             }
             _ => {}
@@ -499,13 +1388,21 @@ async fn configure_kernel_options(
     Ok(())
 }
 
-async fn main_menu(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
+async fn main_menu(
+    config: &mut KernelConfig,
+    source: &mut ConfigSource,
+    theme: &ColorfulTheme,
+) -> Result<()> {
     loop {
         let selections = vec![
             "Download Kernel Source",
+            "Seed .config",
             "Configure Kernel Options",
+            "Profiles",
             "Apply Kernel Configuration",
+            "Trim Unused Modules",
             "Patch Kernel", // New option for patching kernel
+            "Update Kernel Patches",
             "Build Kernel",
             "Package Kernel", // New option for installing kernel
             //"Uninstall Kernel", // New option for uninstalling kernel
@@ -519,24 +1416,27 @@ async fn main_menu(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<(
             .default(0)
             .interact()?;
 
-        let mut config_path = config_dir().unwrap();
-        config_path.push("kcli");
-        fs::create_dir_all(&config_path)?;
-        let packages_dir = config_path.join("ksrc");
+        let packages_dir = ksrc_dir()?;
 
         match selections[selection] {
-            "Download Kernel Source" => configure_download_kernel(config, theme).await?,
-            "Configure Kernel Options" => {
-                configure_kernel_options(config, theme, &packages_dir).await?
-            }
+            "Download Kernel Source" => configure_download_kernel(theme).await?,
+            "Seed .config" => seed_config_menu(theme, &packages_dir).await?,
+            "Configure Kernel Options" => configure_kernel_options(config, source, theme).await?,
+            "Profiles" => profiles_menu(config, source, theme)?,
             "Apply Kernel Configuration" => {
-                apply_kernel_configuration(config, theme, &packages_dir).await?
+                if let Some(kernel_dir) = select_kernel_tree(theme, &packages_dir).await? {
+                    apply_kernel_configuration(config, source, &kernel_dir).await?
+                }
             }
-            "Build Kernel" => build_kernel_menu(config, theme, &packages_dir).await?,
+            "Trim Unused Modules" => trim_modules_menu(theme, &packages_dir).await?,
+            "Build Kernel" => build_kernel_menu(theme, &packages_dir).await?,
             "Patch Kernel" => patch_kernel_process(theme, &packages_dir).await?,
+            "Update Kernel Patches" => update_kernel_patches_menu(theme).await?,
             "Package Kernel" => pkg_manager::menu_install_kernel(theme).await?, // Implementation needed
             //"Uninstall Kernel" => pkg_manager::menu_uninstall_kernel(theme).await?, // Implementation needed
-            "Advanced Search/Configure" => search_and_configure_option(theme).await?,
+            "Advanced Search/Configure" => {
+                search_and_configure_option(theme, &packages_dir).await?
+            }
             "Exit" => break,
             _ => {}
         }
@@ -545,102 +1445,415 @@ async fn main_menu(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<(
     Ok(())
 }
 
-async fn patch_kernel_process(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
-    let packages = pkg_manager::list_kernel_packages(packages_dir)
-        .await
-        .context("Failed to list kernel packages")?;
-
-    // If no packages are found, return an error or a message
-    if packages.is_empty() {
-        return Err(anyhow::anyhow!("No kernel packages found."));
-    }
+async fn trim_modules_menu(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
+    let Some(kernel_dir) = select_kernel_tree(theme, packages_dir).await? else {
+        return Ok(());
+    };
+    let snapshots: String = Input::with_theme(theme)
+        .with_prompt("lsmod snapshots to merge, separated by spaces (leave empty for this machine)")
+        .allow_empty(true)
+        .interact_text()?;
+    let snapshots: Vec<PathBuf> = snapshots.split_whitespace().map(PathBuf::from).collect();
+    println!(
+        "Modules listed in {} are always kept.",
+        localmod::allowlist_path()?.display()
+    );
+    localmod::trim_modules(&kernel_dir, &snapshots).await
+}
 
-    // Add <- Go Back to Main Menu option
-    let mut packages = packages;
-    packages.push("<- Back to Main Menu".to_string());
+async fn seed_config_menu(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
+    let Some(kernel_dir) = select_kernel_tree(theme, packages_dir).await? else {
+        return Ok(());
+    };
 
-    // Prompt the user to select a Linux version
-    let selected_package_index = Select::with_theme(theme)
-        .with_prompt("Select a Linux version to configure")
-        .items(&packages)
+    let mut selections: Vec<String> = SeedSource::NAMED.iter().map(|s| s.to_string()).collect();
+    selections.push("Local file".to_string());
+    selections.push("<- Back".to_string());
+    let selection = Select::with_theme(theme)
+        .with_prompt("Seed .config from")
+        .items(&selections)
         .default(0)
         .interact()?;
 
-    // If <- Back to Main Menu is selected, return to main menu
-    if selected_package_index == packages.len() - 1 {
-        return Ok(());
-    }
+    let source = match selections[selection].as_str() {
+        "<- Back" => return Ok(()),
+        "Local file" => {
+            let path: String = Input::with_theme(theme)
+                .with_prompt("Path to the config file")
+                .interact_text()?;
+            SeedSource::File(PathBuf::from(path.trim()))
+        }
+        _ => SeedSource::NAMED[selection].clone(),
+    };
+    seed::seed_config(&kernel_dir, &source).await
+}
 
-    // Get the selected package name
-    let selected_package = &packages[selected_package_index];
-    println!("Selected package for configuration: {}", selected_package);
+fn profiles_menu(
+    config: &mut KernelConfig,
+    source: &mut ConfigSource,
+    theme: &ColorfulTheme,
+) -> Result<()> {
+    loop {
+        let selections = vec![
+            "Select Profile",
+            "Create Profile",
+            "Clone Profile",
+            "Diff Profiles",
+            "Delete Profile",
+            "<-",
+        ];
 
-    // Enter the kernel directory
-    let kernel_dir = Path::new(packages_dir).join(selected_package);
+        let selection = Select::with_theme(theme)
+            .with_prompt(format!("Profiles (active: {})", source))
+            .items(&selections)
+            .default(0)
+            .interact()?;
 
-    // Clone or use existing patches directory
-    let patches_dir = clone_patches_repo().await?;
-    let selected_patch = navigate_and_select_patch(patches_dir).await?;
+        match selections[selection] {
+            "Select Profile" => {
+                if let Some(selected) = pick_profile(theme, "Select a profile", true)? {
+                    *config = selected.load()?;
+                    profiles::select(&selected)?;
+                    *source = selected;
+                    println!("Profile '{}' selected.", source);
+                }
+            }
+            "Create Profile" => {
+                let name: String = Input::with_theme(theme)
+                    .with_prompt("New profile name (starts from the active configuration)")
+                    .interact_text()?;
+                let created = ConfigSource::parse(&name)?;
+                profiles::create(&created, config)?;
+                println!("Profile '{}' created.", created);
+            }
+            "Clone Profile" => {
+                if let Some(from) = pick_profile(theme, "Profile to clone", true)? {
+                    let name: String = Input::with_theme(theme)
+                        .with_prompt("Name of the copy")
+                        .interact_text()?;
+                    profiles::clone(&from, &ConfigSource::parse(&name)?)?;
+                    println!("Profile '{}' cloned to '{}'.", from, name);
+                }
+            }
+            "Diff Profiles" => {
+                if let Some(a) = pick_profile(theme, "First profile", true)? {
+                    if let Some(b) = pick_profile(theme, "Second profile", true)? {
+                        profiles::print_diff(&a, &b)?;
+                    }
+                }
+            }
+            "Delete Profile" => {
+                if let Some(deleted) = pick_profile(theme, "Profile to delete", false)? {
+                    profiles::delete(&deleted)?;
+                    if *source == deleted {
+                        *source = ConfigSource::Default;
+                        *config = source.load()?;
+                    }
+                    println!("Profile '{}' deleted.", deleted);
+                }
+            }
+            "<-" => return Ok(()),
+            _ => {}
+        }
+    }
+}
 
-    if let Some(patch) = selected_patch {
-        apply_patch(patch, &kernel_dir).await?;
+/// Prompts for a saved profile, optionally offering the default
+/// configuration as well. Returns `None` when the user goes back.
+fn pick_profile(
+    theme: &ColorfulTheme,
+    prompt: &str,
+    include_default: bool,
+) -> Result<Option<ConfigSource>> {
+    let mut names = profiles::list()?;
+    if include_default {
+        names.insert(0, profiles::DEFAULT_PROFILE.to_string());
     }
+    if names.is_empty() {
+        println!("No profiles found.");
+        return Ok(None);
+    }
+    names.push("<-".to_string());
 
-    Ok(())
+    let selection = Select::with_theme(theme)
+        .with_prompt(prompt)
+        .items(&names)
+        .default(0)
+        .interact()?;
+    if selection == names.len() - 1 {
+        return Ok(None);
+    }
+    ConfigSource::parse(&names[selection]).map(Some)
 }
-async fn clone_patches_repo() -> Result<PathBuf, anyhow::Error> {
-    let repo_url = "https://github.com/CachyOS/kernel-patches";
 
-    // Get the configuration directory
-    let base_dirs = BaseDirs::new().context("Failed to get base directories")?;
-    let mut config_path = base_dirs.config_dir().to_path_buf();
-    config_path.push("kcli");
-    config_path.push("kernel-patches");
+async fn update_kernel_patches_menu(theme: &ColorfulTheme) -> Result<()> {
+    let selections = vec![
+        "Update",
+        "Pin a Revision",
+        "Use Another Remote",
+        "Unpin",
+        "<- Back to Main Menu",
+    ];
+    let selection = Select::with_theme(theme)
+        .with_prompt("Update the kernel-patches clone")
+        .items(&selections)
+        .default(0)
+        .interact()?;
 
-    // Check if the target directory already exists
-    if config_path.exists() {
-        println!(
-            "Kernel patches directory already exists at '{}'.",
-            config_path.to_string_lossy()
-        );
-        return Ok(config_path);
+    let options = match selections[selection] {
+        "Update" => UpdateOptions::default(),
+        "Pin a Revision" => UpdateOptions {
+            pin: Some(
+                Input::with_theme(theme)
+                    .with_prompt("Commit, branch or tag")
+                    .interact_text()?,
+            ),
+            ..UpdateOptions::default()
+        },
+        "Use Another Remote" => UpdateOptions {
+            remote: Some(
+                Input::with_theme(theme)
+                    .with_prompt("Remote URL or path")
+                    .interact_text()?,
+            ),
+            ..UpdateOptions::default()
+        },
+        "Unpin" => UpdateOptions {
+            unpin: true,
+            ..UpdateOptions::default()
+        },
+        _ => return Ok(()),
+    };
+    if let Err(err) = kernel_patches::update(&options).await {
+        eprintln!("Error: {:#}", err);
     }
+    Ok(())
+}
 
-    // Temporary directory for cloning to avoid name conflict
-    let tmp_dir = "/tmp/kernel_patches_tmp";
+async fn patch_kernel_process(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
+    let kernel_dir = match select_kernel_tree(theme, packages_dir).await? {
+        Some(kernel_dir) => kernel_dir,
+        None => return Ok(()),
+    };
 
-    // Clone the repository into the temporary directory
-    if Path::new(tmp_dir).exists() {
-        fs::remove_dir_all(tmp_dir).context("Failed to remove existing temporary directory")?;
-    }
+    // Clone or use existing patches directory
+    let patches_dir = kernel_patches::ensure_clone().await?;
+    let mut browse_dir = patch::version_dir(&kernel_dir, &patches_dir).await;
+    let sources = patch_sources::roots().await?;
 
-    Command::new("git")
-        .args(["clone", repo_url, tmp_dir])
-        .output()
-        .await
-        .context("Failed to clone kernel patches repository")?;
-
-    // Copy the directory instead of renaming
-    let tmp_path = Path::new(tmp_dir);
-    if tmp_path.exists() {
-        let mut options = CopyOptions::new();
-        options.copy_inside = true;
-        copy(tmp_path, &config_path, &options)
-            .context("Failed to copy kernel patches to config directory")?;
-        // Clean up temporary directory
-        fs::remove_dir_all(tmp_path).context("Failed to remove temporary directory")?;
-    } else {
-        return Err(anyhow::anyhow!(
-            "Temporary directory not found after cloning."
-        ));
-    }
+    loop {
+        let mut series = Series::load(&kernel_dir)?;
+        println!();
+        print_series(&series);
 
-    Ok(config_path)
-}
+        let selections = vec![
+            "Add Patches",
+            "Remove Patch",
+            "Clear Series",
+            "Check Series",
+            "Apply Series",
+            "Revert Patch",
+            "Resolve Conflicts",
+            "Rejected Hunks",
+            "Patch Backend",
+            "<- Back to Main Menu",
+        ];
+        let selection = Select::with_theme(theme)
+            .with_prompt(format!("Patch series of {}", kernel_dir.display()))
+            .items(&selections)
+            .default(0)
+            .interact()?;
 
-async fn navigate_and_select_patch(patch_dir: PathBuf) -> Result<Option<PathBuf>> {
-    let mut current_dir = patch_dir;
-    let mut history = Vec::new(); // Stack to track the path history
+        match selections[selection] {
+            "Add Patches" => {
+                // Pick a source first when there is more than CachyOS's
+                let (source, root) = if sources.len() == 1 {
+                    sources[0].clone()
+                } else {
+                    let mut items: Vec<String> = sources
+                        .iter()
+                        .map(|(name, root)| format!("{}  ({})", name, root.display()))
+                        .collect();
+                    items.push("<- Back".to_string());
+                    let index = Select::with_theme(theme)
+                        .with_prompt("Patch source")
+                        .items(&items)
+                        .default(0)
+                        .interact()?;
+                    match sources.get(index) {
+                        Some(source) => source.clone(),
+                        None => continue,
+                    }
+                };
+                if !browse_dir.starts_with(&root) {
+                    browse_dir = patch::version_dir(&kernel_dir, &root).await;
+                }
+                // Keep browsing until the user goes back, adding each pick
+                while let Some(picked) =
+                    navigate_and_select_patch(&source, root.clone(), browse_dir.clone()).await?
+                {
+                    match patch::expand(&picked) {
+                        Ok(patches) => {
+                            series.add(patches, &patches_dir);
+                            series.save(&kernel_dir)?;
+                            println!("Added {}", picked.display());
+                        }
+                        Err(err) => eprintln!("Error: {:#}", err),
+                    }
+                    if let Some(parent) = picked.parent() {
+                        browse_dir = parent.to_path_buf();
+                    }
+                }
+            }
+            "Remove Patch" => {
+                if series.is_empty() {
+                    continue;
+                }
+                let mut items: Vec<String> = series
+                    .patches()
+                    .iter()
+                    .map(|patch| patch_sources::label(patch))
+                    .collect();
+                items.push("<- Back".to_string());
+                let index = Select::with_theme(theme)
+                    .with_prompt("Patch to remove")
+                    .items(&items)
+                    .default(0)
+                    .interact()?;
+                if index < series.patches().len() {
+                    series.remove(index);
+                    series.save(&kernel_dir)?;
+                }
+            }
+            "Clear Series" => {
+                series.clear();
+                series.save(&kernel_dir)?;
+            }
+            "Check Series" => match series::check_series(&kernel_dir).await {
+                Ok(checks) => preflight::print_table(&checks),
+                Err(err) => eprintln!("Error: {:#}", err),
+            },
+            "Apply Series" => {
+                let checks = match series::check_series(&kernel_dir).await {
+                    Ok(checks) => checks,
+                    Err(err) => {
+                        eprintln!("Error: {:#}", err);
+                        continue;
+                    }
+                };
+                let force = checks.iter().all(|check| check.applies()) || {
+                    preflight::print_table(&checks);
+                    Confirm::with_theme(theme)
+                        .with_prompt("Some hunks do not apply. Apply the series anyway?")
+                        .default(false)
+                        .interact()?
+                };
+                if !force {
+                    continue;
+                }
+                if let Err(err) = series::apply_series(&kernel_dir, true).await {
+                    eprintln!("Error: {:#}", err);
+                    let partial = TreeMetadata::load(&kernel_dir)?.partial_patch.is_some();
+                    if partial
+                        && Confirm::with_theme(theme)
+                            .with_prompt("Resolve the conflicts it left now?")
+                            .default(true)
+                            .interact()?
+                    {
+                        patch::resolve_conflicts(theme, &kernel_dir).await?;
+                    }
+                }
+            }
+            "Revert Patch" => {
+                let metadata = TreeMetadata::load(&kernel_dir)?;
+                let applied: Vec<&Path> = metadata
+                    .patches
+                    .iter()
+                    .chain(&metadata.partial_patch)
+                    .map(|applied| applied.path.as_path())
+                    .collect();
+                if applied.is_empty() {
+                    println!("No patches are applied to {}.", kernel_dir.display());
+                    continue;
+                }
+                let mut items: Vec<String> = applied
+                    .iter()
+                    .map(|path| patch_sources::label(path))
+                    .collect();
+                items.push("<- Back".to_string());
+                let index = Select::with_theme(theme)
+                    .with_prompt("Revert to before")
+                    .items(&items)
+                    .default(applied.len() - 1)
+                    .interact()?;
+                let Some(path) = applied.get(index) else {
+                    continue;
+                };
+                let later = applied.len() - index - 1;
+                if later > 0
+                    && !Confirm::with_theme(theme)
+                        .with_prompt(format!("Revert the {} patches applied after it too?", later))
+                        .default(false)
+                        .interact()?
+                {
+                    continue;
+                }
+                if let Err(err) = patch::revert(&kernel_dir, path).await {
+                    eprintln!("Error: {:#}", err);
+                }
+            }
+            "Resolve Conflicts" => {
+                if let Err(err) = patch::resolve_conflicts(theme, &kernel_dir).await {
+                    eprintln!("Error: {:#}", err);
+                }
+            }
+            "Rejected Hunks" => {
+                if let Err(err) = rejects::review(theme, &kernel_dir).await {
+                    eprintln!("Error: {:#}", err);
+                }
+            }
+            "Patch Backend" => {
+                let current = TreeMetadata::load(&kernel_dir)?.patch_backend;
+                let backends: Vec<PatchBackend> = PatchBackend::ALL
+                    .into_iter()
+                    .filter(|backend| !backend.needs_git() || kernel_dir.join(".git").exists())
+                    .collect();
+                let items: Vec<String> = backends
+                    .iter()
+                    .map(|backend| match backend {
+                        PatchBackend::Patch => "patch (GNU patch --merge)".to_string(),
+                        PatchBackend::GitApply => "git-apply (git apply --3way)".to_string(),
+                        PatchBackend::GitAm => "git-am (git am -3, keeps authors)".to_string(),
+                    })
+                    .collect();
+                let index = Select::with_theme(theme)
+                    .with_prompt("Apply patches with")
+                    .items(&items)
+                    .default(backends.iter().position(|b| *b == current).unwrap_or(0))
+                    .interact()?;
+                patch::set_backend(&kernel_dir, backends[index])?;
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+/// Browses a patch source from `start_dir` and returns the picked patch file
+/// or directory.
+async fn navigate_and_select_patch(
+    source: &str,
+    patch_dir: PathBuf,
+    start_dir: PathBuf,
+) -> Result<Option<PathBuf>> {
+    // Stack to track the path history, so going back from the start
+    // directory still walks up to the top of the clone
+    let mut history: Vec<PathBuf> = start_dir
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(&patch_dir))
+        .map(Path::to_path_buf)
+        .collect();
+    history.reverse();
+    let mut current_dir = start_dir;
 
     loop {
         // Spawn a blocking task to read and process the directory
@@ -677,11 +1890,12 @@ async fn navigate_and_select_patch(patch_dir: PathBuf) -> Result<Option<PathBuf>
         if !history.is_empty() {
             options.insert(0, "<- Go Back".to_string()); // Option to go back in history
         } else {
-            options.insert(0, "<- Back to Main Menu".to_string()); // Option to return to main menu from top directory
+            options.insert(0, "<- Back".to_string()); // Option to return to the series menu from top directory
         }
+        options.insert(1, "+ All patches in this folder".to_string());
 
         let selection = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt("Select a folder or patch file")
+            .with_prompt(format!("Select a folder or patch file from {}", source))
             .items(&options)
             .default(0)
             .interact()?;
@@ -696,111 +1910,28 @@ async fn navigate_and_select_patch(patch_dir: PathBuf) -> Result<Option<PathBuf>
             }
         }
 
+        if selection == 1 {
+            return Ok(Some(current_dir));
+        }
+
         // Properly adjust the index for selecting entries
-        let selected_path = &entries_vec[selection - 1].1; // Adjust index by one to account for the go back option
+        let selected_path = &entries_vec[selection - 2].1; // Adjust index by two to account for the navigation options
 
         // Check if it's a directory or a .patch file
         if selected_path.is_dir() {
             history.push(current_dir.clone()); // Push current directory to history
             current_dir = selected_path.clone();
-        } else if selected_path.extension().and_then(std::ffi::OsStr::to_str) == Some("patch") {
+        } else if patch_sources::is_patch_file(selected_path) {
             return Ok(Some(selected_path.clone()));
         }
     }
 }
 
-async fn apply_patch(patch_file: PathBuf, kernel_dir: &Path) -> Result<(), anyhow::Error> {
-    let patch_file_str = patch_file.to_string_lossy().to_string();
-
-    // Command to apply the patch
-    let output = Command::new("sh")
-        .current_dir(kernel_dir) // Sets the working directory to kernel_dir
-        .arg("-c")
-        .arg(format!("patch -Np1 --merge < {}", patch_file_str))
-        .output()
-        .await
-        .context("Failed to apply patch")?;
-
-    // Check if the command was successful
-    if !output.status.success() {
-        let error_message = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to apply patch: {}", error_message);
-    }
-
-    println!(
-        "Patch applied successfully: {}",
-        patch_file.to_string_lossy()
-    );
-
-    Ok(())
-}
-
-async fn modify_kernel_config(kernel_dir: &str, config_commands: Vec<String>) -> Result<()> {
-    // Ensure the kernel directory exists and has a scripts/config file
-    if !std::path::Path::new(&format!("{}/scripts/config", kernel_dir)).exists() {
-        return Err(anyhow::anyhow!(
-            "Kernel directory does not contain scripts/config"
-        ));
-    }
-
-    for command in config_commands {
-        let args: Vec<&str> = command.split_whitespace().collect();
-        let status = Command::new("sh")
-            .arg("-c")
-            .arg(format!("{}/scripts/config {}", kernel_dir, command))
-            .current_dir(kernel_dir) // Ensure we're in the right directory
-            .status()
-            .await
-            .context("Failed to execute kernel config command")?;
-
-        if !status.success() {
-            eprintln!("Failed to execute command: {}", command);
-            return Err(anyhow::anyhow!("Kernel configuration command failed"));
-        }
-    }
-
-    println!("Kernel configuration updated successfully.");
-
-    Ok(())
-}
-
-async fn build_kernel_menu(
-    config: &mut KernelConfig,
-    theme: &ColorfulTheme,
-    packages_dir: &Path,
-) -> Result<()> {
-    // First, list available Linux versions
-    let packages = pkg_manager::list_kernel_packages(packages_dir)
-        .await
-        .context("Failed to list kernel packages")?;
-
-    // If no packages are found, return an error or a message
-    if packages.is_empty() {
-        return Err(anyhow::anyhow!("No kernel packages found."));
-    }
-
-    // add <- Go Back to Main Menu option
-    let mut packages = packages;
-    packages.push("<- Back to Main Menu".to_string());
-
-    // Prompt the user to select a Linux version
-    let selected_package_index = Select::with_theme(theme)
-        .with_prompt("Select a Linux version to configure")
-        .items(&packages)
-        .default(0)
-        .interact()?;
-
-    // if <- Back to Main Menu is selected, return to main menu
-    if selected_package_index == packages.len() - 1 {
-        return Ok(());
-    }
-
-    // Get the selected package name
-    let selected_package = &packages[selected_package_index];
-    println!("Selected package for configuration: {}", selected_package);
-
-    // now we should enter the kernel directory
-    let kernel_dir = Path::new(packages_dir).join(selected_package);
+async fn build_kernel_menu(theme: &ColorfulTheme, packages_dir: &Path) -> Result<()> {
+    let kernel_dir = match select_kernel_tree(theme, packages_dir).await? {
+        Some(kernel_dir) => kernel_dir,
+        None => return Ok(()),
+    };
 
     loop {
         let selections = vec![
@@ -816,80 +1947,51 @@ async fn build_kernel_menu(
             .default(0)
             .interact()?;
 
-        match selections.get(selection) {
-            Some(&"Compile") => {
-                let nprocs = num_cpus::get();
-                let make_command = format!(
-                    "LOCALVERSION=\"-capy\" KCFLAGS=\"-mpopcnt -fivopts -fmodulo-sched\" -j{}",
-                    nprocs
-                );
-                run_make_command(&make_command, &kernel_dir).await?;
-            }
-            Some(&"Install Modules") => {
-                run_make_command("modules_install", &kernel_dir).await?;
-            }
-            Some(&"Install Headers") => {
-                run_make_command("headers_install", &kernel_dir).await?;
-            }
+        let target = match selections.get(selection) {
+            Some(&"Compile") => BuildTarget::Compile,
+            Some(&"Install Modules") => BuildTarget::Modules,
+            Some(&"Install Headers") => BuildTarget::Headers,
             Some(&"<- Back to Main Menu") => return Ok(()),
             _ => return Err(anyhow::anyhow!("Invalid selection")),
-        }
+        };
+        build_tree(&kernel_dir, target, &BuildSettings::default()).await?;
     }
 }
 
-async fn run_make_command(args: &str, kernel_dir: &Path) -> Result<()> {
-    let command = format!("{}", args);
-    use shell_words::split; // Add shell_words to your Cargo.toml
-
-    let config_path = kernel_dir.join(".config");
-    // Check if the .config file exists
-    if !config_path.exists() {
-        println!("`.config` file not found, downloading from repository...");
-        // URL to download the .config file
-        let config_url =
-            "https://raw.githubusercontent.com/CachyOS/linux-cachyos/master/linux-cachyos/config";
-        let response = reqwest::get(config_url)
-            .await
-            .context("Failed to download the .config file")?;
-        let contents = response
-            .text()
-            .await
-            .context("Failed to read the .config file content")?;
-
-        // Write the contents to the .config file
-        tokio::fs::write(&config_path, contents)
-            .await
-            .context("Failed to write the .config file")?;
-        println!(
-            "`.config` file downloaded and saved to {}",
-            config_path.display()
-        );
-    } else {
-        println!("Using existing `.config` file at {}", config_path.display());
-    }
-
-    // run make kernelversion > version
-    let status = Command::new("make")
-        .args(split(&command).context("Failed to parse command arguments")?)
-        .current_dir(kernel_dir) // Use the provided kernel directory
-        .status()
-        .await
-        .context("Failed to execute make command")?;
+/// Runs a build step and records its outcome in the tree's metadata.
+async fn build_tree(kernel_dir: &Path, target: BuildTarget, settings: &BuildSettings) -> Result<()> {
+    let result = run_make_command(&target.make_args(settings), kernel_dir).await;
+    let artifacts = match (&result, target) {
+        (Ok(()), BuildTarget::Compile) => compile_artifacts(kernel_dir).await,
+        _ => Vec::new(),
+    };
+    TreeMetadata::record_build(kernel_dir, target.name(), result.is_ok(), artifacts)?;
+    result
+}
 
-    if status.success() {
-        println!("Command executed successfully.");
-    } else {
-        eprintln!("Command execution failed.");
-        return Err(anyhow::anyhow!("Make command failed"));
+/// The kernel image and `vmlinux` left in the tree by a compile.
+async fn compile_artifacts(kernel_dir: &Path) -> Vec<PathBuf> {
+    let mut artifacts = Vec::new();
+    let image_name = Command::new("make")
+        .args(["-s", "image_name"])
+        .current_dir(kernel_dir)
+        .output()
+        .await;
+    if let Some(output) = image_name.ok().filter(|output| output.status.success()) {
+        let image = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !image.is_empty() {
+            artifacts.push(kernel_dir.join(image));
+        }
     }
+    artifacts.push(kernel_dir.join("vmlinux"));
+    artifacts.retain(|artifact| artifact.is_file());
+    artifacts
+}
 
-    let args_vec = split(&command).context("Failed to parse command arguments")?;
-
-    let (make, args) = args_vec
-        .split_first()
-        .ok_or_else(|| anyhow::anyhow!("No command found"))?;
+async fn run_make_command(args: &[String], kernel_dir: &Path) -> Result<()> {
+    ensure_kernel_config(kernel_dir).await?;
 
-    let status = Command::new(make)
+    let status = Command::new("make")
         .args(args)
         .current_dir(kernel_dir) // Use the provided kernel directory
         .status()
@@ -900,164 +2002,204 @@ async fn run_make_command(args: &str, kernel_dir: &Path) -> Result<()> {
         println!("Command executed successfully.");
     } else {
         eprintln!("Command execution failed.");
-        return Err(anyhow::anyhow!("Make command failed"));
+        return Err(Error::CommandFailed(format!("make {}", args.join(" "))).into());
     }
 
     Ok(())
 }
-async fn configure_download_kernel(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Stable Kernel", "RC Kernel", "<-"];
-    let selection = Select::with_theme(theme)
-        .with_prompt("Select Kernel Version to Download")
-        .items(&selections)
-        .default(0)
-        .interact()?;
 
-    let (url, dir_name) = match selections[selection] {
-        "Stable Kernel" => (
-            "https://git.kernel.org/pub/scm/linux/kernel/git/stable/linux.git",
-            "linux-stable",
-        ),
-        "RC Kernel" => ("https://github.com/torvalds/linux.git", "linux-rc"),
-        "<-" => return Ok(()),
-        _ => return Err(anyhow::anyhow!("Invalid selection")),
-    };
-
-    // Temporary directory for cloning to avoid name conflict
-    let tmp_dir = "/tmp/ksrc_tmp";
-    let mut config_path = config_dir().unwrap();
-    config_path.push("kcli");
-    config_path.push("ksrc");
-    fs::create_dir_all(&config_path)?;
-
-    let target_dir = config_path.join(dir_name);
-
-    fs::create_dir_all(&target_dir.parent().unwrap())
-        .context("Failed to create 'ksrc' directory")?;
+/// The CachyOS kernel config used as the baseline for new trees.
+const CACHYOS_CONFIG_URL: &str =
+    "https://raw.githubusercontent.com/CachyOS/linux-cachyos/master/linux-cachyos/config";
 
-    // Execute 'git clone' within the temporary directory
-    let output = TokioCommand::new("git")
-        .args(["clone", "--depth", "1", url, tmp_dir])
-        .output()
+async fn download_cachyos_config() -> Result<String> {
+    let response = reqwest::get(CACHYOS_CONFIG_URL)
+        .await
+        .map_err(Error::from)
+        .context("Failed to download the .config file")?;
+    let contents = response
+        .text()
         .await
-        .context("Failed to execute git clone command")?;
+        .map_err(Error::from)
+        .context("Failed to read the .config file content")?;
+    Ok(contents)
+}
 
-    if !output.status.success() {
-        let error_message = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow::anyhow!("Git clone failed: {}", error_message));
-    }
+/// Downloads the CachyOS `.config` into the tree unless one already exists.
+async fn ensure_kernel_config(kernel_dir: &Path) -> Result<()> {
+    let config_path = kernel_dir.join(".config");
+    // Check if the .config file exists
+    if !config_path.exists() {
+        println!("`.config` file not found, downloading from repository...");
+        let contents = download_cachyos_config().await?;
 
-    // Copy the directory instead of renaming
-    let tmp_path = Path::new(tmp_dir);
-    if tmp_path.exists() {
-        let mut options = CopyOptions::new();
-        options.copy_inside = true;
-        copy(tmp_path, &target_dir, &options)
-            .context(format!("Failed to copy directory to '{}'", dir_name))?;
+        // Write the contents to the .config file
+        tokio::fs::write(&config_path, contents)
+            .await
+            .context("Failed to write the .config file")?;
+        seed::record_seed(kernel_dir, &SeedSource::CachyOs, CACHYOS_CONFIG_URL.to_string())?;
         println!(
-            "Kernel source downloaded and moved to '{}'.",
-            target_dir.to_string_lossy()
+            "`.config` file downloaded and saved to {}",
+            config_path.display()
         );
-        // Clean up temporary directory
-        fs::remove_dir_all(tmp_path).context("Failed to remove temporary directory")?;
     } else {
-        return Err(anyhow::anyhow!(
-            "Temporary directory not found after cloning."
-        ));
+        println!("Using existing `.config` file at {}", config_path.display());
     }
 
     Ok(())
 }
 
-fn configure_cpusched(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["CachyOS", "PDS", "None"];
-    let selection = Select::with_theme(theme)
-        .with_prompt("CPU Scheduler Configuration")
-        .items(&selections)
-        .default(0)
-        .interact()?;
-    config.cpusched_selection = selections[selection].to_string();
-    Ok(())
+/// Loads a config given on the command line: `cachyos` for the CachyOS
+/// baseline, a `.config` file, or a source tree whose `.config` is used.
+/// Returns the tree as well, so its Kconfig files can be read.
+async fn load_config_arg(packages_dir: &Path, arg: &str) -> Result<(DotConfig, Option<PathBuf>)> {
+    if arg == "cachyos" {
+        let contents = download_cachyos_config().await?;
+        return Ok((DotConfig::parse(Path::new(CACHYOS_CONFIG_URL), &contents), None));
+    }
+    let path = Path::new(arg);
+    if path.is_file() {
+        return Ok((DotConfig::load(path)?, None));
+    }
+    let kernel_dir = resolve_kernel_tree(packages_dir, arg)?;
+    Ok((DotConfig::load(&kernel_dir.join(".config"))?, Some(kernel_dir)))
 }
 
-fn configure_llvm_lto(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Thin", "Full", "None"];
+async fn configure_download_kernel(theme: &ColorfulTheme) -> Result<()> {
+    let selections = vec!["Stable Kernel", "RC Kernel", "kernel.org Release", "<-"];
     let selection = Select::with_theme(theme)
-        .with_prompt("LLVM LTO Configuration")
+        .with_prompt("Select Kernel Version to Download")
         .items(&selections)
         .default(0)
         .interact()?;
-    config.llvm_lto_selection = selections[selection].to_string();
-    Ok(())
-}
 
-fn configure_tick_rate(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["100", "250", "500", "600", "1000"];
-    let selection = Select::with_theme(theme)
-        .with_prompt("Tick Rate Configuration")
-        .items(&selections)
-        .default(0)
-        .interact()?;
-    config.tick_rate = selections[selection].to_string();
-    Ok(())
-}
+    let channel = match selections[selection] {
+        "Stable Kernel" => KernelChannel::Stable,
+        "RC Kernel" => KernelChannel::Rc,
+        "kernel.org Release" => return download_release_menu(theme).await,
+        "<-" => return Ok(()),
+        _ => return Err(anyhow::anyhow!("Invalid selection")),
+    };
 
-fn configure_nr_cpus(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["1", "2", "4", "8", "16", "32", "64", "128", "256", "320"];
-    let selection = Select::with_theme(theme)
-        .with_prompt("NR_CPUS Configuration")
-        .items(&selections)
-        .default(0)
-        .interact()?;
-    config.nr_cpus = selections[selection].to_string();
-    Ok(())
+    let tag: String = Input::with_theme(theme)
+        .with_prompt("Tag or branch (leave empty for the latest commit)")
+        .allow_empty(true)
+        .interact_text()?;
+    let tag = Some(tag.trim()).filter(|tag| !tag.is_empty());
+    let default_name = match tag {
+        Some(tag) => format!("linux-{}", tag.trim_start_matches('v')),
+        None => channel.source().1.to_string(),
+    };
+    let dir_name: String = Input::with_theme(theme)
+        .with_prompt("Source tree name")
+        .default(default_name)
+        .interact_text()?;
+
+    download_kernel_source(channel, tag, dir_name.trim()).await
 }
 
-fn configure_hugepages(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Always", "Madvise", "No"];
+async fn download_release_menu(theme: &ColorfulTheme) -> Result<()> {
+    let index = ReleaseIndex::load().await?;
+    index.warn_if_stale();
+    let releases = index.releases();
+    let mut items: Vec<String> = releases.iter().map(|release| release.to_string()).collect();
+    items.push("<-".to_string());
     let selection = Select::with_theme(theme)
-        .with_prompt("Hugepages Configuration")
-        .items(&selections)
+        .with_prompt("Select a kernel.org release")
+        .items(&items)
         .default(0)
         .interact()?;
-    config.hugepages = selections[selection].to_string();
+    let Some(release) = releases.get(selection) else {
+        return Ok(());
+    };
+
+    let allow_unsigned = release.pgp.is_none()
+        && Confirm::with_theme(theme)
+            .with_prompt(format!(
+                "kernel.org publishes no signature for {}. Download it anyway?",
+                release.version
+            ))
+            .interact()?;
+    if release.pgp.is_none() && !allow_unsigned {
+        return Ok(());
+    }
+    kernel_org::install_release(release, &ksrc_dir()?, allow_unsigned).await?;
     Ok(())
 }
 
-fn configure_lru(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Standard", "Stats", "None"];
-    let selection = Select::with_theme(theme)
-        .with_prompt("LRU Configuration")
-        .items(&selections)
-        .default(0)
-        .interact()?;
-    config.lru = selections[selection].to_string();
+/// Checks the channel head, or a tag or branch, out to `ksrc/<dir_name>` as
+/// a worktree of the shared git mirror, fetching new commits first. An
+/// existing worktree is moved to the new commit.
+async fn download_kernel_source(
+    channel: KernelChannel,
+    tag: Option<&str>,
+    dir_name: &str,
+) -> Result<()> {
+    let target_dir = ksrc_dir()?.join(dir_name);
+    // Moving the worktree would drop the commits of kcli's patches
+    let metadata = TreeMetadata::load(&target_dir)?;
+    if !metadata.patches.is_empty() || metadata.partial_patch.is_some() {
+        anyhow::bail!(
+            "{} has patches applied; undo them with `kcli patch reset {}` first",
+            target_dir.display(),
+            dir_name
+        );
+    }
+
+    let mirror = Mirror::open().await?;
+    mirror.fetch(channel).await?;
+    let commit = mirror.resolve(channel, tag).await?;
+    mirror.checkout(&commit, &target_dir).await?;
+    TreeMetadata::record_origin(
+        &target_dir,
+        update::kernel_version(&target_dir).await.ok(),
+        Origin {
+            url: channel.source().0.to_string(),
+            commit: Some(commit),
+        },
+    )?;
+    println!(
+        "Kernel source checked out to '{}'.",
+        target_dir.to_string_lossy()
+    );
     Ok(())
 }
 
-fn configure_tick_type(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Periodic", "NoHz_Full", "NoHz_Idle"];
+fn configure_choice<T: ConfigChoice>(current: &mut T, theme: &ColorfulTheme) -> Result<()> {
+    let labels: Vec<&str> = T::ALL.iter().map(|choice| choice.label()).collect();
     let selection = Select::with_theme(theme)
-        .with_prompt("Tick Type Configuration")
-        .items(&selections)
-        .default(0)
+        .with_prompt(T::PROMPT)
+        .items(&labels)
+        .default(T::ALL.iter().position(|choice| choice == current).unwrap_or(0))
         .interact()?;
-    config.tick_type = selections[selection].to_string();
+    *current = T::ALL[selection];
     Ok(())
 }
 
-fn configure_preempt_type(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
-    let selections = vec!["Voluntary", "Preempt", "None"];
+fn configure_nr_cpus(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
+    let mut selections: Vec<String> = NrCpus::PRESETS.iter().map(|n| n.to_string()).collect();
+    selections.push("Custom".to_string());
     let selection = Select::with_theme(theme)
-        .with_prompt("Preempt Type Configuration")
+        .with_prompt("NR_CPUS Configuration")
         .items(&selections)
         .default(0)
         .interact()?;
-    config.preempt_type = selections[selection].to_string();
+
+    config.nr_cpus = match NrCpus::PRESETS.get(selection) {
+        Some(&count) => NrCpus::new(count)?,
+        None => {
+            let count: u32 = Input::with_theme(theme)
+                .with_prompt(format!("NR_CPUS (2-{})", NrCpus::MAX))
+                .with_initial_text(config.nr_cpus.to_string())
+                .validate_with(|count: &u32| NrCpus::new(*count).map(|_| ()).map_err(|e| e.to_string()))
+                .interact_text()?;
+            NrCpus::new(count)?
+        }
+    };
     Ok(())
 }
 
-fn configure_system_optimizations(config: &mut KernelConfig, theme: &ColorfulTheme) -> Result<()> {
+fn configure_system_optimizations() -> Result<()> {
     // Placeholder: Implement system optimizations configuration
     // This function can use a combination of `Select` and `Confirm` for different types of options
     println!("Configuring System Optimizations (Placeholder)");
@@ -1066,229 +2208,40 @@ fn configure_system_optimizations(config: &mut KernelConfig, theme: &ColorfulThe
 
 async fn apply_kernel_configuration(
     config: &KernelConfig,
-    theme: &ColorfulTheme,
-    packages_dir: &Path,
+    source: &ConfigSource,
+    kernel_src_dir: &Path,
 ) -> Result<()> {
-    // First, list available Linux versions
-    let packages = pkg_manager::list_kernel_packages(packages_dir)
-        .await
-        .context("Failed to list kernel packages")?;
-
-    // If no packages are found, return an error or a message
-    if packages.is_empty() {
-        return Err(anyhow::anyhow!("No kernel packages found."));
-    }
-
-    // add <- Go Back to Main Menu option
-    let mut packages = packages;
-    packages.push("<- Back to Main Menu".to_string());
-
-    // Prompt the user to select a Linux version
-    let selected_package_index = Select::with_theme(theme)
-        .with_prompt("Select a Linux version to configure")
-        .items(&packages)
-        .default(0)
-        .interact()?;
-
-    // if <- Back to Main Menu is selected, return to main menu
-    if selected_package_index == packages.len() - 1 {
-        return Ok(());
-    }
-
-    // Get the selected package name
-    let selected_package = &packages[selected_package_index];
-    println!("Selected package for configuration: {}", selected_package);
-
-    // now we should enter the kernel directory
-    let kernel_src_dir = Path::new(packages_dir).join(selected_package);
-
-    let arch_config_cmd = format!("CONFIG_{}", config.architecture);
-    tokio::process::Command::new("sh")
-        .arg("-c")
-        .arg(format!(
-            "{}/scripts/config --disable CONFIG_GENERIC_CPU --enable {}",
-            kernel_src_dir.display(),
-            arch_config_cmd
-        ))
-        .output()
-        .await
-        .context("Failed to set architecture")?;
-
-    // CPU Scheduler Configuration
-    match config.cpusched_selection.as_str() {
-        "None" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -d SCHED_BORE -d SCHED_CLASS_EXT -d SCHED_PDS")
-                .output()
-                .await
-                .context("Failed to disable CPU scheduler config")?;
-        }
-        _ => {}
-    }
+    println!("Applying kernel configuration to {}", kernel_src_dir.display());
+    apply_kernel_toggles(kernel_src_dir, &config.toggles()).await?;
+    TreeMetadata::update(kernel_src_dir, |metadata| {
+        metadata.config_profile = Some(source.to_string());
+    })
+}
 
-    // Hugepages
-    match config.hugepages.as_str() {
-        "Always" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -e HUGETLBFS -e HUGETLB_PAGE -e HUGETLB -e HUGETLB_PAGE_SIZE_VARIABLE")
-            .current_dir(kernel_src_dir.clone())  // Set the current directory here
-            .output()
-            .await
-            .context("Failed to enable hugepages")?;
-        }
-        "Madvise" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -e HUGETLBFS -e HUGETLB_PAGE -e HUGETLB -d HUGETLB_PAGE_SIZE_VARIABLE")
-            .current_dir(kernel_src_dir.clone())  // Set the current directory here
-            .output()
-            .await
-            .context("Failed to enable hugepages")?;
-        }
-        "No" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -d HUGETLBFS -d HUGETLB_PAGE -d HUGETLB -d HUGETLB_PAGE_SIZE_VARIABLE")
-            .current_dir(kernel_src_dir.clone())  // Set the current directory here
-            .output()
-            .await
-            .context("Failed to disable hugepages")?;
-        }
-        _ => {}
-    }
+/// Writes the toggles into the tree's `.config` and reports the ones that
+/// Kconfig dropped or overrode afterwards. Nothing is written when any
+/// toggle is invalid.
+async fn apply_kernel_toggles(kernel_src_dir: &Path, toggles: &[Toggle]) -> Result<()> {
+    ensure_kernel_config(kernel_src_dir).await?;
+    let report = dotconfig::apply_toggles(kernel_src_dir, toggles).await?;
 
-    // LRU
-    match config.lru.as_str() {
-        "Standard" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -e LRU_LIST -d LRU_STATS -d LRU")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to configure standard LRU")?;
-        }
-        "Stats" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -d LRU_LIST -e LRU_STATS -d LRU")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to configure LRU with stats")?;
-        }
-        "None" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -d LRU_LIST -d LRU_STATS -e LRU")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to disable LRU")?;
-        }
-        _ => {}
+    for rejected in &report.rejected {
+        println!("warning: {}", rejected);
     }
-
-    // Preempt Type Configuration
-    match config.preempt_type.as_str() {
-        "full" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -e PREEMPT_BUILD -d PREEMPT_NONE -d PREEMPT_VOLUNTARY -e PREEMPT -e PREEMPT_COUNT -e PREEMPTION -e PREEMPT_DYNAMIC")
-            .current_dir(kernel_src_dir.clone()) // Set the current directory here
-            .output()
-            .await
-            .context("Failed to configure full preemption")?;
-        }
-        "voluntary" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -e PREEMPT_BUILD -d PREEMPT_NONE -e PREEMPT_VOLUNTARY -d PREEMPT -e PREEMPT_COUNT -e PREEMPTION -d PREEMPT_DYNAMIC")
-            .current_dir(kernel_src_dir.clone()) // Set the current directory here
-            .output()
-            .await
-            .context("Failed to configure voluntary preemption")?;
-        }
-        "none" => {
-            tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg("scripts/config -e PREEMPT_NONE_BUILD -e PREEMPT_NONE -d PREEMPT_VOLUNTARY -d PREEMPT -d PREEMPTION -d PREEMPT_DYNAMIC")
-            .current_dir(kernel_src_dir.clone()) // Set the current directory here
-            .output()
-            .await
-            .context("Failed to disable preemption")?;
-        }
-        _ => {}
-    }
-
-    // Tick Rate Configuration
-
-    println!("Configuring tick rate to {}", config.tick_rate.as_str());
-    match config.tick_rate.as_str() {
-        "100" | "250" | "500" | "600" | "1000" => {
-            let result = tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg(format!(
-                    "scripts/config -d HZ_300 -e HZ_{} --set-val HZ {}",
-                    config.tick_rate, config.tick_rate
-                ))
-                .current_dir(kernel_src_dir.clone()) // Correct placement of .current_dir()
-                .output()
-                .await
-                .context(format!(
-                    "Failed to configure tick rate to {}",
-                    config.tick_rate
-                ));
-
-            match result {
-                Ok(output) => {
-                    // Handle successful output
-                    println!("Command executed successfully.");
-                }
-                Err(e) => {
-                    // Handle errors
-                    eprintln!("Failed to execute command: {}", e);
-                }
-            }
-        }
-        _ => {
-            println!("Tick rate not supported");
-        }
+    for error in &report.errors {
+        eprintln!("error: {}", error);
     }
-
-    // Tick Type Configuration
-    match config.tick_type.as_str() {
-        "Periodic" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -e TICK_PERIODIC -d TICK_ONESHOT -d NO_HZ_IDLE -d NO_HZ_FULL")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to configure tick type to Periodic")?;
-        }
-        "NoHz_Full" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -d TICK_PERIODIC -d TICK_ONESHOT -d NO_HZ_IDLE -e NO_HZ_FULL")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to configure tick type to NoHz_Full")?;
-        }
-        "NoHz_Idle" => {
-            tokio::process::Command::new("sh")
-                .arg("-c")
-                .arg("scripts/config -d TICK_PERIODIC -e TICK_ONESHOT -e NO_HZ_IDLE -d NO_HZ_FULL")
-                .current_dir(kernel_src_dir.clone()) // Set the current directory here
-                .output()
-                .await
-                .context("Failed to configure tick type to NoHz_Idle")?;
-        }
-        _ => {}
+    if !report.errors.is_empty() {
+        anyhow::bail!(
+            "{} kernel options could not be applied; .config was not changed",
+            report.errors.len()
+        );
     }
 
+    println!(
+        "Kernel configuration updated: {} options applied, {} not kept by Kconfig.",
+        toggles.len(),
+        report.rejected.len()
+    );
     Ok(())
 }
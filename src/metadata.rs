@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::git_source;
+use crate::kernel_patches::PatchesRevision;
+use crate::patch_backend::PatchBackend;
+use crate::snapshot::Snapshot;
+
+const METADATA_FILE: &str = ".kcli.json";
+
+/// What kcli knows about how a source tree was prepared, kept in
+/// `.kcli.json` at the top of the tree.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TreeMetadata {
+    /// Kernel version of the upstream sources, e.g. `6.10.3`.
+    pub upstream_version: Option<String>,
+    /// Where the upstream sources were downloaded from.
+    pub origin: Option<Origin>,
+    /// Where the tree's `.config` was seeded from.
+    pub config_seed: Option<ConfigSeed>,
+    /// Configuration profile last applied to the tree's `.config`.
+    pub config_profile: Option<String>,
+    /// Patches kcli applied on top of the upstream sources, in order.
+    pub patches: Vec<AppliedPatch>,
+    /// A patch that failed part way and left the tree half-patched.
+    pub partial_patch: Option<AppliedPatch>,
+    /// kernel-patches revision the tree's CachyOS patches were taken from.
+    pub kernel_patches: Option<PatchesRevision>,
+    /// Tool the tree's patches are applied with.
+    pub patch_backend: PatchBackend,
+    pub last_build: Option<BuildRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Origin {
+    /// Git remote or tarball URL.
+    pub url: String,
+    /// Commit checked out, for git trees.
+    pub commit: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedPatch {
+    pub path: PathBuf,
+    /// SHA-256 of the patch as it was applied.
+    #[serde(default)]
+    pub sha256: String,
+    /// State of the tree before the patch, used to revert it.
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+}
+
+impl AppliedPatch {
+    pub fn new(path: PathBuf, snapshot: Snapshot) -> Result<Self> {
+        Ok(AppliedPatch {
+            sha256: sha256(&path)?,
+            path,
+            snapshot: Some(snapshot),
+        })
+    }
+}
+
+/// SHA-256 of a patch file, as recorded for applied patches.
+pub fn sha256(path: &Path) -> Result<String> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(&contents)))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// `compile`, `modules`, `headers` or `package`.
+    pub target: String,
+    pub succeeded: bool,
+    /// RFC 3339 timestamp.
+    pub finished_at: String,
+    /// Files the build produced, such as the kernel image or package.
+    pub artifacts: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigSeed {
+    /// `running`, `boot`, `cachyos` or `file`.
+    pub source: String,
+    /// Where the config was read from, e.g. `/boot/config-6.10.3-1-cachyos`.
+    pub location: String,
+    /// RFC 3339 timestamp.
+    pub seeded_at: String,
+}
+
+impl TreeMetadata {
+    fn path(kernel_dir: &Path) -> PathBuf {
+        kernel_dir.join(METADATA_FILE)
+    }
+
+    /// Loads the tree's metadata, or empty metadata when kcli has not
+    /// recorded anything yet.
+    pub fn load(kernel_dir: &Path) -> Result<Self> {
+        let path = Self::path(kernel_dir);
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, kernel_dir: &Path) -> Result<()> {
+        let path = Self::path(kernel_dir);
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Records where a freshly downloaded or updated tree came from. The
+    /// kernel-patches revision is dropped, as the tree has none of its
+    /// patches applied.
+    pub fn record_origin(kernel_dir: &Path, version: Option<String>, origin: Origin) -> Result<()> {
+        Self::update(kernel_dir, |metadata| {
+            metadata.upstream_version = version;
+            metadata.origin = Some(origin);
+            metadata.kernel_patches = None;
+        })
+    }
+
+    /// Records the outcome of a build step; failed builds keep no artifacts.
+    pub fn record_build(
+        kernel_dir: &Path,
+        target: &str,
+        succeeded: bool,
+        artifacts: Vec<PathBuf>,
+    ) -> Result<()> {
+        Self::update(kernel_dir, |metadata| {
+            metadata.last_build = Some(BuildRecord {
+                target: target.to_string(),
+                succeeded,
+                finished_at: chrono::Utc::now().to_rfc3339(),
+                artifacts: if succeeded { artifacts } else { Vec::new() },
+            });
+        })
+    }
+
+    /// Short one-line description for tree pickers, e.g.
+    /// `6.10.3, 2 patches, profile gaming, compile ok 2024-08-12`.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(version) = &self.upstream_version {
+            parts.push(version.clone());
+        }
+        match self.patches.len() {
+            0 => {}
+            1 => parts.push("1 patch".to_string()),
+            count => parts.push(format!("{} patches", count)),
+        }
+        if self.partial_patch.is_some() {
+            parts.push("half-patched".to_string());
+        }
+        if let Some(profile) = &self.config_profile {
+            parts.push(format!("profile {}", profile));
+        }
+        if let Some(build) = &self.last_build {
+            let date = build.finished_at.get(..10).unwrap_or(&build.finished_at);
+            let status = if build.succeeded { "ok" } else { "failed" };
+            parts.push(format!("{} {} {}", build.target, status, date));
+        }
+        parts.join(", ")
+    }
+
+    /// Prints everything recorded about the tree.
+    pub fn print(&self) {
+        let unknown = || "unknown".to_string();
+        println!(
+            "  Version:  {}",
+            self.upstream_version.clone().unwrap_or_else(unknown)
+        );
+        match &self.origin {
+            Some(Origin {
+                url,
+                commit: Some(commit),
+            }) => println!("  Origin:   {} @ {}", url, commit),
+            Some(Origin { url, commit: None }) => println!("  Origin:   {}", url),
+            None => println!("  Origin:   {}", unknown()),
+        }
+        if let Some(seed) = &self.config_seed {
+            println!("  Seed:     {} ({})", seed.source, seed.location);
+        }
+        println!(
+            "  Profile:  {}",
+            self.config_profile
+                .clone()
+                .unwrap_or_else(|| "none".to_string())
+        );
+        println!("  Backend:  {}", self.patch_backend);
+        if self.patches.is_empty() {
+            println!("  Patches:  none");
+        } else {
+            println!("  Patches:");
+            for patch in &self.patches {
+                let hash = patch.sha256.get(..12).unwrap_or("unknown");
+                println!("    {}  {}", hash, patch.path.display());
+            }
+        }
+        if let Some(revision) = &self.kernel_patches {
+            let pin = revision
+                .pin
+                .as_ref()
+                .map(|pin| format!(", pinned to {}", pin))
+                .unwrap_or_default();
+            println!(
+                "  From:     {} @ {}{}",
+                revision.remote, revision.commit, pin
+            );
+        }
+        if let Some(patch) = &self.partial_patch {
+            println!("  Failed:   {} (half-applied)", patch.path.display());
+        }
+        match &self.last_build {
+            Some(build) => {
+                let status = if build.succeeded {
+                    "succeeded"
+                } else {
+                    "failed"
+                };
+                println!(
+                    "  Build:    {} {} at {}",
+                    build.target, status, build.finished_at
+                );
+                for artifact in &build.artifacts {
+                    println!("    {}", artifact.display());
+                }
+            }
+            None => println!("  Build:    never built"),
+        }
+    }
+
+    /// Loads the metadata, applies `change` and saves it again.
+    pub fn update(kernel_dir: &Path, change: impl FnOnce(&mut TreeMetadata)) -> Result<()> {
+        let mut metadata = Self::load(kernel_dir)?;
+        change(&mut metadata);
+        metadata.save(kernel_dir)
+    }
+}
+
+/// Tree name followed by what kcli recorded about it and, for git trees,
+/// `git describe`. Used by every tree picker.
+pub async fn tree_label(kernel_dir: &Path) -> String {
+    let name = kernel_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| kernel_dir.display().to_string());
+    let mut details = TreeMetadata::load(kernel_dir)
+        .map(|metadata| metadata.summary())
+        .unwrap_or_else(|_| "unreadable .kcli.json".to_string());
+    if let Some(describe) = git_source::describe(kernel_dir).await {
+        if details.is_empty() {
+            details = describe;
+        } else {
+            details = format!("{}, {}", describe, details);
+        }
+    }
+    if details.is_empty() {
+        name
+    } else {
+        format!("{} ({})", name, details)
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{Context, Result};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::conflicts;
+use crate::kernel_patches;
+use crate::metadata::{self, AppliedPatch, TreeMetadata};
+use crate::patch_backend::{self, PatchBackend};
+use crate::patch_sources;
+use crate::preflight::{self, PatchCheck};
+use crate::rejects;
+use crate::snapshot;
+
+/// The tree's `major.minor`, e.g. `6.10`, which names the matching directory
+/// of the kernel-patches clone.
+pub async fn kernel_series(kernel_dir: &Path) -> Option<String> {
+    let version = match TreeMetadata::load(kernel_dir)
+        .ok()
+        .and_then(|metadata| metadata.upstream_version)
+    {
+        Some(version) => version,
+        None => crate::update::kernel_version(kernel_dir).await.ok()?,
+    };
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next().filter(|part| !part.is_empty())?;
+    let minor = parts.next().filter(|part| !part.is_empty())?;
+    Some(format!("{}.{}", major, minor))
+}
+
+/// The directory of a patch source matching the tree's version, falling
+/// back to the top of the source.
+pub async fn version_dir(kernel_dir: &Path, patches_root: &Path) -> PathBuf {
+    match kernel_series(kernel_dir).await {
+        Some(series) if patches_root.join(&series).is_dir() => patches_root.join(series),
+        _ => patches_root.to_path_buf(),
+    }
+}
+
+/// Resolves a patch or directory given by the user: an existing path, a
+/// path inside the kernel-patches directory matching the tree's version
+/// (`sched` becomes `6.10/sched`), or one relative to the top of the clone.
+/// `source:path` looks the path up in another patch source the same way.
+pub async fn resolve(kernel_dir: &Path, entry: &Path) -> Result<PathBuf> {
+    if entry.exists() {
+        return Ok(fs::canonicalize(entry)?);
+    }
+    let (patches_root, entry) = match patch_sources::split_entry(entry).await? {
+        Some((root, path)) => (root, path),
+        None => (kernel_patches::ensure_clone().await?, entry.to_path_buf()),
+    };
+    let (patches_root, entry) = (patches_root.as_path(), entry.as_path());
+    let candidates = [
+        version_dir(kernel_dir, patches_root).await.join(entry),
+        patches_root.join(entry),
+    ];
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.exists())
+        .with_context(|| {
+            format!(
+                "{} is neither a path nor part of a patch source",
+                entry.display()
+            )
+        })
+}
+
+/// A patch file, or every `.patch` and `.diff` file below a directory in
+/// path order.
+pub fn expand(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut patches: Vec<PathBuf> = WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| patch_sources::is_patch_file(path))
+        .collect();
+    patches.sort();
+    if patches.is_empty() {
+        anyhow::bail!("{} contains no patch files", path.display());
+    }
+    Ok(patches)
+}
+
+/// Resolves the patches and directories given by the user to patch files,
+/// each with the name it was given as.
+pub async fn resolve_all(kernel_dir: &Path, entries: &[PathBuf]) -> Result<Vec<(String, PathBuf)>> {
+    let mut patches = Vec::new();
+    for entry in entries {
+        let path = resolve(kernel_dir, entry).await?;
+        let expanded = expand(&path)?;
+        let single = expanded.len() == 1;
+        for patch in expanded {
+            let name = match single {
+                true => entry.display().to_string(),
+                false => patch_sources::label(&patch),
+            };
+            patches.push((name, patch));
+        }
+    }
+    Ok(patches)
+}
+
+/// Dry-runs the patches in order with the tool the tree's backend applies
+/// them with, without modifying the tree.
+pub async fn check(kernel_dir: &Path, patches: &[(String, PathBuf)]) -> Result<Vec<PatchCheck>> {
+    match TreeMetadata::load(kernel_dir)?.patch_backend.needs_git() {
+        true => preflight::check_patches_git(kernel_dir, patches).await,
+        false => preflight::check_patches(kernel_dir, patches).await,
+    }
+}
+
+/// Fails when a dry run found hunks that do not apply.
+pub fn ensure_applies(checks: &[PatchCheck]) -> Result<()> {
+    let failing = checks.iter().filter(|check| !check.applies()).count();
+    if failing > 0 {
+        anyhow::bail!("{} patches do not apply cleanly", failing);
+    }
+    Ok(())
+}
+
+/// Applies a patch with the tree's backend. A patch that fails part way is
+/// recorded as half-applied, so it can be resolved or reverted.
+pub async fn apply(kernel_dir: &Path, patch_file: &Path) -> Result<()> {
+    // patch runs inside the tree, so relative paths must be resolved first
+    let patch_file = fs::canonicalize(patch_file)
+        .with_context(|| format!("Patch {} not found", patch_file.display()))?;
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    if let Some(partial) = metadata.partial_patch {
+        anyhow::bail!(
+            "{} left the tree half-patched; undo it with `kcli patch revert` first",
+            partial.path.display()
+        );
+    }
+    let snapshot = snapshot::take(kernel_dir, &patch_file).await?;
+
+    if let Err(err) = patch_backend::apply(metadata.patch_backend, kernel_dir, &patch_file).await {
+        let partial = AppliedPatch::new(patch_file, snapshot)?;
+        TreeMetadata::update(kernel_dir, |metadata| {
+            metadata.partial_patch = Some(partial)
+        })?;
+        rejects::print_notice(kernel_dir)?;
+        return Err(err);
+    }
+
+    println!(
+        "Patch applied successfully: {}",
+        patch_file.to_string_lossy()
+    );
+    record(kernel_dir, AppliedPatch::new(patch_file, snapshot)?).await
+}
+
+/// Brings the tree to exactly `patches`, applied in order. Patches already
+/// applied as the same files in the same order are kept; a tree with any
+/// other patches applied is reset first.
+pub async fn apply_exact(kernel_dir: &Path, patches: &[(String, PathBuf)]) -> Result<()> {
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    let mut wanted = Vec::with_capacity(patches.len());
+    for (_, patch_file) in patches {
+        wanted.push(metadata::sha256(patch_file)?);
+    }
+    let applied: Vec<&str> = metadata
+        .patches
+        .iter()
+        .map(|patch| patch.sha256.as_str())
+        .collect();
+    let kept = if metadata.partial_patch.is_none()
+        && applied.len() <= wanted.len()
+        && applied
+            .iter()
+            .zip(&wanted)
+            .all(|(applied, wanted)| applied == wanted)
+    {
+        applied.len()
+    } else {
+        println!(
+            "The patches applied to {} differ from the ones requested; resetting it.",
+            kernel_dir.display()
+        );
+        reset(kernel_dir).await?;
+        0
+    };
+    if kept > 0 {
+        println!("{} of {} patches are already applied.", kept, patches.len());
+    }
+    for (_, patch_file) in &patches[kept..] {
+        apply(kernel_dir, patch_file).await?;
+    }
+    Ok(())
+}
+
+/// Commits an applied patch in git trees and adds it to the tree's applied
+/// patches, replacing the half-applied patch it finishes.
+async fn record(kernel_dir: &Path, applied: AppliedPatch) -> Result<()> {
+    snapshot::commit_patch(kernel_dir, &applied.path).await?;
+    // Remember which kernel-patches revision CachyOS patches came from
+    let revision = match applied.path.starts_with(kernel_patches::clone_path()?) {
+        true => kernel_patches::revision().await?,
+        false => None,
+    };
+    TreeMetadata::update(kernel_dir, |metadata| {
+        metadata.partial_patch = None;
+        metadata.patches.push(applied);
+        if revision.is_some() {
+            metadata.kernel_patches = revision;
+        }
+    })
+}
+
+/// Restores the tree to its state before `patch` was applied. Patches
+/// applied after it are reverted as well.
+pub async fn revert(kernel_dir: &Path, patch: &Path) -> Result<()> {
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    let matches = |applied: &AppliedPatch| applied.path == patch || applied.path.ends_with(patch);
+    if let Some(index) = metadata.patches.iter().position(matches) {
+        let later = metadata.patches.len() - index - 1;
+        if later > 0 {
+            println!("{} patches applied after it are reverted too.", later);
+        }
+        return snapshot::roll_back(kernel_dir, index).await;
+    }
+    if metadata.partial_patch.as_ref().is_some_and(matches) {
+        return snapshot::roll_back(kernel_dir, metadata.patches.len()).await;
+    }
+    anyhow::bail!(
+        "{} is not applied to {}",
+        patch.display(),
+        kernel_dir.display()
+    )
+}
+
+/// Restores the tree to its state before kcli applied any patch.
+pub async fn reset(kernel_dir: &Path) -> Result<()> {
+    snapshot::roll_back(kernel_dir, 0).await
+}
+
+/// Makes `backend` the tool the tree's patches are applied with.
+pub fn set_backend(kernel_dir: &Path, backend: PatchBackend) -> Result<()> {
+    if backend.needs_git() && !kernel_dir.join(".git").exists() {
+        anyhow::bail!(
+            "{} is not a git checkout; the {} backend needs one",
+            kernel_dir.display(),
+            backend
+        );
+    }
+    TreeMetadata::update(kernel_dir, |metadata| metadata.patch_backend = backend)
+}
+
+/// Resolves the conflicts a half-applied patch left, or every conflict in
+/// the tree when no patch failed. Once the files of a half-applied patch are
+/// free of conflicts, offers to record it as applied.
+pub async fn resolve_conflicts(theme: &ColorfulTheme, kernel_dir: &Path) -> Result<()> {
+    let partial = TreeMetadata::load(kernel_dir)?.partial_patch;
+    let files = match &partial {
+        Some(partial) => {
+            let contents = fs::read(&partial.path)
+                .with_context(|| format!("Failed to read {}", partial.path.display()))?;
+            let touched = preflight::touched_files(&String::from_utf8_lossy(&contents));
+            conflicts::find(kernel_dir, touched)?
+        }
+        None => conflicts::find_all(kernel_dir)?,
+    };
+    let left = conflicts::resolve_interactive(theme, kernel_dir, files).await?;
+    if left > 0 {
+        println!("{} conflicts are still unresolved.", left);
+        return Ok(());
+    }
+    let Some(partial) = partial else {
+        println!("No conflicts left in {}.", kernel_dir.display());
+        return Ok(());
+    };
+    let leftovers = rejects::by_patch(kernel_dir, rejects::find(kernel_dir)?)?;
+    let own = leftovers
+        .iter()
+        .find(|(patch, _)| patch.as_ref() == Some(&partial.path));
+    if let Some((_, leftovers)) = own {
+        println!("{} also left:", partial.path.display());
+        for leftover in leftovers.iter().filter(|leftover| leftover.is_reject()) {
+            println!(
+                "  {} with {} rejected hunks",
+                leftover.path.display(),
+                leftover.hunks.len()
+            );
+        }
+    }
+    // Hunks patch skipped leave no markers, so let the user decide
+    let done = Confirm::with_theme(theme)
+        .with_prompt(format!(
+            "No conflicts left in the files {} touches. Record it as applied?",
+            partial.path.display()
+        ))
+        .default(false)
+        .interact()?;
+    if done {
+        let path = partial.path.clone();
+        record(kernel_dir, partial).await?;
+        println!("{} is now applied.", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tokio::process::Command;
+
+use crate::git_source::git;
+use crate::snapshot;
+use crate::Error;
+
+/// The tool kcli applies patches with.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PatchBackend {
+    /// GNU patch with --merge, leaving conflict markers for hunks that fail
+    #[default]
+    Patch,
+    /// `git apply --3way`, merging with the blobs the patch was made against
+    GitApply,
+    /// `git am -3`, keeping the author, date and message of mail-style patches
+    GitAm,
+}
+
+impl PatchBackend {
+    pub const ALL: [PatchBackend; 3] = [
+        PatchBackend::Patch,
+        PatchBackend::GitApply,
+        PatchBackend::GitAm,
+    ];
+
+    pub fn needs_git(&self) -> bool {
+        *self != PatchBackend::Patch
+    }
+}
+
+impl fmt::Display for PatchBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PatchBackend::Patch => "patch",
+            PatchBackend::GitApply => "git-apply",
+            PatchBackend::GitAm => "git-am",
+        })
+    }
+}
+
+/// Author and message of a patch in `git format-patch` or mail form.
+#[derive(Debug)]
+pub struct MailHeader {
+    pub author: Option<String>,
+    pub date: Option<String>,
+    /// The subject without its `[PATCH v2 1/3]` prefix.
+    pub subject: String,
+    pub body: String,
+}
+
+impl MailHeader {
+    /// Reads the headers at the top of a patch, or `None` when it has no
+    /// `Subject:` header.
+    pub fn parse(patch: &str) -> Option<Self> {
+        let mut headers: Vec<String> = Vec::new();
+        let mut lines = patch.lines();
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            match headers.last_mut() {
+                // Folded header lines continue the previous one
+                Some(last) if line.starts_with([' ', '\t']) => {
+                    last.push(' ');
+                    last.push_str(line.trim());
+                }
+                _ => headers.push(line.to_string()),
+            }
+        }
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find_map(|line| line.strip_prefix(name))
+                .map(|value| value.trim().to_string())
+        };
+
+        let mut subject = header("Subject:")?;
+        while let Some((_, rest)) = subject
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+        {
+            subject = rest.trim_start().to_string();
+        }
+        let body: Vec<&str> = lines
+            .take_while(|line| *line != "---" && !line.starts_with("diff --git "))
+            .collect();
+        Some(MailHeader {
+            author: header("From:"),
+            date: header("Date:"),
+            subject,
+            body: body.join("\n").trim().to_string(),
+        })
+    }
+
+    pub fn message(&self) -> String {
+        if self.body.is_empty() {
+            self.subject.clone()
+        } else {
+            format!("{}\n\n{}", self.subject, self.body)
+        }
+    }
+}
+
+/// Reads the mail headers of a patch file, if it has any.
+pub fn mail_header(patch_file: &Path) -> Option<MailHeader> {
+    let contents = fs::read(patch_file).ok()?;
+    MailHeader::parse(&String::from_utf8_lossy(&contents))
+}
+
+/// Runs a command in the tree, printing its output when it fails.
+async fn run(kernel_dir: &Path, program: &str, args: &[&str], patch_file: &Path) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .arg(patch_file)
+        .current_dir(kernel_dir)
+        .output()
+        .await
+        .with_context(|| format!("Failed to execute {}", program))?;
+    if !output.status.success() {
+        // patch reports failed hunks on stdout, git on stderr
+        eprintln!(
+            "Failed to apply patch: {}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let command = format!("{} {} {}", program, args.join(" "), patch_file.display());
+        return Err(Error::CommandFailed(command).into());
+    }
+    Ok(())
+}
+
+/// Applies a patch to the tree with `backend`. Hunks that do not apply are
+/// left as conflict markers for `kcli patch conflicts`; with `git am` the
+/// patch is committed right away.
+pub async fn apply(backend: PatchBackend, kernel_dir: &Path, patch_file: &Path) -> Result<()> {
+    if backend.needs_git() && !kernel_dir.join(".git").exists() {
+        anyhow::bail!(
+            "{} is not a git checkout; the {} backend needs one",
+            kernel_dir.display(),
+            backend
+        );
+    }
+    match backend {
+        PatchBackend::Patch => {
+            run(kernel_dir, "patch", &["-Np1", "--merge", "-i"], patch_file).await
+        }
+        PatchBackend::GitApply => {
+            run(
+                kernel_dir,
+                "git",
+                &["apply", "--3way", "--whitespace=nowarn"],
+                patch_file,
+            )
+            .await
+        }
+        PatchBackend::GitAm if mail_header(patch_file).is_none() => {
+            println!(
+                "{} has no mail headers for git am; using git apply --3way.",
+                patch_file.display()
+            );
+            Box::pin(apply(PatchBackend::GitApply, kernel_dir, patch_file)).await
+        }
+        PatchBackend::GitAm => {
+            let identity = snapshot::identity_args(kernel_dir).await;
+            let mut args = identity.clone();
+            args.extend(["am", "-3", "--keep-cr", "--quiet"]);
+            let result = run(kernel_dir, "git", &args, patch_file).await;
+            if result.is_err() {
+                // Keep the conflicts in the tree, but end the am session so
+                // kcli can commit the patch once they are resolved
+                let mut quit = identity;
+                quit.extend(["am", "--quit"]);
+                git(kernel_dir, &quit).await?;
+            }
+            result
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use anyhow::{Context, Result};
+use dirs_next::config_dir;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use walkdir::WalkDir;
+
+use crate::git_source::git;
+use crate::{kernel_org, kernel_patches, patch, Error};
+
+/// Name of the built-in source, the CachyOS kernel-patches clone.
+pub const CACHYOS: &str = "cachyos";
+
+const SOURCES_FILE: &str = "patch-sources.toml";
+
+/// Where the patches of a source come from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    /// A git repository, cloned by kcli and fast-forwarded on sync.
+    Git(String),
+    /// A local directory, used in place.
+    Dir(PathBuf),
+    /// A single `.patch` or `.diff` file, or an mbox of patches such as
+    /// lore.kernel.org's `/raw` and `t.mbox.gz` links.
+    Url(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Git(url) => write!(f, "git {}", url),
+            Location::Dir(path) => write!(f, "dir {}", path.display()),
+            Location::Url(url) => write!(f, "url {}", url),
+        }
+    }
+}
+
+/// A user-configured place to take patches from next to CachyOS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchSource {
+    pub name: String,
+    #[serde(flatten)]
+    pub location: Location,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SourcesFile {
+    #[serde(default, rename = "source")]
+    sources: Vec<PatchSource>,
+}
+
+fn kcli_dir() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli"))
+}
+
+fn sources_path() -> Result<PathBuf> {
+    Ok(kcli_dir()?.join(SOURCES_FILE))
+}
+
+/// Directory holding the clone or downloads of a git or URL source.
+fn cache_dir(name: &str) -> Result<PathBuf> {
+    Ok(kcli_dir()?.join("patch-sources").join(name))
+}
+
+/// The configured sources from `~/.config/kcli/patch-sources.toml`, in the
+/// order they were added.
+pub fn load() -> Result<Vec<PatchSource>> {
+    let path = sources_path()?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let file: SourcesFile =
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(file.sources)
+}
+
+fn save(sources: &[PatchSource]) -> Result<()> {
+    let path = sources_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = toml::to_string(&SourcesFile {
+        sources: sources.to_vec(),
+    })?;
+    fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+impl PatchSource {
+    /// The directory the source's patches are browsed in.
+    pub fn root(&self) -> Result<PathBuf> {
+        match &self.location {
+            Location::Dir(path) => Ok(path.clone()),
+            Location::Git(_) | Location::Url(_) => cache_dir(&self.name),
+        }
+    }
+
+    /// Clones or downloads the source if that has not happened yet; with
+    /// `refresh` git sources are fast-forwarded and URLs downloaded again.
+    async fn sync(&self, refresh: bool) -> Result<()> {
+        let root = self.root()?;
+        match &self.location {
+            Location::Dir(path) => {
+                if !path.is_dir() {
+                    anyhow::bail!(
+                        "Patch source '{}': {} is not a directory",
+                        self.name,
+                        path.display()
+                    );
+                }
+            }
+            Location::Git(url) if !root.join(".git").exists() => {
+                println!("Cloning {} into {}", url, root.display());
+                let status = Command::new("git")
+                    .args(["clone", "--quiet", url])
+                    .arg(&root)
+                    .status()
+                    .await
+                    .context("Failed to execute git")?;
+                if !status.success() {
+                    return Err(Error::CommandFailed("git clone".to_string()).into());
+                }
+            }
+            Location::Git(url) if refresh => {
+                println!("Updating {} from {}", self.name, url);
+                git(&root, &["pull", "--ff-only", "--quiet"]).await?;
+            }
+            Location::Git(_) => {}
+            Location::Url(url) if refresh || !root.is_dir() => download(url, &root).await?,
+            Location::Url(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Downloads a patch or mbox into `dir`, splitting an mbox into one file per
+/// patch.
+async fn download(url: &str, dir: &Path) -> Result<()> {
+    let file_name = url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download");
+    let scratch = std::env::temp_dir().join(format!("kcli-{}-{}", std::process::id(), file_name));
+    kernel_org::download(url, &scratch).await?;
+    let contents = if file_name.ends_with(".gz") {
+        let output = Command::new("gzip")
+            .arg("-dc")
+            .arg(&scratch)
+            .output()
+            .await
+            .context("Failed to execute gzip")?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed(format!("gzip -dc {}", url)).into());
+        }
+        output.stdout
+    } else {
+        fs::read(&scratch)?
+    };
+    fs::remove_file(&scratch)?;
+    let contents = String::from_utf8_lossy(&contents);
+
+    let mut patches = if contents.starts_with("From ") {
+        split_mbox(&contents)
+    } else {
+        let name = file_name.trim_end_matches(".gz");
+        let name = if name.ends_with(".patch") || name.ends_with(".diff") {
+            name.to_string()
+        } else {
+            format!("{}.patch", name)
+        };
+        vec![(name, contents.into_owned())]
+    };
+    if patches.is_empty() {
+        anyhow::bail!("{} contains no patches", url);
+    }
+
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+    for (name, patch) in patches.drain(..) {
+        let path = dir.join(name);
+        fs::write(&path, patch).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Splits an mbox into `NNNN-subject.patch` files, numbered in mbox order.
+/// Messages without a diff, such as cover letters, are left out.
+fn split_mbox(mbox: &str) -> Vec<(String, String)> {
+    let mut messages: Vec<String> = Vec::new();
+    let mut previous_blank = true;
+    for line in mbox.split_inclusive('\n') {
+        if line.starts_with("From ") && previous_blank {
+            messages.push(String::new());
+        }
+        if let Some(message) = messages.last_mut() {
+            message.push_str(line);
+        }
+        previous_blank = line.trim_end().is_empty();
+    }
+    messages
+        .into_iter()
+        .filter(|message| {
+            message
+                .lines()
+                .any(|line| line.starts_with("diff --git ") || line.starts_with("--- "))
+        })
+        .enumerate()
+        .map(|(index, message)| {
+            let name = format!("{:04}-{}.patch", index + 1, subject_slug(&message));
+            (name, message)
+        })
+        .collect()
+}
+
+/// A file name friendly form of the message's subject without its
+/// `[PATCH v2 1/3]` prefix.
+fn subject_slug(message: &str) -> String {
+    let mut subject = String::new();
+    let mut lines = message
+        .lines()
+        .skip_while(|line| !line.starts_with("Subject:"));
+    if let Some(first) = lines.next() {
+        subject.push_str(first.trim_start_matches("Subject:"));
+        // Long subjects are folded onto indented lines
+        for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+            subject.push_str(line);
+        }
+    }
+    let mut subject = subject.trim();
+    while let Some(rest) = subject
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+    {
+        subject = rest.1.trim_start();
+    }
+    let slug: String = subject
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let slug = slug
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug: String = slug.chars().take(52).collect();
+    match slug.trim_end_matches('-') {
+        "" => "patch".to_string(),
+        slug => slug.to_string(),
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        anyhow::bail!(
+            "Invalid patch source name '{}': use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+    if name == CACHYOS {
+        anyhow::bail!("'{}' is the built-in kernel-patches source", CACHYOS);
+    }
+    Ok(())
+}
+
+/// Adds a source and fetches it right away, so a bad URL or path is noticed
+/// before it is saved.
+pub async fn add(name: &str, location: Location) -> Result<()> {
+    validate_name(name)?;
+    let mut sources = load()?;
+    if sources.iter().any(|source| source.name == name) {
+        anyhow::bail!("A patch source named '{}' already exists", name);
+    }
+    let location = match location {
+        Location::Dir(path) => Location::Dir(
+            fs::canonicalize(&path)
+                .with_context(|| format!("Directory {} not found", path.display()))?,
+        ),
+        location => location,
+    };
+    let source = PatchSource {
+        name: name.to_string(),
+        location,
+    };
+    source.sync(true).await?;
+    println!(
+        "Added patch source '{}' ({}).",
+        source.name, source.location
+    );
+    sources.push(source);
+    save(&sources)
+}
+
+/// Removes a source along with its clone or downloads. Local directories are
+/// left alone.
+pub fn remove(name: &str) -> Result<()> {
+    let mut sources = load()?;
+    let index = sources
+        .iter()
+        .position(|source| source.name == name)
+        .with_context(|| format!("There is no patch source named '{}'", name))?;
+    let source = sources.remove(index);
+    if !matches!(source.location, Location::Dir(_)) {
+        let root = source.root()?;
+        if root.exists() {
+            fs::remove_dir_all(&root)
+                .with_context(|| format!("Failed to remove {}", root.display()))?;
+        }
+    }
+    save(&sources)?;
+    println!("Removed patch source '{}'.", name);
+    Ok(())
+}
+
+/// Fast-forwards the git sources and downloads the URL sources again.
+pub async fn sync() -> Result<()> {
+    let sources = load()?;
+    if sources.is_empty() {
+        println!("No patch sources besides {} are configured.", CACHYOS);
+    }
+    for source in &sources {
+        source.sync(true).await?;
+    }
+    Ok(())
+}
+
+/// Every source with the directory its patches are in, the kernel-patches
+/// clone first. Sources are cloned or downloaded on first use.
+pub async fn roots() -> Result<Vec<(String, PathBuf)>> {
+    let mut roots = vec![(CACHYOS.to_string(), kernel_patches::ensure_clone().await?)];
+    for source in load()? {
+        source.sync(false).await?;
+        roots.push((source.name.clone(), source.root()?));
+    }
+    Ok(roots)
+}
+
+/// The source a patch belongs to and its path inside that source. Relative
+/// paths, as kept in series files, are inside the kernel-patches clone.
+pub fn locate(patch: &Path) -> Option<(String, PathBuf)> {
+    if patch.is_relative() {
+        return Some((CACHYOS.to_string(), patch.to_path_buf()));
+    }
+    let mut roots = vec![(CACHYOS.to_string(), kernel_patches::clone_path().ok()?)];
+    for source in load().ok()? {
+        roots.push((source.name.clone(), source.root().ok()?));
+    }
+    roots.into_iter().find_map(|(name, root)| {
+        patch
+            .strip_prefix(&root)
+            .ok()
+            .map(|relative| (name, relative.to_path_buf()))
+    })
+}
+
+/// `patch` prefixed with the name of its source, e.g. `[backports] fixes/usb.patch`.
+pub fn label(patch: &Path) -> String {
+    match locate(patch) {
+        Some((source, relative)) => format!("[{}] {}", source, relative.display()),
+        None => patch.display().to_string(),
+    }
+}
+
+/// Splits `source:path` into the source's directory and the path inside it.
+pub async fn split_entry(entry: &Path) -> Result<Option<(PathBuf, PathBuf)>> {
+    let Some((name, rest)) = entry.to_str().and_then(|entry| entry.split_once(':')) else {
+        return Ok(None);
+    };
+    if name == CACHYOS {
+        return Ok(Some((
+            kernel_patches::ensure_clone().await?,
+            PathBuf::from(rest),
+        )));
+    }
+    match load()?.into_iter().find(|source| source.name == name) {
+        Some(source) => {
+            source.sync(false).await?;
+            Ok(Some((source.root()?, PathBuf::from(rest))))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn is_patch_file(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|ext| ext == "patch" || ext == "diff")
+}
+
+/// One patch of the catalog.
+#[derive(Debug)]
+pub struct CatalogEntry {
+    pub source: String,
+    /// Path inside the source.
+    pub name: PathBuf,
+}
+
+/// All patches of all sources. With a tree, sources that keep one directory
+/// per kernel version only contribute the one matching the tree.
+pub async fn catalog(kernel_dir: Option<&Path>) -> Result<Vec<CatalogEntry>> {
+    let mut entries = Vec::new();
+    for (source, root) in roots().await? {
+        let dir = match kernel_dir {
+            Some(kernel_dir) => patch::version_dir(kernel_dir, &root).await,
+            None => root.clone(),
+        };
+        let mut patches: Vec<PathBuf> = WalkDir::new(&dir)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| is_patch_file(path))
+            .collect();
+        patches.sort();
+        for path in patches {
+            let name = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+            entries.push(CatalogEntry {
+                source: source.clone(),
+                name,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Prints the built-in source and the configured ones.
+pub fn print_sources() -> Result<()> {
+    let sources = load()?;
+    let width = sources
+        .iter()
+        .map(|source| source.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(CACHYOS.len());
+    let clone = kernel_patches::clone_path()?;
+    println!(
+        "{:<width$}  kernel-patches {}",
+        CACHYOS,
+        clone.display(),
+        width = width
+    );
+    for source in sources {
+        println!("{:<width$}  {}", source.name, source.location, width = width);
+    }
+    Ok(())
+}
@@ -1,21 +1,16 @@
 use anyhow::{Context, Result};
 use chrono::Utc;
-use colored::*;
-use dialoguer::{theme::ColorfulTheme, Input, Select};
+use dialoguer::{theme::ColorfulTheme, Select};
 use dirs_next::config_dir;
-use futures::TryStreamExt;
-use once_cell::sync::Lazy;
-use regex::Regex;
-use std::collections::HashMap;
-use std::io::Write;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tokio::fs::{self, File};
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tokio::process::Command as AsyncCommand;
 use walkdir::WalkDir; // Add this import
-use futures::stream::StreamExt; // Add missing import
+
+use crate::metadata::TreeMetadata;
 
 pub async fn menu_install_kernel(theme: &ColorfulTheme) -> Result<()> {
     let mut config_path = config_dir().unwrap();
@@ -23,10 +18,14 @@ pub async fn menu_install_kernel(theme: &ColorfulTheme) -> Result<()> {
     let packages_dir = config_path.join("ksrc");
 
     let packages = list_kernel_packages(&packages_dir).await?;
+    let mut items = Vec::with_capacity(packages.len());
+    for package in &packages {
+        items.push(crate::metadata::tree_label(&packages_dir.join(package)).await);
+    }
 
     let selection = Select::with_theme(theme)
         .with_prompt("Select a kernel package to install")
-        .items(&packages)
+        .items(&items)
         .default(0)
         .interact()?;
 
@@ -34,7 +33,13 @@ pub async fn menu_install_kernel(theme: &ColorfulTheme) -> Result<()> {
     let kernel_src_dir = packages_dir.join(selected_package);
     let pkg_dir = config_path.join("pkg");
 
-    installing_kernel(&kernel_src_dir, &pkg_dir, selected_package).await?;
+    installing_kernel(
+        &kernel_src_dir,
+        &pkg_dir,
+        selected_package,
+        &PackageInfo::default(),
+    )
+    .await?;
     println!("Kernel '{}' installed successfully.", selected_package);
 
     Ok(())
@@ -53,7 +58,8 @@ pub async fn list_kernel_packages(packages_dir: &Path) -> Result<Vec<String>> {
     {
         if let Ok(file_type) = entry.file_type().await {
             if file_type.is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
+                // Skip kcli's own scratch directories
+                if let Some(name) = entry.file_name().to_str().filter(|n| !n.starts_with('.')) {
                     packages.push(name.to_owned());
                 }
             }
@@ -63,44 +69,54 @@ pub async fn list_kernel_packages(packages_dir: &Path) -> Result<Vec<String>> {
     Ok(packages)
 }
 
+/// Apparent size of everything below `dir`, like `du -sb --apparent-size`.
+/// Symlinks count as links and are not followed, since `lib/modules/*/build`
+/// points back into the source tree and may dangle.
 async fn calculate_directory_size(dir: &Path) -> Result<u64, anyhow::Error> {
     let mut total_size = 0u64;
     let mut dir_entries = fs::read_dir(dir).await?;
     while let Some(entry) = dir_entries.next_entry().await? {
         let path = entry.path();
-        if path.is_dir() {
+        if entry.file_type().await?.is_dir() {
             total_size += Box::pin(calculate_directory_size(&path)).await?;
         } else {
-            let metadata = fs::metadata(path).await?;
+            let metadata = fs::symlink_metadata(path).await?;
             total_size += metadata.len();
         }
     }
     Ok(total_size)
 }
 
-struct PackageInfo {
-    pkgname: &'static str,
-    pkgver: &'static str,
-    pkgrel: &'static str,
-    pkgdesc: &'static str,
-    url: &'static str,
-    license: &'static str,
-    depends: Vec<&'static str>,
-    makedepends: Vec<&'static str>,
+/// Metadata written to the `.PKGINFO` of a packaged kernel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackageInfo {
+    pub pkgname: String,
+    pub pkgver: String,
+    pub pkgrel: String,
+    pub pkgdesc: String,
+    pub url: String,
+    pub license: String,
+    pub depends: Vec<String>,
+    pub makedepends: Vec<String>,
+}
+
+impl Default for PackageInfo {
+    fn default() -> Self {
+        Self {
+            pkgname: "capykernel".to_string(),
+            pkgver: "0.0.1".to_string(),
+            pkgrel: "1".to_string(),
+            pkgdesc: "Custom kernel package for capykernel".to_string(),
+            url: "https://cachyos.org".to_string(),
+            license: "GPL".to_string(),
+            depends: vec![],
+            makedepends: vec![],
+        }
+    }
 }
 
-static PACKAGE_INFO: Lazy<PackageInfo> = Lazy::new(|| PackageInfo {
-    pkgname: "capykernel",
-    pkgver: "0.0.1",
-    pkgrel: "1",
-    pkgdesc: "Custom kernel package for capykernel",
-    url: "https://cachyos.org",
-    license: "GPL",
-    depends: vec![],
-    makedepends: vec![],
-});
-
-async fn create_pkginfo_file(install_target: &Path) -> Result<()> {
+async fn create_pkginfo_file(install_target: &Path, package_info: &PackageInfo) -> Result<()> {
     // Determine system architecture
     let output = Command::new("uname").arg("-m").output().await?;
     let arch = String::from_utf8(output.stdout)?.trim().to_string();
@@ -109,17 +125,7 @@ async fn create_pkginfo_file(install_target: &Path) -> Result<()> {
     let builddate = Utc::now().timestamp();
 
     // Calculate the directory size
-    let output = Command::new("du")
-        .arg("-sb")
-        .arg("--apparent-size")
-        .arg(install_target.to_str().unwrap()) // Ensure the path is correctly converted to string
-        .output()
-        .await?;
-    let size = String::from_utf8(output.stdout)?
-        .split_whitespace()
-        .next()
-        .unwrap_or("0")
-        .to_string();
+    let size = calculate_directory_size(install_target).await?;
 
     // Prepare the content of the .PKGINFO file
     let pkginfo_content = format!(
@@ -132,21 +138,21 @@ async fn create_pkginfo_file(install_target: &Path) -> Result<()> {
         size = {}\n\
         arch = {}\n\
         {}",
-        PACKAGE_INFO.pkgname,
-        PACKAGE_INFO.pkgver,
-        PACKAGE_INFO.pkgrel,
-        PACKAGE_INFO.pkgdesc,
-        PACKAGE_INFO.url,
-        PACKAGE_INFO.license,
+        package_info.pkgname,
+        package_info.pkgver,
+        package_info.pkgrel,
+        package_info.pkgdesc,
+        package_info.url,
+        package_info.license,
         builddate,
         size,
         arch,
-        PACKAGE_INFO
+        package_info
             .depends
             .iter()
             .map(|d| format!("depend = {}\n", d))
             .collect::<String>()
-            + &PACKAGE_INFO
+            + &package_info
                 .makedepends
                 .iter()
                 .map(|md| format!("makedepend = {}\n", md))
@@ -175,7 +181,7 @@ async fn create_buildinfo_file(install_target: &Path) -> Result<()> {
     Ok(())
 }
 
-async fn create_mtree_file(install_target: &Path, kernel_name: &str) -> Result<()> {
+async fn create_mtree_file(install_target: &Path) -> Result<()> {
     // Ensure the directory where the .MTREE will be created exists
     let mtree_path = install_target.join(".MTREE");
     ensure_directory_exists(mtree_path.parent().unwrap()).await?;
@@ -217,7 +223,25 @@ pub async fn installing_kernel(
     kernel_src_dir: &Path,
     base_pkg_dir: &Path,
     kernel_name: &str,
+    package_info: &PackageInfo,
 ) -> Result<()> {
+    let result = build_package(kernel_src_dir, base_pkg_dir, kernel_name, package_info).await;
+    let (succeeded, artifacts) = match &result {
+        Ok(artifacts) => (true, artifacts.clone()),
+        Err(_) => (false, Vec::new()),
+    };
+    TreeMetadata::record_build(kernel_src_dir, "package", succeeded, artifacts)?;
+    result.map(|_| ())
+}
+
+/// Installs the built tree into `base_pkg_dir` and compresses it, returning
+/// the package directory and tarball.
+async fn build_package(
+    kernel_src_dir: &Path,
+    base_pkg_dir: &Path,
+    kernel_name: &str,
+    package_info: &PackageInfo,
+) -> Result<Vec<PathBuf>> {
     // Ensure the target directory for the installation is correct
     let install_target = base_pkg_dir.join(kernel_name);
     fs::create_dir_all(&install_target)
@@ -246,9 +270,9 @@ pub async fn installing_kernel(
     }
 
     // Metadata and packaging
-    create_pkginfo_file(&install_target).await?;
+    create_pkginfo_file(&install_target, package_info).await?;
     create_buildinfo_file(&install_target).await?;
-    create_mtree_file(&install_target, kernel_name).await?; // Adjusted to use install_target
+    create_mtree_file(&install_target).await?;
 
     println!("Kernel dir is {}", kernel_src_dir.display());
     // Copy kernel image
@@ -273,9 +297,11 @@ pub async fn installing_kernel(
         "Kernel package '{}' installed and compressed successfully.",
         kernel_name
     );
-    Ok(())
+    let tarball = std::env::current_dir()?.join(format!("{}.capy.tar.gz", kernel_name));
+    Ok(vec![install_target, tarball])
 }
 
+#[allow(dead_code)]
 pub async fn uninstalling_kernel(installed_kernels_dir: &Path, kernel_name: &str) -> Result<()> {
     let installed_kernel_dir = installed_kernels_dir.join(kernel_name);
     fs::remove_dir_all(&installed_kernel_dir)
@@ -285,6 +311,7 @@ pub async fn uninstalling_kernel(installed_kernels_dir: &Path, kernel_name: &str
     Ok(())
 }
 
+#[allow(dead_code)]
 pub async fn menu_uninstall_kernel(theme: &ColorfulTheme) -> Result<()> {
     let installed_kernels_dir = Path::new("./pkg/");
     let installed_packages = list_kernel_packages(installed_kernels_dir).await?;
@@ -311,10 +338,9 @@ pub async fn menu_uninstall_kernel(theme: &ColorfulTheme) -> Result<()> {
     Ok(())
 }
 
-async fn run_make_commands(kernel_src_dir: &Path, install_target: &PathBuf) -> Result<()> {
+async fn run_make_commands(kernel_src_dir: &Path, install_target: &Path) -> Result<()> {
     // Calculate the relative path for the install target for modules
-    let install_mod_path = install_target.clone();
-
+    let install_mod_path = install_target.to_path_buf();
 
     // get kernel name from kernel_src_dir
     //let kernel_name = kernel_src_dir.file_name().unwrap().to_str().unwrap();
@@ -340,65 +366,11 @@ async fn run_make_commands(kernel_src_dir: &Path, install_target: &PathBuf) -> R
         return Err(anyhow::anyhow!("`make modules_install` failed"));
     }    
 
-    // strip last dir from install_mod_path
-    // get the directory list in the path
-    let install_mod_path_installed = install_mod_path.clone();
-    let install_mod_path_installed = install_mod_path_installed.join("lib/modules");
-    println!("Install mod path: {}", install_mod_path_installed.to_str().unwrap());
-    
-    // get the directories inside install_mod_path
-    //let mut path_list = Vec::new();
-    let mut dir_stream = fs::read_dir(install_mod_path_installed).await?;
-
-    let mut last_dir = PathBuf::new();
-    while let Some(entry) = dir_stream.next_entry().await? {
-        last_dir = entry.path();
-    }
-
-    /*
-    // delete build directory
-    let build_dir = "{}/build".replace("{}", last_dir.to_str().unwrap());
-    println!("Removing build directory: {}", build_dir);
-    // do it in a try
-    let _ = fs::remove_dir_all(build_dir).await;
-
-    // Construct headers install path (mod_path/build)
-    let install_hdr_path = install_mod_path.join("usr");
-
-    println!("Installing modules to: {}", install_mod_path.display());
-    println!("Installing headers to: {}", install_hdr_path.display());
-
-    // Running headers_install with INSTALL_HDR_PATH
-    
-    println!("Executing `make headers_install` with INSTALL_HDR_PATH...");
-    let status_headers_install = Command::new("make")
-        .arg("headers_install")
-        .arg(format!(
-            "INSTALL_HDR_PATH={}/build",
-            last_dir.display()
-        ))
-        .current_dir(kernel_src_dir)
-        .stdout(Stdio::inherit()) // Inherit stdout to see command output
-        .stderr(Stdio::inherit()) // Inherit stderr to see any errors
-        .status()
-        .await
-        .context("`make headers_install` command failed")?;
-
-    if !status_headers_install.success() {
-        return Err(anyhow::anyhow!("`make headers_install` failed"));
-    }
-     */
-    
-
     Ok(())
 }
-async fn compress_kernel_package(pkg_dir: &Path, kernel_name: &str) -> Result<()> {
-    // Construct the tarball path
 
-    let mut config_path = config_dir().unwrap();
-    config_path.push("kcli");
-    let packages_dir = config_path.join("pkg");
-    let kernel_path = packages_dir.join(kernel_name);
+async fn compress_kernel_package(kernel_path: &Path, kernel_name: &str) -> Result<()> {
+    // Construct the tarball path
     let current_dir = std::env::current_dir().unwrap();
 
     // Construct the bsdtar command to respect the order of files
@@ -431,285 +403,3 @@ async fn compress_kernel_package(pkg_dir: &Path, kernel_name: &str) -> Result<()
     //println!("Package compressed to: {}", tarball_path.display());
     Ok(())
 }
-
-pub async fn apply_patches_and_handle_conflicts(theme: &ColorfulTheme) -> Result<()> {
-    let kernels_dir = Path::new("./kernels/");
-    let kernel_versions = list_kernel_packages(kernels_dir).await?;
-
-    let mut kernel_versions_items = Vec::new();
-    for kernel_version in &kernel_versions {
-        kernel_versions_items.push(kernel_version.as_str());
-    }
-
-    let selection = Select::with_theme(theme)
-        .with_prompt("Select a kernel to patch")
-        .items(&kernel_versions_items)
-        .default(0)
-        .interact()?;
-
-    let selected_kernel_version = &kernel_versions[selection];
-    let kernel_src_dir = kernels_dir.join(selected_kernel_version);
-
-    let patches_dir = Path::new("./patches");
-    let kernel_patch_dir = patches_dir.join(selected_kernel_version);
-
-    let mut patch_files = Vec::new();
-    for patch in WalkDir::new(&kernel_patch_dir) {
-        let patch = patch?;
-        if patch.path().is_file() {
-            let path = patch.path();
-            if let Some(extension) = path.extension() {
-                if extension == "patch" {
-                    patch_files.push(path.to_path_buf());
-                }
-            }
-        }
-    }
-
-    for patch in patch_files {
-        //let patch_file_path = patch.display().to_string();
-
-        // change the patch file path to a relative path with ../../
-        let relative_patch_file_path = PathBuf::from("../../")
-            .join(patch.strip_prefix("./")?)
-            .clone();
-        let status = AsyncCommand::new("patch")
-            .arg("-Np1")
-            .arg("--merge")
-            .arg("-i")
-            .arg(&relative_patch_file_path)
-            .arg("-d")
-            .arg(&kernel_src_dir)
-            .status()
-            .await
-            .context(format!("Failed to apply patch {:?}", patch.display()))?;
-
-        if !status.success() {
-            println!("Failed to apply patch. Searching for merge conflicts...");
-
-            let conflicts = search_merge_conflicts(&kernel_src_dir).await?;
-            if conflicts.is_empty() {
-                println!("No merge conflicts found.");
-            } else {
-                handle_conflicts(conflicts.clone()).await?;
-            }
-
-            return Ok(());
-        }
-    }
-
-    Ok(())
-}
-
-async fn search_merge_conflicts(
-    kernel_src_dir: &Path,
-) -> Result<HashMap<String, Vec<(usize, usize)>>> {
-    let mut conflicts = HashMap::new();
-    let rg_command = Command::new("rg")
-        .arg("-n")
-        .arg("--no-messages")
-        .arg(r"^<<<<<<<|^=======$|^>>>>>>>")
-        .arg(kernel_src_dir)
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Failed to start ripgrep")?;
-
-    let rg_output = rg_command
-        .wait_with_output()
-        .await
-        .context("Failed to wait on ripgrep")?;
-    if !rg_output.status.success() {
-        return Err(anyhow::anyhow!("Ripgrep search failed"));
-    }
-
-    let output_str =
-        String::from_utf8(rg_output.stdout).context("Failed to parse ripgrep output")?;
-    let line_regex = Regex::new(r"^(.*?):(\d+):.*$").unwrap();
-
-    let mut current_file = String::new();
-    let mut current_conflict: Option<(usize, usize)> = None;
-
-    for line in output_str.lines() {
-        if let Some(caps) = line_regex.captures(line) {
-            let file = caps.get(1).unwrap().as_str().to_string();
-            let line_num: usize = caps.get(2).unwrap().as_str().parse().unwrap();
-
-            if current_file != file {
-                if let Some(conflict) = current_conflict.take() {
-                    conflicts
-                        .entry(current_file.clone())
-                        .or_insert_with(Vec::new)
-                        .push(conflict);
-                }
-                current_file = file.clone();
-            }
-
-            match line.chars().last().unwrap() {
-                '<' => {
-                    if let Some(conflict) = current_conflict.take() {
-                        conflicts
-                            .entry(current_file.clone())
-                            .or_insert_with(Vec::new)
-                            .push(conflict);
-                    }
-                    current_conflict = Some((line_num, 0)); // Start of a new conflict
-                }
-                '>' => {
-                    if let Some(ref mut conflict) = current_conflict {
-                        conflict.1 = line_num; // End of the current conflict
-                    }
-                }
-                '=' => {} // Ignore '=======' line for now
-                _ => {}
-            }
-        }
-    }
-
-    // Catch any trailing conflict not followed by a new file or EOF
-    if let Some(conflict) = current_conflict {
-        conflicts
-            .entry(current_file)
-            .or_insert_with(Vec::new)
-            .push(conflict);
-    }
-
-    Ok(conflicts)
-}
-
-async fn handle_conflicts(conflicts: HashMap<String, Vec<(usize, usize)>>) -> Result<()> {
-    let theme = ColorfulTheme::default();
-    for (file_path_str, ranges) in conflicts {
-        println!("Found conflict in file: {}", file_path_str);
-
-        let file_path = PathBuf::from(&file_path_str);
-        let file = File::open(&file_path).await?;
-        let reader = BufReader::new(file);
-
-        let mut lines = reader.lines();
-        let mut line_number = 0;
-        let mut buffer = Vec::new();
-
-        while let Some(line) = lines.next_line().await? {
-            line_number += 1;
-            buffer.push((line_number, line));
-        }
-
-        println!("Displaying conflicted lines for file: {}", file_path_str);
-        for (start, end) in ranges {
-            // Display conflicted lines
-            println!("Conflict between lines {} and {}:", start, end);
-
-            Command::new("bat")
-                .args(&[
-                    "--color=always",
-                    "--line-range",
-                    &format!("{}:{}", start, end),
-                    &file_path_str,
-                ])
-                .status()
-                .await?;
-
-            let selections = [
-                "Accept Incoming",
-                "Keep Current",
-                "Accept Both",
-                "Open in Editor",
-            ];
-            let selection = Select::with_theme(&theme)
-                .with_prompt("Choose an option for resolving conflict")
-                .default(0)
-                .items(&selections[..])
-                .interact()?;
-
-            match selections[selection] {
-                "Accept Incoming" => accept_incoming(&file_path_str, start, end).await?,
-                "Keep Current" => keep_current(&file_path_str, start, end).await?,
-                "Accept Both" => accept_both(&file_path_str, start, end).await?,
-                "Open in Editor" => {
-                    // Launch editor
-                    // remove ./ before passing to nano
-
-                    let clean_file_path = file_path_str.strip_prefix("./").unwrap();
-                    if let Err(e) = AsyncCommand::new("nano")
-                        .arg(format!(" {}", clean_file_path)) // Nano uses +line,column syntax
-                        .status()
-                        .await
-                    {
-                        eprintln!("Failed to open editor: {}", e);
-                    }
-                }
-                _ => {}
-            }
-        }
-    }
-    Ok(())
-}
-
-async fn apply_changes_to_file<F: FnOnce(&[String]) -> Vec<String>>(
-    file_path: &str,
-    start: usize,
-    end: usize,
-    modify_lines: F,
-) -> Result<()> {
-    let file = fs::read_to_string(file_path).await?;
-    let lines: Vec<String> = file.lines().map(String::from).collect();
-
-    let before = lines[..start - 1].to_vec();
-    let conflict = lines[start - 1..end].to_vec();
-    let after = lines[end..].to_vec();
-
-    let modified_conflict = modify_lines(&conflict);
-
-    let modified_lines = [before, modified_conflict, after].concat();
-    fs::write(file_path, modified_lines.join("\n")).await?;
-
-    Ok(())
-}
-
-async fn accept_incoming(file_path: &str, start: usize, end: usize) -> Result<()> {
-    apply_changes_to_file(file_path, start, end, |conflict_lines| {
-        conflict_lines
-            .iter()
-            .skip_while(|line| !line.starts_with("======="))
-            .skip(1) // Skip the ======= line itself
-            .take_while(|line| !line.starts_with(">>>>>>>"))
-            .cloned()
-            .collect()
-    })
-    .await?;
-
-    println!("Accepted incoming changes for {}", file_path);
-    Ok(())
-}
-
-async fn keep_current(file_path: &str, start: usize, end: usize) -> Result<()> {
-    apply_changes_to_file(file_path, start, end, |conflict_lines| {
-        conflict_lines
-            .iter()
-            .take_while(|line| !line.starts_with("======="))
-            .cloned()
-            .collect()
-    })
-    .await?;
-
-    println!("Kept current changes for {}", file_path);
-    Ok(())
-}
-
-async fn accept_both(file_path: &str, start: usize, end: usize) -> Result<()> {
-    apply_changes_to_file(file_path, start, end, |conflict_lines| {
-        conflict_lines
-            .iter()
-            .filter(|line| {
-                !line.starts_with("<<<<<<<")
-                    && !line.starts_with("=======")
-                    && !line.starts_with(">>>>>>>")
-            })
-            .cloned()
-            .collect()
-    })
-    .await?;
-
-    println!("Merged both changes for {}", file_path);
-    Ok(())
-}
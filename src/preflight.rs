@@ -0,0 +1,414 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::git_source::git;
+
+/// How `patch` placed the hunks of one patch.
+#[derive(Debug, Default)]
+pub struct PatchCheck {
+    pub name: String,
+    pub hunks: usize,
+    /// Hunks that applied at a different line than the patch says.
+    pub offset: usize,
+    /// Hunks that only applied after ignoring context lines.
+    pub fuzz: usize,
+    /// One line per hunk that did not apply, e.g. `mm/slub.c: hunk #2 FAILED at 310`,
+    /// or per file when checked with git, e.g. `mm/slub.c: conflicts`.
+    pub failed: Vec<String>,
+}
+
+impl PatchCheck {
+    pub fn clean(&self) -> usize {
+        let failed = self.failed.len().min(self.hunks);
+        self.hunks.saturating_sub(self.offset + self.fuzz + failed)
+    }
+
+    pub fn applies(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Files a `-p1` patch reads or writes, relative to the tree. Only the
+/// headers between hunks are read, so removed lines starting with `-- ` are
+/// not taken for file names.
+pub fn touched_files(patch: &str) -> BTreeSet<PathBuf> {
+    let mut files = BTreeSet::new();
+    // Lines of the current hunk still to come, on the old and the new side
+    let (mut old_left, mut new_left): (usize, usize) = (0, 0);
+    for line in patch.lines() {
+        if old_left > 0 || new_left > 0 {
+            match line.chars().next() {
+                Some('-') => old_left = old_left.saturating_sub(1),
+                Some('+') => new_left = new_left.saturating_sub(1),
+                Some('\\') => {}
+                _ => {
+                    old_left = old_left.saturating_sub(1);
+                    new_left = new_left.saturating_sub(1);
+                }
+            }
+            continue;
+        }
+        if let Some((old, new)) = hunk_lengths(line) {
+            (old_left, new_left) = (old, new);
+            continue;
+        }
+        let name = line
+            .strip_prefix("--- ")
+            .or_else(|| line.strip_prefix("+++ "))
+            .and_then(|name| name.split('\t').next())
+            .filter(|name| *name != "/dev/null")
+            .and_then(|name| name.split_once('/').map(|(_, path)| path))
+            // git names both sides of a rename without the a/ and b/ prefixes
+            .or_else(|| line.strip_prefix("rename from "))
+            .or_else(|| line.strip_prefix("rename to "));
+        if let Some(name) = name {
+            files.insert(PathBuf::from(name.trim()));
+        }
+    }
+    files
+}
+
+/// How many lines of the old and the new file a hunk header such as
+/// `@@ -10,6 +10,7 @@` covers.
+fn hunk_lengths(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ -")?.split(' ');
+    let length = |range: &str| match range.split_once(',') {
+        Some((_, length)) => length.parse().ok(),
+        None => Some(1),
+    };
+    let old = length(ranges.next()?)?;
+    let new = length(ranges.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+fn count_hunks(patch: &str) -> usize {
+    patch
+        .lines()
+        .filter(|line| line.starts_with("@@ -"))
+        .count()
+}
+
+/// Reads `patch`'s report for one patch file.
+fn parse_report(check: &mut PatchCheck, report: &str) -> Result<()> {
+    let file_re = Regex::new(r"^(?:patching|checking) file '?([^']+?)'?$")?;
+    let hunk_re = Regex::new(
+        r"^Hunk #(\d+) (succeeded|FAILED) at (\d+)(?:-\d+)?(?: with fuzz (\d+))?(?: \(offset (-?\d+) lines?\))?",
+    )?;
+    let ignored_re = Regex::new(r"^(\d+) out of \d+ hunks? ignored")?;
+
+    let mut file = String::new();
+    let mut reason = "FAILED";
+    for line in report.lines() {
+        if let Some(caps) = file_re.captures(line) {
+            file = caps[1].to_string();
+            reason = "FAILED";
+        } else if line.starts_with("Reversed (or previously applied) patch detected") {
+            reason = "already applied";
+        } else if line.starts_with("can't find file to patch") {
+            file.clear();
+            reason = "file missing";
+        } else if let Some(name) = line.strip_prefix("|+++ ").filter(|_| file.is_empty()) {
+            // The header of a patch for a missing file is quoted with `|`
+            let name = name.split('\t').next().unwrap_or(name);
+            file = name
+                .split_once('/')
+                .map_or(name, |(_, path)| path)
+                .to_string();
+        } else if let Some(caps) = hunk_re.captures(line) {
+            if &caps[2] == "FAILED" {
+                check.failed.push(format!(
+                    "{}: hunk #{} FAILED at {}",
+                    file, &caps[1], &caps[3]
+                ));
+            } else if caps.get(4).is_some() {
+                check.fuzz += 1;
+            } else if caps.get(5).is_some() {
+                check.offset += 1;
+            }
+        } else if let Some(caps) = ignored_re.captures(line) {
+            let name = if file.is_empty() {
+                "(unknown file)"
+            } else {
+                &file
+            };
+            for _ in 0..caps[1].parse::<usize>().unwrap_or(1) {
+                check
+                    .failed
+                    .push(format!("{}: hunk ignored, {}", name, reason));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks how each patch would apply on top of the ones before it, without
+/// modifying the tree: the files the patches touch are copied to a scratch
+/// directory and the series is applied there.
+pub async fn check_patches(
+    kernel_dir: &Path,
+    patches: &[(String, PathBuf)],
+) -> Result<Vec<PatchCheck>> {
+    let mut contents = Vec::with_capacity(patches.len());
+    let mut files = BTreeSet::new();
+    for (_, path) in patches {
+        let patch = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let patch = String::from_utf8_lossy(&patch).into_owned();
+        files.extend(touched_files(&patch));
+        contents.push(patch);
+    }
+
+    // Removed when dropped, also when a step below fails
+    let scratch = tempfile::Builder::new()
+        .prefix("kcli-preflight-")
+        .tempdir()
+        .context("Failed to create a scratch directory")?;
+    for file in &files {
+        let source = kernel_dir.join(file);
+        if source.is_file() {
+            let target = scratch.path().join(file);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&source, &target)?;
+        }
+    }
+
+    let mut checks = Vec::with_capacity(patches.len());
+    for ((name, path), patch) in patches.iter().zip(&contents) {
+        let output = Command::new("patch")
+            .args([
+                "-p1",
+                "-N",
+                "--batch",
+                "--no-backup-if-mismatch",
+                "-r",
+                "-",
+                "-i",
+            ])
+            .arg(fs::canonicalize(path)?)
+            .current_dir(scratch.path())
+            .output()
+            .await
+            .context("Failed to execute patch")?;
+        let mut check = PatchCheck {
+            name: name.clone(),
+            hunks: count_hunks(patch),
+            ..PatchCheck::default()
+        };
+        parse_report(&mut check, &String::from_utf8_lossy(&output.stdout))?;
+        if !output.status.success() && check.failed.is_empty() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            check.failed.push(stderr.trim().to_string());
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+/// Checks how each patch would apply with `git apply --3way`, as the git
+/// backends apply them, on top of the ones before it. The patches go into a
+/// scratch copy of the tree's index, so neither the index nor the files
+/// change; git reports no offsets or fuzz.
+pub async fn check_patches_git(
+    kernel_dir: &Path,
+    patches: &[(String, PathBuf)],
+) -> Result<Vec<PatchCheck>> {
+    let index = kernel_dir.join(git(kernel_dir, &["rev-parse", "--git-path", "index"]).await?);
+    // Removed when dropped, also when a step below fails
+    let scratch = tempfile::Builder::new()
+        .prefix("kcli-preflight-")
+        .suffix(".index")
+        .tempfile()
+        .context("Failed to create a scratch index")?;
+    fs::copy(&index, scratch.path())
+        .with_context(|| format!("Failed to copy the git index of {}", kernel_dir.display()))?;
+
+    let mut checks = Vec::with_capacity(patches.len());
+    for (name, path) in patches {
+        let patch = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let output = Command::new("git")
+            .args(["apply", "--cached", "--3way", "--whitespace=nowarn"])
+            .arg(fs::canonicalize(path)?)
+            .env("GIT_INDEX_FILE", scratch.path())
+            .current_dir(kernel_dir)
+            .output()
+            .await
+            .context("Failed to execute git")?;
+        let mut check = PatchCheck {
+            name: name.clone(),
+            hunks: count_hunks(&String::from_utf8_lossy(&patch)),
+            ..PatchCheck::default()
+        };
+        if !output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // git lists conflicted files as `U path` and failures as
+            // `error: path: reason`, on either stream
+            for line in stdout.lines().chain(stderr.lines()) {
+                if let Some(file) = line.strip_prefix("U ") {
+                    check.failed.push(format!("{}: conflicts", file));
+                }
+                let failure = line
+                    .strip_prefix("error: ")
+                    .filter(|message| !message.starts_with("patch failed: "))
+                    .and_then(|message| message.split_once(": "));
+                if let Some((file, reason)) = failure {
+                    check.failed.push(format!("{}: {}", file, reason));
+                }
+            }
+            if check.failed.is_empty() {
+                check.failed.push(stderr.trim().to_string());
+            }
+        }
+        checks.push(check);
+    }
+    Ok(checks)
+}
+
+/// Prints one row per patch with its hunk counts, then the failing hunks.
+pub fn print_table(checks: &[PatchCheck]) {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Patch".len());
+    println!(
+        "{:<width$}  {:>5}  {:>5}  {:>6}  {:>4}  {:>6}",
+        "Patch",
+        "Hunks",
+        "Clean",
+        "Offset",
+        "Fuzz",
+        "Failed",
+        width = width
+    );
+    for check in checks {
+        println!(
+            "{:<width$}  {:>5}  {:>5}  {:>6}  {:>4}  {:>6}",
+            check.name,
+            check.hunks,
+            check.clean(),
+            check.offset,
+            check.fuzz,
+            check.failed.len(),
+            width = width
+        );
+    }
+    for check in checks.iter().filter(|check| !check.applies()) {
+        println!();
+        println!("{}:", check.name);
+        for failure in &check.failed {
+            println!("  {}", failure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(patch: &str) -> Vec<String> {
+        touched_files(patch)
+            .into_iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn removed_dash_lines_are_not_headers() {
+        let patch =
+            "--- a/foo.c\n+++ b/foo.c\n@@ -1,3 +1,3 @@\n a\n--- a/evil.c\n+++ b/evil.c\n b\n";
+        assert_eq!(files(patch), ["foo.c"]);
+    }
+
+    #[test]
+    fn git_renames_touch_both_names() {
+        let patch = "diff --git a/old.c b/new.c\n\
+                     similarity index 90%\n\
+                     rename from old.c\n\
+                     rename to new.c\n\
+                     --- a/old.c\n\
+                     +++ b/new.c\n\
+                     @@ -1 +1 @@\n\
+                     -x\n\
+                     +y\n\
+                     diff --git a/moved.c b/dir/moved.c\n\
+                     similarity index 100%\n\
+                     rename from moved.c\n\
+                     rename to dir/moved.c\n";
+        assert_eq!(files(patch), ["dir/moved.c", "moved.c", "new.c", "old.c"]);
+    }
+
+    #[test]
+    fn dev_null_sides_are_skipped() {
+        let patch = "--- /dev/null\n+++ b/added.c\n@@ -0,0 +1,2 @@\n+1\n+--- a/nope.c\n\
+                     --- a/gone.c\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n";
+        assert_eq!(files(patch), ["added.c", "gone.c"]);
+    }
+
+    #[test]
+    fn single_line_hunks() {
+        assert_eq!(hunk_lengths("@@ -1 +1 @@"), Some((1, 1)));
+        assert_eq!(hunk_lengths("@@ -0,0 +1 @@"), Some((0, 1)));
+        assert_eq!(
+            hunk_lengths("@@ -10,6 +10,7 @@ static int f(void)"),
+            Some((6, 7))
+        );
+        assert_eq!(hunk_lengths("--- a/foo.c"), None);
+
+        let patch = "--- a/a.c\n+++ b/a.c\n@@ -1 +1 @@\n--- a/x.c\n+++ b/y.c\n\
+                     --- a/b.c\n+++ b/b.c\n@@ -1 +1 @@\n-a\n+b\n";
+        assert_eq!(files(patch), ["a.c", "b.c"]);
+    }
+
+    #[test]
+    fn report_with_offset_fuzz_and_failures() {
+        let mut check = PatchCheck {
+            hunks: 5,
+            ..PatchCheck::default()
+        };
+        let report = "checking file mm/slub.c\n\
+                      Hunk #1 succeeded at 7 (offset -3 lines).\n\
+                      Hunk #2 succeeded at 17 with fuzz 1.\n\
+                      Hunk #3 succeeded at 40 with fuzz 2 (offset 3 lines).\n\
+                      Hunk #4 FAILED at 310.\n\
+                      1 out of 5 hunks FAILED\n";
+        parse_report(&mut check, report).unwrap();
+        assert_eq!(check.offset, 1);
+        assert_eq!(check.fuzz, 2);
+        assert_eq!(check.failed, ["mm/slub.c: hunk #4 FAILED at 310"]);
+        assert_eq!(check.clean(), 1);
+        assert!(!check.applies());
+    }
+
+    #[test]
+    fn report_with_ignored_hunks() {
+        let mut check = PatchCheck::default();
+        let report = "checking file a.c\n\
+                      Reversed (or previously applied) patch detected!  Skipping patch.\n\
+                      2 out of 2 hunks ignored\n\
+                      can't find file to patch at input line 3\n\
+                      Perhaps you used the wrong -p or --strip option?\n\
+                      The text leading up to this was:\n\
+                      --------------------------\n\
+                      |--- a/missing.c\n\
+                      |+++ b/missing.c\n\
+                      --------------------------\n\
+                      No file to patch.  Skipping patch.\n\
+                      1 out of 1 hunk ignored\n";
+        parse_report(&mut check, report).unwrap();
+        assert_eq!(
+            check.failed,
+            [
+                "a.c: hunk ignored, already applied",
+                "a.c: hunk ignored, already applied",
+                "missing.c: hunk ignored, file missing",
+            ]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use dirs_next::config_dir;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::kernel_config::KernelConfig;
+
+/// Name that refers to the unnamed configuration in `kernel_config.json`.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Where a kernel configuration is persisted: the unnamed default file or a
+/// named profile under `~/.config/kcli/profiles`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    Profile(String),
+}
+
+impl ConfigSource {
+    /// Parses a profile name given by the user; `default` selects the
+    /// unnamed configuration.
+    pub fn parse(name: &str) -> Result<Self> {
+        let name = name.trim();
+        if name == DEFAULT_PROFILE {
+            return Ok(ConfigSource::Default);
+        }
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            anyhow::bail!(
+                "Invalid profile name '{}': use letters, digits, '-', '_' and '.'",
+                name
+            );
+        }
+        Ok(ConfigSource::Profile(name.to_string()))
+    }
+
+    pub fn path(&self) -> Result<PathBuf> {
+        match self {
+            ConfigSource::Default => Ok(kcli_dir()?.join("kernel_config.json")),
+            ConfigSource::Profile(name) => Ok(profiles_dir()?.join(format!("{}.json", name))),
+        }
+    }
+
+    pub fn exists(&self) -> Result<bool> {
+        Ok(self.path()?.is_file())
+    }
+
+    pub fn load(&self) -> Result<KernelConfig> {
+        if let ConfigSource::Profile(name) = self {
+            if !self.exists()? {
+                anyhow::bail!("Profile '{}' does not exist", name);
+            }
+        }
+        KernelConfig::load_or_default(&self.path()?)
+    }
+
+    pub fn save(&self, config: &KernelConfig) -> Result<()> {
+        config.save_to_file(&self.path()?)
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str(DEFAULT_PROFILE),
+            ConfigSource::Profile(name) => f.write_str(name),
+        }
+    }
+}
+
+fn kcli_dir() -> Result<PathBuf> {
+    Ok(config_dir()
+        .context("Failed to locate the config directory")?
+        .join("kcli"))
+}
+
+fn profiles_dir() -> Result<PathBuf> {
+    Ok(kcli_dir()?.join("profiles"))
+}
+
+fn selection_path() -> Result<PathBuf> {
+    Ok(profiles_dir()?.join(".selected"))
+}
+
+/// Names of all saved profiles, sorted.
+pub fn list() -> Result<Vec<String>> {
+    let dir = profiles_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = fs::read_dir(&dir)
+        .context("Failed to read profiles directory")?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            path.file_stem()?.to_str().map(str::to_string)
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// The profile selected with `select`, or the default configuration.
+pub fn selected() -> Result<ConfigSource> {
+    match fs::read_to_string(selection_path()?) {
+        Ok(name) => {
+            let source = ConfigSource::parse(&name)?;
+            Ok(if source.exists()? { source } else { ConfigSource::Default })
+        }
+        Err(_) => Ok(ConfigSource::Default),
+    }
+}
+
+pub fn select(source: &ConfigSource) -> Result<()> {
+    if let ConfigSource::Profile(name) = source {
+        if !source.exists()? {
+            anyhow::bail!("Profile '{}' does not exist", name);
+        }
+    }
+    let path = selection_path()?;
+    fs::create_dir_all(profiles_dir()?)?;
+    fs::write(&path, source.to_string()).context("Failed to save the selected profile")?;
+    Ok(())
+}
+
+pub fn create(source: &ConfigSource, config: &KernelConfig) -> Result<()> {
+    if source.exists()? {
+        anyhow::bail!("Profile '{}' already exists", source);
+    }
+    source.save(config)
+}
+
+pub fn clone(from: &ConfigSource, to: &ConfigSource) -> Result<()> {
+    let config = from.load()?;
+    create(to, &config)
+}
+
+pub fn delete(source: &ConfigSource) -> Result<()> {
+    let ConfigSource::Profile(name) = source else {
+        anyhow::bail!("The default configuration cannot be deleted");
+    };
+    let was_selected = selected()? == *source;
+    fs::remove_file(source.path()?)
+        .with_context(|| format!("Failed to delete profile '{}'", name))?;
+    if was_selected {
+        select(&ConfigSource::Default)?;
+    }
+    Ok(())
+}
+
+/// Prints the options that differ between two profiles.
+pub fn print_diff(a: &ConfigSource, b: &ConfigSource) -> Result<()> {
+    let differences = a.load()?.diff(&b.load()?);
+    if differences.is_empty() {
+        println!("Profiles '{}' and '{}' are identical.", a, b);
+        return Ok(());
+    }
+    let width = differences
+        .iter()
+        .map(|(option, _, _)| option.len())
+        .max()
+        .unwrap_or(0);
+    println!("{:width$}  {}  ->  {}", "option", a, b, width = width);
+    for (option, ours, theirs) in differences {
+        println!("{:width$}  {}  ->  {}", option, ours, theirs, width = width);
+    }
+    Ok(())
+}
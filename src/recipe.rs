@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::kernel_config::{KernelConfig, Toggle};
+use crate::pkg_manager::PackageInfo;
+use crate::KernelChannel;
+
+/// A declarative description of a complete kernel build, usually checked in
+/// as `kcli.toml` and run with `kcli build --recipe kcli.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recipe {
+    pub source: SourceSettings,
+    pub config: KernelConfig,
+    pub patches: Vec<PathBuf>,
+    pub kconfig: KconfigToggles,
+    pub build: BuildSettings,
+    pub package: PackageInfo,
+}
+
+/// Which kernel tree the recipe builds.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceSettings {
+    pub channel: KernelChannel,
+    /// Git tag or branch to check out instead of the channel head, e.g. `v6.10.3`.
+    pub tag: Option<String>,
+    /// Name of the tree under the kcli source directory. Defaults to the
+    /// channel's directory name.
+    pub tree: Option<String>,
+}
+
+impl Default for SourceSettings {
+    fn default() -> Self {
+        Self {
+            channel: KernelChannel::Stable,
+            tag: None,
+            tree: None,
+        }
+    }
+}
+
+impl SourceSettings {
+    pub fn tree_name(&self) -> String {
+        self.tree
+            .clone()
+            .unwrap_or_else(|| self.channel.source().1.to_string())
+    }
+}
+
+/// Extra Kconfig toggles applied after the kernel options.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KconfigToggles {
+    pub enable: Vec<String>,
+    pub disable: Vec<String>,
+    pub module: Vec<String>,
+    pub set_val: BTreeMap<String, String>,
+    pub set_str: BTreeMap<String, String>,
+}
+
+impl KconfigToggles {
+    pub fn toggles(&self) -> Vec<Toggle> {
+        let mut toggles = Vec::new();
+        toggles.extend(self.enable.iter().cloned().map(Toggle::Enable));
+        toggles.extend(self.disable.iter().cloned().map(Toggle::Disable));
+        toggles.extend(self.module.iter().cloned().map(Toggle::Module));
+        toggles.extend(
+            self.set_val
+                .iter()
+                .map(|(sym, val)| Toggle::Value(sym.clone(), val.clone())),
+        );
+        toggles.extend(
+            self.set_str
+                .iter()
+                .map(|(sym, val)| Toggle::Str(sym.clone(), val.clone())),
+        );
+        toggles
+    }
+}
+
+/// Variables passed to `make` when compiling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildSettings {
+    pub localversion: String,
+    pub kcflags: String,
+}
+
+impl Default for BuildSettings {
+    fn default() -> Self {
+        Self {
+            localversion: "-capy".to_string(),
+            kcflags: "-mpopcnt -fivopts -fmodulo-sched".to_string(),
+        }
+    }
+}
+
+impl Recipe {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read recipe {}", path.display()))?;
+        let mut recipe: Recipe = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse recipe {}", path.display()))?;
+
+        // Patches listed relative to the recipe are resolved against its
+        // directory; anything else is looked up in the kernel-patches clone.
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for patch in recipe.patches.iter_mut() {
+            let local = base_dir.join(&patch);
+            if local.is_file() {
+                *patch = local;
+            }
+        }
+
+        Ok(recipe)
+    }
+}
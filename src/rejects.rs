@@ -0,0 +1,249 @@
+use anyhow::{Context, Result};
+use dialoguer::{theme::ColorfulTheme, Confirm, Select};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::editor;
+use crate::metadata::TreeMetadata;
+use crate::preflight;
+
+/// Directories that never hold leftovers of kcli's patch runs.
+const SKIPPED_DIRS: [&str; 2] = [".git", ".kcli-snapshots"];
+
+/// Longest stretch of a rejected hunk printed in the summary.
+const MAX_HUNK_LINES: usize = 12;
+
+/// A `.rej` or `.orig` file `patch` left next to a file it patched.
+#[derive(Debug)]
+pub struct Leftover {
+    /// Relative to the tree.
+    pub path: PathBuf,
+    /// The file it belongs to, relative to the tree.
+    pub target: PathBuf,
+    /// Rejected hunks of a `.rej` file; empty for `.orig` backups.
+    pub hunks: Vec<String>,
+}
+
+impl Leftover {
+    pub fn is_reject(&self) -> bool {
+        self.path.extension().is_some_and(|ext| ext == "rej")
+    }
+
+    /// The line of the target file the first rejected hunk was meant for.
+    fn line(&self) -> usize {
+        self.hunks
+            .first()
+            .and_then(|hunk| hunk.strip_prefix("@@ -"))
+            .and_then(|range| range.split([',', ' ']).next())
+            .and_then(|line| line.parse().ok())
+            .unwrap_or(1)
+    }
+}
+
+/// Splits a unified `.rej` file into its hunks, each starting at its `@@` line.
+fn parse_hunks(reject: &str) -> Vec<String> {
+    let mut hunks: Vec<String> = Vec::new();
+    for line in reject.lines() {
+        if line.starts_with("@@ ") {
+            hunks.push(String::new());
+        }
+        if let Some(hunk) = hunks.last_mut() {
+            hunk.push_str(line);
+            hunk.push('\n');
+        }
+    }
+    hunks
+}
+
+/// Every `.rej` file and every `.orig` backup of an existing file in the tree.
+pub fn find(kernel_dir: &Path) -> Result<Vec<Leftover>> {
+    let mut leftovers = Vec::new();
+    let entries = WalkDir::new(kernel_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !SKIPPED_DIRS.iter().any(|dir| entry.file_name() == *dir));
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let Some(extension) = path
+            .extension()
+            .filter(|ext| *ext == "rej" || *ext == "orig")
+        else {
+            continue;
+        };
+        let target = path.with_extension("");
+        if extension == "orig" && !target.is_file() {
+            continue;
+        }
+        let hunks = if extension == "rej" {
+            let contents =
+                fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            parse_hunks(&String::from_utf8_lossy(&contents))
+        } else {
+            Vec::new()
+        };
+        leftovers.push(Leftover {
+            path: path.strip_prefix(kernel_dir)?.to_path_buf(),
+            target: target.strip_prefix(kernel_dir)?.to_path_buf(),
+            hunks,
+        });
+    }
+    Ok(leftovers)
+}
+
+/// A patch, or `None` for leftovers no kcli patch explains, with its leftovers.
+pub type PatchLeftovers = (Option<PathBuf>, Vec<Leftover>);
+
+/// Groups leftovers by the last patch kcli applied, or half-applied, to
+/// their file, in the order the patches were applied.
+pub fn by_patch(kernel_dir: &Path, leftovers: Vec<Leftover>) -> Result<Vec<PatchLeftovers>> {
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    let mut groups: Vec<PatchLeftovers> = Vec::new();
+    let mut touched = Vec::new();
+    for patch in metadata.patches.iter().chain(&metadata.partial_patch) {
+        // A patch that is gone cannot be matched, but should not hide the rest
+        let contents = fs::read(&patch.path).unwrap_or_default();
+        touched.push(preflight::touched_files(&String::from_utf8_lossy(
+            &contents,
+        )));
+        groups.push((Some(patch.path.clone()), Vec::new()));
+    }
+    groups.push((None, Vec::new()));
+    for leftover in leftovers {
+        let index = touched
+            .iter()
+            .rposition(|files| files.contains(&leftover.target))
+            .unwrap_or(touched.len());
+        groups[index].1.push(leftover);
+    }
+    groups.retain(|(_, leftovers)| !leftovers.is_empty());
+    Ok(groups)
+}
+
+/// Prints the leftovers per patch with the rejected hunks and their context.
+pub fn print_summary(groups: &[PatchLeftovers]) {
+    for (patch, leftovers) in groups {
+        match patch {
+            Some(patch) => println!("{}:", patch.display()),
+            None => println!("Not from a patch kcli applied:"),
+        }
+        for leftover in leftovers {
+            if !leftover.is_reject() {
+                println!(
+                    "  {}: backup of {} from before patching",
+                    leftover.path.display(),
+                    leftover.target.display()
+                );
+                continue;
+            }
+            println!(
+                "  {}: {} rejected hunks",
+                leftover.path.display(),
+                leftover.hunks.len()
+            );
+            for hunk in &leftover.hunks {
+                let lines: Vec<&str> = hunk.lines().collect();
+                for line in lines.iter().take(MAX_HUNK_LINES) {
+                    println!("    {}", line);
+                }
+                if lines.len() > MAX_HUNK_LINES {
+                    println!("    ... {} more lines", lines.len() - MAX_HUNK_LINES);
+                }
+            }
+        }
+    }
+}
+
+/// Deletes the leftovers.
+pub fn clean(kernel_dir: &Path, leftovers: &[Leftover]) -> Result<()> {
+    for leftover in leftovers {
+        let path = kernel_dir.join(&leftover.path);
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    println!("Removed {} .rej and .orig files.", leftovers.len());
+    Ok(())
+}
+
+/// Prints a short note about leftovers after a patch run.
+pub fn print_notice(kernel_dir: &Path) -> Result<()> {
+    let leftovers = find(kernel_dir)?;
+    let rejects = leftovers
+        .iter()
+        .filter(|leftover| leftover.is_reject())
+        .count();
+    if leftovers.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "patch left {} .rej and {} .orig files in {}; review them with `kcli patch rejects`.",
+        rejects,
+        leftovers.len() - rejects,
+        kernel_dir.display()
+    );
+    Ok(())
+}
+
+/// Shows the leftovers and lets the user open them one by one, or remove
+/// them all once the rejected hunks are dealt with.
+pub async fn review(theme: &ColorfulTheme, kernel_dir: &Path) -> Result<()> {
+    loop {
+        let leftovers = find(kernel_dir)?;
+        if leftovers.is_empty() {
+            println!("No .rej or .orig files in {}.", kernel_dir.display());
+            return Ok(());
+        }
+        let groups = by_patch(kernel_dir, leftovers)?;
+        print_summary(&groups);
+        let leftovers: Vec<Leftover> = groups
+            .into_iter()
+            .flat_map(|(_, leftovers)| leftovers)
+            .collect();
+
+        let mut items: Vec<String> = leftovers
+            .iter()
+            .map(|leftover| format!("Open {}", leftover.path.display()))
+            .collect();
+        items.push("Mark Resolved and Remove All".to_string());
+        items.push("<- Back".to_string());
+        let selection = Select::with_theme(theme)
+            .with_prompt("Rejected hunks and backups")
+            .items(&items)
+            .default(0)
+            .interact()?;
+
+        if let Some(leftover) = leftovers.get(selection) {
+            let path = kernel_dir.join(&leftover.path);
+            if let Err(err) = editor::open(&path, 1).await {
+                eprintln!("Error: {:#}", err);
+            }
+            // Offer the file the hunks were meant for right after
+            if leftover.is_reject()
+                && Confirm::with_theme(theme)
+                    .with_prompt(format!("Open {} as well?", leftover.target.display()))
+                    .default(true)
+                    .interact()?
+            {
+                let target = kernel_dir.join(&leftover.target);
+                if let Err(err) = editor::open(&target, leftover.line()).await {
+                    eprintln!("Error: {:#}", err);
+                }
+            }
+        } else if selection == leftovers.len() {
+            let confirmed = Confirm::with_theme(theme)
+                .with_prompt(format!(
+                    "Remove all {} files? Rejected hunks not applied by hand are lost",
+                    leftovers.len()
+                ))
+                .default(false)
+                .interact()?;
+            if confirmed {
+                return clean(kernel_dir, &leftovers);
+            }
+        } else {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::metadata::{ConfigSeed, TreeMetadata};
+
+/// Where `seed_config` keeps the `.config` it replaces.
+const BACKUP_FILE: &str = ".config.kcli-seed.bak";
+
+/// Where a tree's initial `.config` comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeedSource {
+    /// The booted kernel, through `/proc/config.gz`.
+    Running,
+    /// `/boot/config-$(uname -r)`.
+    Boot,
+    /// The CachyOS default config.
+    CachyOs,
+    File(PathBuf),
+}
+
+impl SeedSource {
+    pub const NAMED: [SeedSource; 3] = [SeedSource::Running, SeedSource::Boot, SeedSource::CachyOs];
+
+    /// Parses `running`, `boot`, `cachyos` or a path to a config file.
+    pub fn parse(source: &str) -> Self {
+        match source {
+            "running" => SeedSource::Running,
+            "boot" => SeedSource::Boot,
+            "cachyos" => SeedSource::CachyOs,
+            path => SeedSource::File(PathBuf::from(path)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SeedSource::Running => "running",
+            SeedSource::Boot => "boot",
+            SeedSource::CachyOs => "cachyos",
+            SeedSource::File(_) => "file",
+        }
+    }
+
+    /// Reads the config, returning it along with where it was read from.
+    async fn read(&self) -> Result<(String, String)> {
+        match self {
+            SeedSource::Running => {
+                let location = "/proc/config.gz";
+                if !Path::new(location).exists() {
+                    anyhow::bail!(
+                        "{} does not exist; the running kernel was built without CONFIG_IKCONFIG_PROC",
+                        location
+                    );
+                }
+                let output = Command::new("gzip")
+                    .args(["-dc", location])
+                    .output()
+                    .await
+                    .context("Failed to execute gzip")?;
+                if !output.status.success() {
+                    return Err(
+                        crate::Error::CommandFailed(format!("gzip -dc {}", location)).into(),
+                    );
+                }
+                let contents = String::from_utf8(output.stdout)
+                    .with_context(|| format!("{} is not valid UTF-8", location))?;
+                Ok((contents, location.to_string()))
+            }
+            SeedSource::Boot => {
+                let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+                    .context("Failed to read the running kernel release")?;
+                let location = format!("/boot/config-{}", release.trim());
+                let contents = fs::read_to_string(&location)
+                    .with_context(|| format!("Failed to read {}", location))?;
+                Ok((contents, location))
+            }
+            SeedSource::CachyOs => Ok((
+                crate::download_cachyos_config().await?,
+                crate::CACHYOS_CONFIG_URL.to_string(),
+            )),
+            SeedSource::File(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let location = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                Ok((contents, location.display().to_string()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for SeedSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedSource::Running => f.write_str("Running kernel (/proc/config.gz)"),
+            SeedSource::Boot => f.write_str("Installed kernel (/boot/config-$(uname -r))"),
+            SeedSource::CachyOs => f.write_str("CachyOS default config"),
+            SeedSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Replaces the tree's `.config` with the one from `source`, keeping the
+/// previous file as `.config.kcli-seed.bak`, brings it up to date with
+/// `make olddefconfig` and records the source in the tree's metadata.
+pub async fn seed_config(kernel_dir: &Path, source: &SeedSource) -> Result<()> {
+    let (contents, location) = source.read().await?;
+    let config_path = kernel_dir.join(".config");
+    // Not `.config.old`: olddefconfig moves the seeded file over that one
+    let backup_path = kernel_dir.join(BACKUP_FILE);
+    let backed_up = config_path.exists();
+    if backed_up {
+        fs::rename(&config_path, &backup_path).context("Failed to back up the existing .config")?;
+    }
+    fs::write(&config_path, contents).context("Failed to write the .config file")?;
+
+    crate::dotconfig::olddefconfig(kernel_dir).await?;
+
+    record_seed(kernel_dir, source, location)?;
+    println!("Seeded {} from {}.", config_path.display(), source);
+    if backed_up {
+        println!("The previous .config is kept as {}.", backup_path.display());
+    }
+    Ok(())
+}
+
+pub fn record_seed(kernel_dir: &Path, source: &SeedSource, location: String) -> Result<()> {
+    TreeMetadata::update(kernel_dir, |metadata| {
+        metadata.config_seed = Some(ConfigSeed {
+            source: source.name().to_string(),
+            location,
+            seeded_at: chrono::Utc::now().to_rfc3339(),
+        });
+    })
+}
@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::metadata::TreeMetadata;
+use crate::preflight::{self, PatchCheck};
+use crate::{patch, patch_sources};
+
+const SERIES_FILE: &str = ".kcli-series";
+
+/// An ordered list of patches for a tree, kept quilt-style in `.kcli-series`
+/// at the top of the tree: one patch per line, `#` starts a comment, and
+/// relative paths point into the kernel-patches clone.
+#[derive(Debug, Default)]
+pub struct Series {
+    patches: Vec<PathBuf>,
+}
+
+impl Series {
+    fn path(kernel_dir: &Path) -> PathBuf {
+        kernel_dir.join(SERIES_FILE)
+    }
+
+    /// Loads the tree's series, or an empty one when none was saved yet.
+    pub fn load(kernel_dir: &Path) -> Result<Self> {
+        let path = Self::path(kernel_dir);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        let patches = contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        Ok(Series { patches })
+    }
+
+    pub fn save(&self, kernel_dir: &Path) -> Result<()> {
+        let path = Self::path(kernel_dir);
+        let mut contents = String::from(
+            "# kcli patch series, applied in order.\n# Relative paths are inside the kernel-patches clone.\n",
+        );
+        for patch in &self.patches {
+            contents.push_str(&patch.to_string_lossy());
+            contents.push('\n');
+        }
+        fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn patches(&self) -> &[PathBuf] {
+        &self.patches
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    /// Appends patches, storing paths inside `patches_root` relative to it.
+    /// Patches already in the series keep their position.
+    pub fn add(&mut self, patches: Vec<PathBuf>, patches_root: &Path) {
+        for patch in patches {
+            let patch = patch
+                .strip_prefix(patches_root)
+                .map(Path::to_path_buf)
+                .unwrap_or(patch);
+            if !self.patches.contains(&patch) {
+                self.patches.push(patch);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> PathBuf {
+        self.patches.remove(index)
+    }
+
+    pub fn clear(&mut self) {
+        self.patches.clear();
+    }
+}
+
+/// Series entries kcli has not applied yet, with the patch files they
+/// resolve to.
+async fn pending(kernel_dir: &Path, series: &Series) -> Result<Vec<(String, PathBuf)>> {
+    let applied: Vec<PathBuf> = TreeMetadata::load(kernel_dir)?
+        .patches
+        .into_iter()
+        .map(|patch| patch.path)
+        .collect();
+    let patches_root = if series.patches().iter().any(|patch| patch.is_relative()) {
+        crate::kernel_patches::ensure_clone().await?
+    } else {
+        PathBuf::new()
+    };
+    Ok(series
+        .patches()
+        .iter()
+        .map(|entry| {
+            let patch = patches_root.join(entry);
+            let patch = fs::canonicalize(&patch).unwrap_or(patch);
+            (patch_sources::label(entry), patch)
+        })
+        .filter(|(_, patch)| !applied.contains(patch))
+        .collect())
+}
+
+/// Dry-runs the patches of the series that are not applied yet.
+pub async fn check_series(kernel_dir: &Path) -> Result<Vec<PatchCheck>> {
+    let series = Series::load(kernel_dir)?;
+    patch::check(kernel_dir, &pending(kernel_dir, &series).await?).await
+}
+
+/// Applies the tree's series in order, skipping patches kcli already
+/// applied. Unless `force` is set nothing is applied when a dry run finds
+/// hunks that fail; otherwise the run stops at the first failing patch.
+pub async fn apply_series(kernel_dir: &Path, force: bool) -> Result<()> {
+    let series = Series::load(kernel_dir)?;
+    if series.is_empty() {
+        anyhow::bail!("The patch series of {} is empty", kernel_dir.display());
+    }
+    let pending = pending(kernel_dir, &series).await?;
+    if !force {
+        let checks = patch::check(kernel_dir, &pending).await?;
+        let failing = checks.iter().filter(|check| !check.applies()).count();
+        if failing > 0 {
+            preflight::print_table(&checks);
+            anyhow::bail!(
+                "{} patches of the series do not apply cleanly; nothing was changed",
+                failing
+            );
+        }
+    }
+
+    let total = series.patches().len();
+    let already = total - pending.len();
+    if already > 0 {
+        println!("{} of {} patches are already applied.", already, total);
+    }
+    for (index, (entry, patch_file)) in pending.iter().enumerate() {
+        println!("[{}/{}] Applying {}", already + index + 1, total, entry);
+        if let Err(err) = patch::apply(kernel_dir, patch_file).await {
+            println!();
+            println!(
+                "Series stopped at patch {} of {}: {}",
+                already + index + 1,
+                total,
+                entry
+            );
+            println!("  {} patches before it are applied", already + index);
+            let remaining = &pending[index + 1..];
+            if !remaining.is_empty() {
+                println!("  not applied:");
+                for (entry, _) in remaining {
+                    println!("    {}", entry);
+                }
+            }
+            return Err(err.context(format!("Failed to apply {}", entry)));
+        }
+    }
+    println!("All {} patches of the series are applied.", total);
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::git_source::git;
+use crate::metadata::{AppliedPatch, TreeMetadata};
+use crate::patch_backend::mail_header;
+use crate::preflight;
+
+const SNAPSHOT_DIR: &str = ".kcli-snapshots";
+
+/// Files kcli keeps out of the commits it makes in git trees.
+const KCLI_FILES: [&str; 3] = [".kcli.json", ".kcli-series", SNAPSHOT_DIR];
+
+/// The state of a tree before a patch was applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Snapshot {
+    /// Commit a git tree was at; kcli commits each patch it applies.
+    Git { commit: String },
+    /// Copies of the files the patch touched, under `.kcli-snapshots`, and
+    /// the files it created.
+    Files { dir: PathBuf, created: Vec<PathBuf> },
+}
+
+fn is_git_tree(kernel_dir: &Path) -> bool {
+    kernel_dir.join(".git").exists()
+}
+
+/// Keeps kcli's own files out of `git status` and the commits kcli makes.
+async fn exclude_kcli_files(kernel_dir: &Path) -> Result<()> {
+    let common_dir = git(kernel_dir, &["rev-parse", "--git-common-dir"]).await?;
+    let exclude = kernel_dir.join(common_dir).join("info").join("exclude");
+    let contents = fs::read_to_string(&exclude).unwrap_or_default();
+    let missing: Vec<&str> = KCLI_FILES
+        .iter()
+        .filter(|file| !contents.lines().any(|line| line == format!("/{}", file)))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if let Some(parent) = exclude.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut contents = contents;
+    for file in missing {
+        contents.push_str(&format!("/{}\n", file));
+    }
+    fs::write(&exclude, contents).with_context(|| format!("Failed to write {}", exclude.display()))
+}
+
+/// Arguments giving git a kcli identity when the user has not configured one.
+pub async fn identity_args(kernel_dir: &Path) -> Vec<&'static str> {
+    if git(kernel_dir, &["config", "user.email"]).await.is_ok() {
+        return Vec::new();
+    }
+    vec!["-c", "user.name=kcli", "-c", "user.email=kcli@localhost"]
+}
+
+/// Commits everything in the tree with extra `git commit` arguments. Does
+/// nothing when the tree is clean.
+async fn commit_all(kernel_dir: &Path, message: &str, extra: &[&str]) -> Result<()> {
+    if git(kernel_dir, &["status", "--porcelain"])
+        .await?
+        .is_empty()
+    {
+        return Ok(());
+    }
+    git(kernel_dir, &["add", "-A"]).await?;
+    let mut args = identity_args(kernel_dir).await;
+    args.extend(["commit", "--quiet", "--no-verify", "-m", message]);
+    args.extend(extra);
+    git(kernel_dir, &args).await?;
+    Ok(())
+}
+
+fn patch_name(patch_file: &Path) -> String {
+    patch_file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| patch_file.display().to_string())
+}
+
+/// Commits local changes in a git tree before kcli changes it, so they are
+/// kept apart from kcli's own commits. `before` names the change.
+pub async fn commit_local_changes(kernel_dir: &Path, before: &str) -> Result<()> {
+    if !is_git_tree(kernel_dir) {
+        return Ok(());
+    }
+    exclude_kcli_files(kernel_dir).await?;
+    commit_all(
+        kernel_dir,
+        &format!("kcli: local changes before {}", before),
+        &[],
+    )
+    .await
+}
+
+/// Commits one upstream update step of a git tree as `Linux <version>`,
+/// returning the new commit, or `None` for trees that are not git
+/// checkouts.
+pub async fn commit_update(kernel_dir: &Path, version: &str) -> Result<Option<String>> {
+    if !is_git_tree(kernel_dir) {
+        return Ok(None);
+    }
+    exclude_kcli_files(kernel_dir).await?;
+    commit_all(kernel_dir, &format!("Linux {}", version), &[]).await?;
+    Ok(Some(git(kernel_dir, &["rev-parse", "HEAD"]).await?))
+}
+
+/// Records the tree's state before `patch_file` is applied. Local changes in
+/// git trees are committed first so the snapshot includes them.
+pub async fn take(kernel_dir: &Path, patch_file: &Path) -> Result<Snapshot> {
+    if is_git_tree(kernel_dir) {
+        commit_local_changes(kernel_dir, &patch_name(patch_file)).await?;
+        let commit = git(kernel_dir, &["rev-parse", "HEAD"]).await?;
+        return Ok(Snapshot::Git { commit });
+    }
+
+    let patch =
+        fs::read(patch_file).with_context(|| format!("Failed to read {}", patch_file.display()))?;
+    let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S%f").to_string();
+    let dir = PathBuf::from(SNAPSHOT_DIR).join(stamp);
+    let mut created = Vec::new();
+    for file in preflight::touched_files(&String::from_utf8_lossy(&patch)) {
+        let source = kernel_dir.join(&file);
+        if !source.exists() {
+            created.push(file);
+            continue;
+        }
+        let target = kernel_dir.join(&dir).join(&file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // fs::copy reflinks on filesystems that support it
+        fs::copy(&source, &target)
+            .with_context(|| format!("Failed to snapshot {}", source.display()))?;
+    }
+    fs::create_dir_all(kernel_dir.join(&dir))?;
+    Ok(Snapshot::Files { dir, created })
+}
+
+/// Commits a patch kcli just applied to a git tree, with the patch's own
+/// author, date and message when it is mail-style.
+pub async fn commit_patch(kernel_dir: &Path, patch_file: &Path) -> Result<()> {
+    if !is_git_tree(kernel_dir) {
+        return Ok(());
+    }
+    match mail_header(patch_file) {
+        Some(header) => {
+            let mut extra = Vec::new();
+            if let Some(author) = &header.author {
+                extra.extend(["--author", author.as_str()]);
+            }
+            if let Some(date) = &header.date {
+                extra.extend(["--date", date.as_str()]);
+            }
+            commit_all(kernel_dir, &header.message(), &extra).await
+        }
+        None => {
+            commit_all(
+                kernel_dir,
+                &format!("kcli: apply {}", patch_name(patch_file)),
+                &[],
+            )
+            .await
+        }
+    }
+}
+
+fn restore_files(kernel_dir: &Path, dir: &Path, created: &[PathBuf]) -> Result<()> {
+    let snapshot_dir = kernel_dir.join(dir);
+    for entry in WalkDir::new(&snapshot_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+    {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(&snapshot_dir)?;
+        let target = kernel_dir.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(entry.path(), &target)
+            .with_context(|| format!("Failed to restore {}", target.display()))?;
+    }
+    for file in created {
+        let path = kernel_dir.join(file);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    fs::remove_dir_all(&snapshot_dir)?;
+    Ok(())
+}
+
+/// Restores the tree to before the patch at `index` of the applied patches,
+/// undoing it, every patch applied after it and a half-applied patch.
+pub async fn roll_back(kernel_dir: &Path, index: usize) -> Result<()> {
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    let mut undo: Vec<&AppliedPatch> = metadata.patches[index..].iter().collect();
+    undo.extend(&metadata.partial_patch);
+    let Some(first) = undo.first() else {
+        println!("No patches to revert.");
+        return Ok(());
+    };
+    if let Some(patch) = undo.iter().find(|patch| patch.snapshot.is_none()) {
+        anyhow::bail!(
+            "{} was applied without a snapshot and cannot be reverted",
+            patch.path.display()
+        );
+    }
+
+    match &first.snapshot {
+        Some(Snapshot::Git { commit }) => {
+            git(kernel_dir, &["reset", "--hard", "--quiet", commit]).await?;
+            git(kernel_dir, &["clean", "-fd", "--quiet"]).await?;
+        }
+        _ => {
+            for patch in undo.iter().rev() {
+                if let Some(Snapshot::Files { dir, created }) = &patch.snapshot {
+                    restore_files(kernel_dir, dir, created)?;
+                }
+            }
+        }
+    }
+    for patch in undo.iter().rev() {
+        println!("Reverted {}", patch.path.display());
+    }
+    TreeMetadata::update(kernel_dir, |metadata| {
+        metadata.patches.truncate(index);
+        metadata.partial_patch = None;
+    })
+}
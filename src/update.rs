@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::kernel_org::{self, ReleaseIndex};
+use crate::metadata::{AppliedPatch, TreeMetadata};
+use crate::patch;
+use crate::snapshot;
+use crate::Error;
+
+/// A stable kernel version, `major.minor.sublevel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub sublevel: u32,
+}
+
+impl KernelVersion {
+    /// Parses `6.10.3`, or `6.10` and `6.10.0` for the initial release.
+    /// Release candidates and other suffixed versions are rejected.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let sublevel = match parts.next() {
+            Some(sublevel) => sublevel.parse().ok()?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(KernelVersion {
+            major,
+            minor,
+            sublevel,
+        })
+    }
+
+    fn same_series(&self, other: &KernelVersion) -> bool {
+        self.major == other.major && self.minor == other.minor
+    }
+
+    fn next(&self) -> KernelVersion {
+        KernelVersion {
+            sublevel: self.sublevel + 1,
+            ..*self
+        }
+    }
+
+    /// Path of the patch taking this version to the next point release,
+    /// relative to kernel.org's `/pub/linux/kernel`, without extension.
+    /// The first point release is a full patch against the base release.
+    fn incremental_patch(&self) -> String {
+        let next = self.next();
+        if self.sublevel == 0 {
+            format!("v{}.x/patch-{}", self.major, next)
+        } else {
+            format!("v{}.x/incr/patch-{}-{}", self.major, self, next.sublevel)
+        }
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sublevel == 0 {
+            write!(f, "{}.{}", self.major, self.minor)
+        } else {
+            write!(f, "{}.{}.{}", self.major, self.minor, self.sublevel)
+        }
+    }
+}
+
+/// The version of the sources in `kernel_dir`, from `make kernelversion`.
+pub async fn kernel_version(kernel_dir: &Path) -> Result<String> {
+    let output = Command::new("make")
+        .args(["-s", "kernelversion"])
+        .current_dir(kernel_dir)
+        .output()
+        .await
+        .context("Failed to execute make")?;
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr).trim_end());
+        return Err(Error::CommandFailed("make kernelversion".to_string()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Feeds `diff` to `patch -p1` inside the tree.
+async fn run_patch(kernel_dir: &Path, diff: &[u8], args: &[&str]) -> Result<()> {
+    let mut child = Command::new("patch")
+        .args(["-p1", "--batch", "--silent"])
+        .args(args)
+        .current_dir(kernel_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to execute patch")?;
+    let mut stdin = child.stdin.take().context("Failed to write to patch")?;
+    stdin.write_all(diff).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let report = String::from_utf8_lossy(&output.stdout);
+        if !report.trim().is_empty() {
+            eprintln!("{}", report.trim_end());
+        }
+        return Err(Error::CommandFailed(format!("patch -p1 {}", args.join(" "))).into());
+    }
+    Ok(())
+}
+
+async fn decompress_xz(path: &Path) -> Result<Vec<u8>> {
+    let output = Command::new("xz")
+        .arg("-dc")
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to execute xz")?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(format!("xz -dc {}", path.display())).into());
+    }
+    Ok(output.stdout)
+}
+
+/// Brings the tree to the latest point release of its series by applying
+/// kernel.org's signed incremental patches one release at a time. Patches
+/// kcli applied on top, such as the CachyOS ones, are reverted first and
+/// re-applied afterwards, also when an upstream patch fails.
+pub async fn update_tree(kernel_dir: &Path) -> Result<()> {
+    let reported = kernel_version(kernel_dir).await?;
+    let current = KernelVersion::parse(&reported).with_context(|| {
+        format!(
+            "{} is at {}; only stable releases can be updated with incremental patches",
+            kernel_dir.display(),
+            reported
+        )
+    })?;
+
+    let index = ReleaseIndex::load().await?;
+    index.warn_if_stale();
+    let latest = index
+        .releases()
+        .iter()
+        .filter_map(|release| KernelVersion::parse(&release.version))
+        .filter(|version| version.same_series(&current))
+        .max()
+        .with_context(|| {
+            format!(
+                "kernel.org no longer lists the {}.{} series",
+                current.major, current.minor
+            )
+        })?;
+    if latest <= current {
+        println!(
+            "{} is already at {}, the latest {}.{} release.",
+            kernel_dir.display(),
+            current,
+            current.major,
+            current.minor
+        );
+        return Ok(());
+    }
+    println!(
+        "Updating {} from {} to {}.",
+        kernel_dir.display(),
+        current,
+        latest
+    );
+
+    // Download and verify the whole chain before touching the tree.
+    let mut steps = Vec::new();
+    let mut version = current;
+    while version < latest {
+        let patch = version.incremental_patch();
+        let path = kernel_org::download_verified(
+            &kernel_org::pub_url(&format!("{}.xz", patch)),
+            &kernel_org::pub_url(&format!("{}.sign", patch)),
+        )
+        .await?;
+        steps.push((version.next(), decompress_xz(&path).await?));
+        version = version.next();
+    }
+
+    // Take kcli's own patches off so the upstream patches apply cleanly.
+    let metadata = TreeMetadata::load(kernel_dir)?;
+    if let Some(partial) = &metadata.partial_patch {
+        anyhow::bail!(
+            "{} left the tree half-patched; undo it with `kcli patch revert` first",
+            partial.path.display()
+        );
+    }
+    let mut applied = Vec::new();
+    for patch in &metadata.patches {
+        let diff = std::fs::read(&patch.path).with_context(|| {
+            format!(
+                "Failed to read applied patch {}; it is needed to update the tree",
+                patch.path.display()
+            )
+        })?;
+        applied.push((patch.clone(), diff));
+    }
+    if metadata
+        .patches
+        .iter()
+        .all(|patch| patch.snapshot.is_some())
+    {
+        patch::reset(kernel_dir).await?;
+    } else {
+        // Patches applied before kcli kept snapshots are reversed instead
+        for (index, (patch, diff)) in applied.iter().enumerate().rev() {
+            run_patch(kernel_dir, diff, &["-R", "--dry-run"])
+                .await
+                .with_context(|| format!("{} cannot be reverted", patch.path.display()))?;
+            run_patch(kernel_dir, diff, &["-R"]).await?;
+            TreeMetadata::update(kernel_dir, |metadata| metadata.patches.truncate(index))?;
+            println!("Reverted {}", patch.path.display());
+        }
+    }
+
+    snapshot::commit_local_changes(kernel_dir, &format!("Linux {}", latest)).await?;
+    if let Err(err) = apply_chain(kernel_dir, &steps).await {
+        let failed = reapply(kernel_dir, &applied).await;
+        if !failed.is_empty() {
+            eprintln!(
+                "The update of {} failed, and these patches were not re-applied:\n  {}",
+                kernel_dir.display(),
+                failed.join("\n  ")
+            );
+        }
+        return Err(err);
+    }
+
+    let failed = reapply(kernel_dir, &applied).await;
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} is at {}, but these patches were not re-applied:\n  {}",
+            kernel_dir.display(),
+            latest,
+            failed.join("\n  ")
+        );
+    }
+    println!("{} is now at {}.", kernel_dir.display(), latest);
+    Ok(())
+}
+
+/// Applies the upstream patches in order, checking the version the tree
+/// reports after each one. In git trees each step is committed, and the
+/// tree's origin follows it.
+async fn apply_chain(kernel_dir: &Path, steps: &[(KernelVersion, Vec<u8>)]) -> Result<()> {
+    for (version, diff) in steps {
+        run_patch(kernel_dir, diff, &["-N", "--dry-run"])
+            .await
+            .with_context(|| format!("The patch to {} does not apply", version))?;
+        run_patch(kernel_dir, diff, &["-N"]).await?;
+        let reported = kernel_version(kernel_dir).await?;
+        if KernelVersion::parse(&reported) != Some(*version) {
+            anyhow::bail!(
+                "Expected {} after patching, but the tree reports {}",
+                version,
+                reported
+            );
+        }
+        let commit = snapshot::commit_update(kernel_dir, &version.to_string()).await?;
+        TreeMetadata::update(kernel_dir, |metadata| {
+            metadata.upstream_version = Some(version.to_string());
+            // The commit of the tag the tree was fetched at no longer applies
+            if let Some(origin) = &mut metadata.origin {
+                origin.commit = commit;
+            }
+        })?;
+        println!("Updated to {}", version);
+    }
+    Ok(())
+}
+
+/// Re-applies the patches taken off before updating, returning the ones
+/// left off. Patches after one that fails are not tried.
+async fn reapply(kernel_dir: &Path, applied: &[(AppliedPatch, Vec<u8>)]) -> Vec<String> {
+    for (index, (reapplied, _)) in applied.iter().enumerate() {
+        if patch::apply(kernel_dir, &reapplied.path).await.is_err() {
+            return applied[index..]
+                .iter()
+                .map(|(patch, _)| patch.path.display().to_string())
+                .collect();
+        }
+    }
+    Vec::new()
+}